@@ -0,0 +1,572 @@
+//! Assembly of absorption imaging shots from a configurable set of frames.
+//!
+//! Every frame of a shot is identified by a pattern in its file name and has
+//! a role. Frames are grouped, and each group gives one OD image:
+//!
+//! - a `kinetics` frame holds the atoms in its top half and the bright
+//!   reference in its bottom half (or the reverse, with `atoms = "bottom"`),
+//!   and gives an OD of half its height, which must be even (after the
+//!   trim);
+//! - an `atoms` and a `bright` frame give an OD of their full height.
+//!
+//! The OD of a group is flipped vertically if its kinetics or atoms frame has
+//! `flip = true`, for species imaged through a mirror.
+//!
+//! A `dark` frame is subtracted from the frames of its group, or from all of
+//! them if it has no group. When the atoms and bright exposures have
+//! different dark levels (e.g. in kinetics mode), `dark_atoms` and
+//! `dark_bright` frames are subtracted from the atoms and from the bright
+//! reference respectively, taking precedence over `dark`. The ODs of the
+//! groups are stacked vertically, in group order. The default mapping is the
+//! one of our dual species kinetics shots: two kinetics frames and a shared
+//! dark.
+//!
+//! OD images are written as u16, after `od_offset` and `od_scale`. Values
+//! out of 0..=65535 are clipped to the range and counted, NaN becomes 0.
+
+use std::cell::Cell;
+
+use anyhow::{bail, Result};
+use log::debug;
+use ndarray::{concatenate, s, Array2, ArrayView2, Axis, Slice};
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::noise::NoiseConf;
+
+thread_local! {
+    /// OD pixels clipped by the u16 encoding in this thread, since startup;
+    /// per thread, so that the archival thread does not count in the shots
+    static CLIPPED_PIXELS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Role of a frame in a shot
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Atoms, full frame
+    Atoms,
+    /// Bright reference, full frame
+    Bright,
+    /// Atoms in the top half, bright reference in the bottom half
+    Kinetics,
+    /// Dark frame, subtracted from the others
+    Dark,
+    /// Dark frame of the atoms exposure only
+    DarkAtoms,
+    /// Dark frame of the bright exposure only
+    DarkBright,
+}
+
+impl Role {
+    fn is_dark(&self) -> bool {
+        matches!(self, Role::Dark | Role::DarkAtoms | Role::DarkBright)
+    }
+}
+
+/// Half of a kinetics frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Half {
+    /// Top half
+    #[default]
+    Top,
+    /// Bottom half
+    Bottom,
+}
+
+/// A frame of a shot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameConf {
+    /// Pattern identifying the frame in the file names
+    pub pattern: String,
+    /// Role of the frame
+    pub role: Role,
+    /// OD image the frame contributes to, darks without group apply to all
+    #[serde(default)]
+    pub group: Option<usize>,
+    /// Half of a kinetics frame holding the atoms
+    #[serde(default)]
+    pub atoms: Half,
+    /// Flip the OD of the group vertically (kinetics and atoms frames)
+    #[serde(default)]
+    pub flip: bool,
+}
+
+impl FrameConf {
+    fn new(pattern: &str, role: Role, group: Option<usize>) -> FrameConf {
+        FrameConf {
+            pattern: String::from(pattern),
+            role,
+            group,
+            atoms: Half::Top,
+            flip: false,
+        }
+    }
+
+    /// Human readable description of the role of the frame.
+    pub fn describe(&self) -> String {
+        let role = match self.role {
+            Role::Atoms => "atoms",
+            Role::Bright => "bright reference",
+            Role::Kinetics if self.atoms == Half::Bottom => {
+                "bright reference and atoms"
+            }
+            Role::Kinetics => "atoms and bright reference",
+            Role::Dark => "dark",
+            Role::DarkAtoms => "dark of the atoms exposure",
+            Role::DarkBright => "dark of the bright exposure",
+        };
+        let flip = if self.flip { ", flipped" } else { "" };
+        match self.group {
+            Some(g) => format!("{} of OD image {}{}", role, g, flip),
+            None => format!("{} of all OD images{}", role, flip),
+        }
+    }
+}
+
+/// Frames of our dual species kinetics shots.
+pub fn default_frames() -> Vec<FrameConf> {
+    vec![
+        FrameConf::new("rawimg-0001", Role::Kinetics, Some(0)),
+        FrameConf::new("rawimg-0002", Role::Kinetics, Some(1)),
+        FrameConf::new("rawimg-0003", Role::Dark, None),
+    ]
+}
+
+/// Indices of the frames with the role in the group (or shared, for darks).
+fn find(frames: &[FrameConf], role: Role, group: usize) -> Vec<usize> {
+    frames
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.role == role)
+        .filter(|(_, f)| match f.group {
+            Some(g) => g == group,
+            None => role.is_dark(),
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Check the frame mapping, returning the number of OD images.
+pub fn check(frames: &[FrameConf]) -> Result<usize> {
+    let mut problems = vec![];
+    for f in frames {
+        if f.group.is_none() && !f.role.is_dark() {
+            problems.push(format!("frame {} has no group", f.pattern));
+        }
+        if f.atoms != Half::Top && f.role != Role::Kinetics {
+            problems.push(format!("frame {} is not kinetics", f.pattern));
+        }
+        if f.flip && !matches!(f.role, Role::Kinetics | Role::Atoms) {
+            problems.push(format!(
+                "frame {} cannot be flipped, only kinetics and atoms can",
+                f.pattern
+            ));
+        }
+    }
+    let ngroups = frames
+        .iter()
+        .filter_map(|f| f.group)
+        .max()
+        .map_or(0, |g| g + 1);
+    if ngroups == 0 {
+        problems.push(String::from("no OD image is defined"));
+    }
+    for g in 0..ngroups {
+        let count = |role| find(frames, role, g).len();
+        let (at, br, ki) = (
+            count(Role::Atoms),
+            count(Role::Bright),
+            count(Role::Kinetics),
+        );
+        if !((ki == 1 && at == 0 && br == 0) || (ki == 0 && at == 1 && br == 1))
+        {
+            problems.push(format!(
+                "OD image {} needs one kinetics frame, or one atoms and one \
+                 bright frame",
+                g
+            ));
+        }
+        for dark in [Role::Dark, Role::DarkAtoms, Role::DarkBright] {
+            if count(dark) > 1 {
+                problems.push(format!(
+                    "OD image {} has more than one {:?}",
+                    g, dark
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!("invalid frames: {}", problems.join(", "));
+    }
+    Ok(ngroups)
+}
+
+/// Check that frames of the given height, after the trim, can be split in
+/// halves if the mapping has kinetics frames.
+pub fn check_height(frames: &[FrameConf], height: usize) -> Result<()> {
+    let kinetics = frames.iter().any(|f| f.role == Role::Kinetics);
+    if kinetics && !height.is_multiple_of(2) {
+        bail!("kinetics frames need an even height, not {}", height);
+    }
+    Ok(())
+}
+
+/// Frame minus the dark, if any.
+fn subtract<F>(img: ArrayView2<u16>, dark: Option<ArrayView2<u16>>) -> Array2<F>
+where
+    F: Float + From<u16>,
+{
+    let img = img.mapv(<F as From<u16>>::from);
+    match dark {
+        Some(d) => img - d.mapv(<F as From<u16>>::from),
+        None => img,
+    }
+}
+
+/// The two exposures giving an OD image, dark subtracted
+struct Exposures<F> {
+    atoms: Array2<F>,
+    bright: Array2<F>,
+    /// Whether a dark was subtracted from the atoms and from the bright
+    darks: (bool, bool),
+    flip: bool,
+}
+
+/// Exposures of each OD image of a shot, in group order.
+fn exposures<F>(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+) -> Result<Vec<Exposures<F>>>
+where
+    F: Float + From<u16>,
+{
+    let ngroups = check(frames)?;
+    if imgs.len() != frames.len() {
+        bail!("{} images given for {} frames", imgs.len(), frames.len());
+    }
+    if imgs.iter().any(|i| i.dim() != imgs[0].dim()) {
+        bail!("Frames of the shot have different sizes");
+    }
+    if let Some(img) = imgs.first() {
+        check_height(frames, img.shape()[0])?;
+    }
+    debug!(
+        "Calculating {} OD images from {} frames",
+        ngroups,
+        imgs.len()
+    );
+
+    let mut groups = vec![];
+    for g in 0..ngroups {
+        // Indices of the darks of the two exposures
+        let dark = |role| {
+            let specific = find(frames, role, g).first().copied();
+            specific.or(find(frames, Role::Dark, g).first().copied())
+        };
+        let (dark_at, dark_br) =
+            (dark(Role::DarkAtoms), dark(Role::DarkBright));
+        let kinetics = find(frames, Role::Kinetics, g).first().copied();
+        let main = kinetics.unwrap_or_else(|| find(frames, Role::Atoms, g)[0]);
+        let (atoms, bright) = match kinetics {
+            Some(i) => {
+                let height = imgs[i].shape()[0];
+                let (top, bottom) =
+                    (s![..height / 2, ..], s![height / 2.., ..]);
+                let (at, br) = match frames[i].atoms {
+                    Half::Top => (top, bottom),
+                    Half::Bottom => (bottom, top),
+                };
+                (
+                    subtract(
+                        imgs[i].slice(at),
+                        dark_at.map(|d| imgs[d].slice(at)),
+                    ),
+                    subtract(
+                        imgs[i].slice(br),
+                        dark_br.map(|d| imgs[d].slice(br)),
+                    ),
+                )
+            }
+            None => {
+                let at = find(frames, Role::Atoms, g)[0];
+                let br = find(frames, Role::Bright, g)[0];
+                (
+                    subtract(imgs[at].view(), dark_at.map(|d| imgs[d].view())),
+                    subtract(imgs[br].view(), dark_br.map(|d| imgs[d].view())),
+                )
+            }
+        };
+        groups.push(Exposures {
+            atoms,
+            bright,
+            darks: (dark_at.is_some(), dark_br.is_some()),
+            flip: frames[main].flip,
+        });
+    }
+    Ok(groups)
+}
+
+/// Flip the images that need it, and stack them vertically.
+fn stack<F: Clone>(imgs: Vec<(Array2<F>, bool)>) -> Result<Array2<F>> {
+    let imgs = imgs
+        .into_iter()
+        .map(|(mut img, flip)| {
+            if flip {
+                img.slice_axis_inplace(Axis(0), Slice::new(0, None, -1));
+            }
+            img
+        })
+        .collect::<Vec<_>>();
+    let views = imgs.iter().map(|o| o.view()).collect::<Vec<_>>();
+    Ok(concatenate(Axis(0), &views)?)
+}
+
+/// OD from the atoms and the bright reference, the latter multiplied by the
+/// correction factor.
+fn logratio<F>(
+    atoms: ArrayView2<F>,
+    bright: ArrayView2<F>,
+    factor: F,
+) -> Array2<F>
+where
+    F: Float + Send + Sync,
+{
+    let mut out = bright.mapv(|x| x * factor);
+    out.par_mapv_inplace(F::ln);
+    out - atoms.mapv(F::ln)
+}
+
+/// Stacked OD images of a shot, in f32 for live processing or in f64 for the
+/// archival pass. Images are given in the order of the frames.
+pub fn calc_od<F>(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+    factor: F,
+) -> Result<Array2<F>>
+where
+    F: Float + From<u16> + Send + Sync,
+{
+    let ods = exposures::<F>(frames, imgs)?
+        .into_iter()
+        .map(|e| (logratio(e.atoms.view(), e.bright.view(), factor), e.flip))
+        .collect();
+    stack(ods)
+}
+
+/// Encode a scaled OD image as u16, rounding or truncating, with the
+/// number of pixels clipped to the u16 range.
+pub fn encode<F: Float>(img: &Array2<F>, round: bool) -> (Array2<u16>, usize) {
+    let mut clipped = 0;
+    let out = img.mapv(|x| {
+        let x = x.to_f64().unwrap_or(f64::NAN);
+        let x = if round { x.round() } else { x.trunc() };
+        if x < 0.0 || x > f64::from(u16::MAX) {
+            clipped += 1;
+        }
+        // Saturating, NaN is 0
+        x as u16
+    });
+    (out, clipped)
+}
+
+/// Add pixels to the count of the clipped ones of the current thread.
+pub fn count_clipped(n: usize) {
+    CLIPPED_PIXELS.with(|c| c.set(c.get() + n as u64));
+}
+
+/// OD pixels clipped by the u16 encoding in the current thread since
+/// startup.
+pub fn clipped_count() -> u64 {
+    CLIPPED_PIXELS.with(Cell::get)
+}
+
+/// Every intermediate array of the OD computation of a shot, named after
+/// its stage, for debugging: the exposures after dark subtraction, their
+/// logarithms (the bright one multiplied by the correction factor), the OD
+/// of each group and the stacked OD, which is the one of `calc_od`.
+pub fn stages(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+    factor: f32,
+) -> Result<Vec<(String, Array2<f32>)>> {
+    let mut stages = vec![];
+    let mut ods = vec![];
+    for (g, e) in exposures::<f32>(frames, imgs)?.into_iter().enumerate() {
+        let ln_atoms = e.atoms.mapv(f32::ln);
+        let ln_bright = e.bright.mapv(|x| (x * factor).ln());
+        let od = &ln_bright - &ln_atoms;
+        stages.push((format!("g{}-atoms-minus-dark", g), e.atoms));
+        stages.push((format!("g{}-bright-minus-dark", g), e.bright));
+        stages.push((format!("g{}-ln-atoms", g), ln_atoms));
+        stages.push((format!("g{}-ln-bright", g), ln_bright));
+        stages.push((format!("g{}-od", g), od.clone()));
+        ods.push((od, e.flip));
+    }
+    stages.push((String::from("od"), stack(ods)?));
+    Ok(stages)
+}
+
+/// Stacked per-pixel variance of the OD images of a shot, propagated from
+/// the photon and read noise of the exposures (the correction factor of the
+/// bright frames cancels out).
+pub fn calc_var(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+    noise: &NoiseConf,
+) -> Result<Array2<f32>> {
+    let vars = exposures::<f32>(frames, imgs)?
+        .into_iter()
+        .map(|e| {
+            let (dark_at, dark_br) = e.darks;
+            let mut var = noise.relvar(&e.atoms, dark_at);
+            var += &noise.relvar(&e.bright, dark_br);
+            (var, e.flip)
+        })
+        .collect();
+    stack(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{
+        calc_od, calc_var, check, check_height, clipped_count, count_clipped,
+        default_frames, encode, stages, FrameConf, Half, Role,
+    };
+    use crate::noise::NoiseConf;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(&default_frames()).unwrap(), 2);
+
+        let frames = vec![
+            FrameConf::new("a", Role::Atoms, Some(0)),
+            FrameConf::new("b", Role::Bright, Some(0)),
+            FrameConf::new("d", Role::Dark, Some(0)),
+            FrameConf::new("e", Role::Dark, Some(0)),
+        ];
+        assert!(check(&frames).is_err());
+        assert!(check(&frames[..1]).is_err());
+        assert_eq!(check(&frames[..3]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_encode() {
+        let img = Array2::from_shape_vec(
+            (1, 5),
+            vec![-3.0f32, 1.6, 65535.4, 7e4, f32::NAN],
+        )
+        .unwrap();
+        let (out, clipped) = encode(&img, false);
+        assert_eq!(out.into_raw_vec(), vec![0, 1, 65535, 65535, 0]);
+        assert_eq!(clipped, 2);
+        let (out, clipped) = encode(&img.mapv(f64::from), true);
+        assert_eq!(out.into_raw_vec(), vec![0, 2, 65535, 65535, 0]);
+        assert_eq!(clipped, 2);
+
+        // Clips of other threads, e.g. the archival one, are not counted
+        let before = clipped_count();
+        std::thread::spawn(|| count_clipped(5)).join().unwrap();
+        assert_eq!(clipped_count(), before);
+        count_clipped(2);
+        assert_eq!(clipped_count() - before, 2);
+    }
+
+    #[test]
+    fn test_calc_od() {
+        let frames = vec![
+            FrameConf::new("a", Role::Atoms, Some(0)),
+            FrameConf::new("b", Role::Bright, Some(0)),
+            FrameConf::new("k", Role::Kinetics, Some(1)),
+            FrameConf::new("d", Role::Dark, None),
+        ];
+        let dark = Array2::<u16>::from_elem((2, 3), 10);
+        let atoms = Array2::<u16>::from_elem((2, 3), 20);
+        let bright = Array2::<u16>::from_elem((2, 3), 50);
+        let mut kin = Array2::<u16>::from_elem((2, 3), 110);
+        kin.row_mut(0).fill(20);
+        let imgs = [atoms, bright, kin, dark];
+
+        let od = calc_od::<f64>(&frames, &imgs, 1.0).unwrap();
+        assert_eq!(od.dim(), (3, 3));
+        assert!((od[[0, 0]] - 4f64.ln()).abs() < 1e-12);
+        assert!((od[[2, 2]] - 10f64.ln()).abs() < 1e-12);
+
+        let od = calc_od::<f64>(&frames, &imgs, 2.0).unwrap();
+        assert!((od[[0, 0]] - 8f64.ln()).abs() < 1e-12);
+
+        // The last stage is the OD itself
+        let stages = stages(&frames, &imgs, 2.0).unwrap();
+        assert_eq!(stages.len(), 11);
+        assert_eq!(stages[0].0, "g0-atoms-minus-dark");
+        assert_eq!(stages[0].1[[0, 0]], 10.0);
+        let (name, last) = stages.last().unwrap();
+        assert_eq!(name, "od");
+        assert_eq!(last, &calc_od::<f32>(&frames, &imgs, 2.0).unwrap());
+
+        // Kinetics frames of odd height cannot be split in halves
+        let odd = Array2::<u16>::from_elem((3, 3), 50);
+        let imgs = [odd.clone(), odd.clone(), odd.clone(), odd];
+        assert!(calc_od::<f64>(&frames, &imgs, 1.0).is_err());
+        assert!(check_height(&frames[..2], 3).is_ok());
+    }
+
+    #[test]
+    fn test_calc_od_double_dark() {
+        let frames = vec![
+            FrameConf::new("k", Role::Kinetics, Some(0)),
+            FrameConf::new("da", Role::DarkAtoms, None),
+            FrameConf::new("db", Role::DarkBright, None),
+        ];
+        let mut kin = Array2::<u16>::from_elem((2, 1), 130);
+        kin[[0, 0]] = 30;
+        let dark_at = Array2::<u16>::from_elem((2, 1), 10);
+        let dark_br = Array2::<u16>::from_elem((2, 1), 30);
+        let imgs = [kin, dark_at, dark_br];
+
+        let od = calc_od::<f64>(&frames, &imgs, 1.0).unwrap();
+        assert_eq!(od.dim(), (1, 1));
+        assert!((od[[0, 0]] - 5f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_calc_od_mirrored() {
+        let mut kin = FrameConf::new("k", Role::Kinetics, Some(0));
+        kin.atoms = Half::Bottom;
+        kin.flip = true;
+        // Reference on top, atoms below, with two rows per half
+        let img = ndarray::array![[80, 80], [80, 80], [40, 40], [20, 20]];
+        let od = calc_od::<f64>(&[kin.clone()], &[img], 1.0).unwrap();
+        assert_eq!(od.dim(), (2, 2));
+        assert!((od[[0, 0]] - 4f64.ln()).abs() < 1e-12);
+        assert!((od[[1, 1]] - 2f64.ln()).abs() < 1e-12);
+
+        let mut dark = FrameConf::new("d", Role::Dark, None);
+        dark.flip = true;
+        assert!(check(&[kin, dark]).is_err());
+    }
+
+    #[test]
+    fn test_calc_var() {
+        let frames = vec![
+            FrameConf::new("k", Role::Kinetics, Some(0)),
+            FrameConf::new("d", Role::Dark, None),
+        ];
+        let kin = ndarray::array![[110u16], [210]];
+        let dark = ndarray::array![[10u16], [10]];
+        let noise = NoiseConf {
+            gain: 2.0,
+            read_noise: 3.0,
+            ..NoiseConf::default()
+        };
+        let var = calc_var(&frames, &[kin, dark], &noise).unwrap();
+        assert_eq!(var.dim(), (1, 1));
+        // Atoms 100 and bright 200 counts, read noise of frame and dark
+        let expected = (100.0 / 2.0 + 18.0) / 1e4 + (200.0 / 2.0 + 18.0) / 4e4;
+        assert!((var[[0, 0]] - expected).abs() < 1e-6);
+    }
+}
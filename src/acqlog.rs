@@ -0,0 +1,221 @@
+//! Pairing of acquire.py log lines with shots.
+//!
+//! The log file of acquire.py is tailed, and every line matching the
+//! configured regex is stored as the parameters of the shot in its `shot`
+//! group. All the other named groups of the regex become parameters. Lines
+//! of a shot that was already processed came too late to be paired, and a
+//! warning is logged; they are still kept, for the shot of the same id after
+//! a restart of the counter of acquire.py.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::textout::{Record, Value};
+
+/// Maximum number of shots whose parameters are kept while waiting for the
+/// images.
+const MAXPENDING: usize = 1000;
+
+/// Configuration of the acquire.py log tailer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcqLogConf {
+    /// Path of the acquire.py log file
+    pub path: String,
+    /// Regex matching parameter lines, must have a `shot` named group
+    pub regex: String,
+}
+
+/// Tailer of the acquire.py log
+#[derive(Debug)]
+pub struct AcqLog {
+    path: PathBuf,
+    re: Regex,
+    offset: u64,
+    partial: String,
+    pending: VecDeque<(String, Record)>,
+    /// Shots processed last, whose lines can no longer be paired
+    done: VecDeque<String>,
+}
+
+impl AcqLog {
+    /// Start tailing the log from its current end.
+    pub fn new(conf: &AcqLogConf) -> Result<AcqLog> {
+        let re = Regex::new(&conf.regex)
+            .context(format!("Invalid acquire.py log regex {}", conf.regex))?;
+        if !re.capture_names().any(|n| n == Some("shot")) {
+            bail!("acquire.py log regex must have a named group `shot`");
+        }
+
+        let path = PathBuf::from(&conf.path);
+        let offset = match path.metadata() {
+            Ok(m) => m.len(),
+            Err(_) => {
+                warn!("acquire.py log {:?} does not exist (yet)", path);
+                0
+            }
+        };
+        debug!("Tailing acquire.py log {:?} from {}", path, offset);
+
+        Ok(AcqLog {
+            path,
+            re,
+            offset,
+            partial: String::new(),
+            pending: VecDeque::new(),
+            done: VecDeque::new(),
+        })
+    }
+
+    /// Read the lines appended since the last poll.
+    pub fn poll(&mut self) -> Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) => {
+                debug!("Cannot open acquire.py log: {}", e);
+                return Ok(());
+            }
+        };
+
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            debug!("acquire.py log truncated, reading from start");
+            self.offset = 0;
+            self.partial.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        self.offset += buf.len() as u64;
+        self.partial.push_str(&String::from_utf8_lossy(&buf));
+
+        // Only complete lines are parsed, the rest waits for the next poll
+        let Some(end) = self.partial.rfind('\n') else {
+            return Ok(());
+        };
+        let complete: String = self.partial.drain(..=end).collect();
+        for line in complete.lines() {
+            self.parse(line);
+        }
+
+        Ok(())
+    }
+
+    fn parse(&mut self, line: &str) {
+        let Some(caps) = self.re.captures(line) else {
+            return;
+        };
+        let shot = caps["shot"].to_string();
+        let rec: Record = self
+            .re
+            .capture_names()
+            .flatten()
+            .filter(|n| *n != "shot")
+            .filter_map(|n| {
+                caps.name(n).map(|m| {
+                    (String::from(n), Value::Str(String::from(m.as_str())))
+                })
+            })
+            .collect();
+        if let Some(idx) = self.done.iter().position(|s| *s == shot) {
+            warn!(
+                "acquire.py parameters of shot {} logged after it was \
+                 processed, not paired",
+                shot
+            );
+            self.done.remove(idx);
+        }
+        debug!("Parameters for shot {}: {:?}", shot, rec);
+
+        if self.pending.len() >= MAXPENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((shot, rec));
+    }
+
+    /// Parameters of a shot, if they were logged.
+    pub fn get(&self, shot: &str) -> Option<Record> {
+        let idx = self.pending.iter().rposition(|(s, _)| s == shot)?;
+        self.pending.get(idx).map(|(_, r)| r.clone())
+    }
+
+    /// Remove and return the parameters of a shot, if they were logged.
+    /// The shot is done: parameters logged later are late.
+    pub fn take(&mut self, shot: &str) -> Option<Record> {
+        if self.done.len() >= MAXPENDING {
+            self.done.pop_front();
+        }
+        self.done.push_back(String::from(shot));
+        let idx = self.pending.iter().rposition(|(s, _)| s == shot)?;
+        self.pending.remove(idx).map(|(_, r)| r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::{AcqLog, AcqLogConf};
+    use crate::{
+        testutil::TempDir,
+        textout::{Record, Value},
+    };
+
+    #[test]
+    fn test_poll() {
+        let dir = TempDir::new("acqlog");
+        let path = dir.join("acquire.log");
+        fs::write(&path, "shot 1 species K\n").unwrap();
+        let conf = AcqLogConf {
+            path: path.to_string_lossy().into_owned(),
+            regex: String::from(r"shot (?P<shot>\d+) species (?P<species>\w+)"),
+        };
+        let bad = AcqLogConf {
+            regex: String::from(r"species (?P<species>\w+)"),
+            ..conf.clone()
+        };
+        assert!(AcqLog::new(&bad).is_err());
+        let append = |text: &str| {
+            let mut f =
+                fs::OpenOptions::new().append(true).open(&path).unwrap();
+            f.write_all(text.as_bytes()).unwrap();
+        };
+
+        // Lines already in the log are not read
+        let mut log = AcqLog::new(&conf).unwrap();
+        append("noise\nshot 2 species Rb\nshot 3 spec");
+        log.poll().unwrap();
+        assert!(log.get("1").is_none());
+        let species = |rec: Option<Record>| match rec.as_deref() {
+            Some([(k, Value::Str(v))]) if k == "species" => Some(v.clone()),
+            _ => None,
+        };
+        assert_eq!(species(log.get("2")).as_deref(), Some("Rb"));
+        // Partial lines wait for their end
+        assert!(log.get("3").is_none());
+        append("ies K\n");
+        log.poll().unwrap();
+        assert_eq!(species(log.get("3")).as_deref(), Some("K"));
+        assert_eq!(species(log.take("2")).as_deref(), Some("Rb"));
+        assert!(log.get("2").is_none());
+        // Too late for a shot already processed, kept for the next one
+        append("shot 2 species K\n");
+        log.poll().unwrap();
+        assert!(!log.done.contains(&String::from("2")));
+        assert_eq!(species(log.get("2")).as_deref(), Some("K"));
+
+        // Truncated logs are read again from their start
+        fs::write(&path, "shot 4 species K\n").unwrap();
+        log.poll().unwrap();
+        assert!(log.get("4").is_some());
+    }
+}
@@ -0,0 +1,82 @@
+//! UDP multicast announcement of processed shots.
+//!
+//! For every shot processed successfully a single datagram, holding a JSON
+//! object with the shot id and the path of the main output, is sent to a
+//! multicast group, so that any number of viewer stations on the lab LAN can
+//! react to new shots without connecting to acqmidproc.
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    textout::{self, NumFmt, Record, Value},
+    version::FORMAT_VERSION,
+};
+
+fn default_ttl() -> u32 {
+    1
+}
+
+/// Configuration of the shot announcements
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnounceConf {
+    /// IPv4 multicast group and port, e.g. `239.255.42.1:5005`
+    pub group: String,
+    /// Multicast TTL, 1 keeps datagrams on the local network
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+}
+
+/// Sender of the shot announcements
+#[derive(Debug)]
+pub struct Announcer {
+    socket: UdpSocket,
+    group: SocketAddr,
+}
+
+impl Announcer {
+    /// Open the socket, checking that the group is an IPv4 multicast address.
+    pub fn new(conf: &AnnounceConf) -> Result<Announcer> {
+        let group: SocketAddr = conf
+            .group
+            .parse()
+            .context(format!("Invalid announce group {:?}", conf.group))?;
+        if !group.is_ipv4() || !group.ip().is_multicast() {
+            bail!("Announce group {} is not an IPv4 multicast address", group);
+        }
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .context("Cannot open announcement socket")?;
+        socket.set_multicast_ttl_v4(conf.ttl)?;
+        debug!("Announcing shots to {} with ttl {}", group, conf.ttl);
+
+        Ok(Announcer { socket, group })
+    }
+
+    /// Announce a processed shot.
+    pub fn shot(&self, watch: &str, shot: &str, output: &Path) -> Result<()> {
+        let rec: Record = vec![
+            (
+                String::from("format_version"),
+                Value::Int(i64::from(FORMAT_VERSION)),
+            ),
+            (String::from("watch"), Value::Str(String::from(watch))),
+            (String::from("shot"), Value::Str(String::from(shot))),
+            (
+                String::from("output"),
+                Value::Str(output.to_string_lossy().into_owned()),
+            ),
+        ];
+        let msg = textout::json_object(&NumFmt::default(), &rec);
+        self.socket
+            .send_to(msg.as_bytes(), self.group)
+            .context(format!("Cannot announce shot to {}", self.group))?;
+        debug!("Announced {} to {}", msg, self.group);
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! Source for cameras appending frames to a single growing file.
+//!
+//! The file is polled, and every time enough bytes for a complete shot have
+//! been appended, its frames are written as separate sis images in the spool
+//! folder, named like acquire.py raw frames. The resulting paths are sent to
+//! the event queue as if the watcher had seen them being created. Shots are
+//! numbered on from the ones already in the file or the spool folder, also
+//! when the file is truncated or rotated, so that spooled frames are never
+//! overwritten.
+
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error, info};
+use ndarray::Array2;
+use notify_debouncer_full::DebounceEventResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{events, SisImg};
+
+fn default_poll_ms() -> u64 {
+    200
+}
+
+/// Configuration of the append-mode source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendConf {
+    /// Path of the growing file
+    pub path: String,
+    /// Height of each frame in pixels
+    pub height: usize,
+    /// Width of each frame in pixels
+    pub width: usize,
+    /// Bytes to skip before the pixels of each frame
+    #[serde(default)]
+    pub header: usize,
+    /// Number of frames in a shot
+    pub frames: usize,
+    /// Folder where extracted frames are written, outside the watched folders
+    pub spool: String,
+    /// Polling interval in milliseconds
+    #[serde(default = "default_poll_ms")]
+    pub poll_ms: u64,
+}
+
+impl AppendConf {
+    fn framesize(&self) -> u64 {
+        (self.header + 2 * self.height * self.width) as u64
+    }
+
+    fn shotsize(&self) -> u64 {
+        self.framesize() * self.frames as u64
+    }
+}
+
+/// Tailer of the growing file
+struct AppendSrc {
+    conf: AppendConf,
+    offset: u64,
+    /// Number of the next extracted shot
+    next: u64,
+}
+
+/// Number of the last shot in the spool folder, if any.
+fn last_spooled(spool: &str) -> Option<u64> {
+    fs::read_dir(spool)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.split_once("-rawimg-")?.0.parse::<u64>().ok()
+        })
+        .max()
+}
+
+impl AppendSrc {
+    /// Start from the last complete shot already in the file.
+    fn new(conf: AppendConf) -> AppendSrc {
+        let len = fs::metadata(&conf.path).map(|m| m.len()).unwrap_or(0);
+        let offset = len - len % conf.shotsize();
+        let spooled = last_spooled(&conf.spool).map_or(0, |n| n + 1);
+        let next = spooled.max(offset / conf.shotsize());
+        debug!(
+            "Tailing {:?} from offset {}, next shot {}",
+            conf.path, offset, next
+        );
+        AppendSrc { conf, offset, next }
+    }
+
+    /// Extract all the complete shots appended since the last poll.
+    fn poll(&mut self) -> Result<Vec<Vec<PathBuf>>> {
+        let mut file = match File::open(&self.conf.path) {
+            Ok(f) => f,
+            Err(e) => {
+                debug!("Cannot open {:?}: {}", self.conf.path, e);
+                return Ok(vec![]);
+            }
+        };
+
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            info!("{:?} truncated, restarting from its start", self.conf.path);
+            self.offset = 0;
+        }
+
+        let shotsize = self.conf.shotsize();
+        let mut shots = vec![];
+        while len - self.offset >= shotsize {
+            file.seek(SeekFrom::Start(self.offset))?;
+            let shotnum = self.next;
+            let mut paths = vec![];
+            for k in 0..self.conf.frames {
+                paths.push(self.extract(&mut file, shotnum, k)?);
+            }
+            self.offset += shotsize;
+            self.next += 1;
+            shots.push(paths);
+        }
+
+        Ok(shots)
+    }
+
+    fn extract(
+        &self,
+        file: &mut File,
+        shotnum: u64,
+        k: usize,
+    ) -> Result<PathBuf> {
+        let (height, width) = (self.conf.height, self.conf.width);
+        file.seek(SeekFrom::Current(self.conf.header as i64))?;
+        let mut buf = vec![0u8; 2 * height * width];
+        file.read_exact(&mut buf)?;
+        let mut image = vec![0u16; height * width];
+        LittleEndian::read_u16_into(&buf, &mut image);
+
+        let mut path = PathBuf::from(&self.conf.spool);
+        path.push(format!("{:08}-rawimg-{:04}.sis", shotnum, k + 1));
+        let arr = Array2::from_shape_vec((height, width), image)?;
+        SisImg::new(arr)?.write(path.clone())?;
+        debug!("Frame {} of shot {} extracted to {:?}", k, shotnum, path);
+
+        Ok(path)
+    }
+}
+
+/// Start polling the growing file in a separate thread.
+///
+/// Every complete shot is sent on `tx` as a batch of creation events.
+pub fn spawn(
+    conf: &AppendConf,
+    tx: Sender<DebounceEventResult>,
+) -> Result<JoinHandle<()>> {
+    if conf.shotsize() == 0 || conf.height == 0 || conf.width == 0 {
+        bail!("Append source needs non-zero frames, height and width");
+    }
+    fs::create_dir_all(&conf.spool)
+        .context(format!("Cannot create spool folder {}", conf.spool))?;
+    let poll = Duration::from_millis(conf.poll_ms);
+    let mut src = AppendSrc::new(conf.clone());
+
+    let handle = thread::spawn(move || loop {
+        match src.poll() {
+            Ok(shots) => {
+                for paths in shots {
+                    let batch = vec![events::created(paths)];
+                    if tx.send(Ok(batch)).is_err() {
+                        debug!("Event queue closed, stopping append source");
+                        return;
+                    }
+                }
+            }
+            Err(e) => error!("Error while reading appended frames: {:?}", e),
+        }
+        thread::sleep(poll);
+    });
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::PathBuf};
+
+    use super::{AppendConf, AppendSrc};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_numbering() {
+        let dir = TempDir::new("appendsrc");
+        let spool = dir.join("spool");
+        fs::create_dir_all(&spool).unwrap();
+        let path = dir.join("camera.raw");
+        let conf = AppendConf {
+            path: path.to_string_lossy().into_owned(),
+            height: 1,
+            width: 2,
+            header: 0,
+            frames: 1,
+            spool: spool.to_string_lossy().into_owned(),
+            poll_ms: 200,
+        };
+        let append = |shots: usize| {
+            let mut f =
+                fs::OpenOptions::new().append(true).open(&path).unwrap();
+            f.write_all(&vec![1u8; 4 * shots]).unwrap();
+        };
+        let names = |shots: Vec<Vec<PathBuf>>| {
+            shots
+                .concat()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Shots already in the file are skipped, but counted
+        fs::write(&path, [0u8; 8]).unwrap();
+        let mut src = AppendSrc::new(conf.clone());
+        append(1);
+        assert_eq!(names(src.poll().unwrap()), ["00000002-rawimg-0001.sis"]);
+
+        // Numbering goes on after truncation, and after a restart
+        fs::write(&path, []).unwrap();
+        append(1);
+        assert_eq!(names(src.poll().unwrap()), ["00000003-rawimg-0001.sis"]);
+        let mut src = AppendSrc::new(conf);
+        append(1);
+        assert_eq!(names(src.poll().unwrap()), ["00000004-rawimg-0001.sis"]);
+    }
+}
@@ -0,0 +1,84 @@
+//! Second, high-accuracy processing pass into an archival output tree.
+//!
+//! Live processing uses the fast path, to keep the latency for cam.py low.
+//! When an archive is configured, every shot processed successfully is also
+//! queued to a background thread, which processes it again with the accurate
+//! path of its processor and writes the result under
+//! `<archive outpath>/<watch entry name>/`, optionally gzip-compressed.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{codec::CodecConf, logctx, usage, Process};
+
+/// Configuration of the archival pass
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConf {
+    /// Root of the archival output tree
+    pub outpath: String,
+    /// Compression of the archived files
+    #[serde(default)]
+    pub codec: CodecConf,
+}
+
+/// A shot waiting for its archival pass
+#[derive(Debug)]
+pub struct Job {
+    /// Index of the watch entry of the shot
+    pub entry: usize,
+    /// Shot id, naming the archived outputs
+    pub shot: String,
+    /// Input files of the shot
+    pub paths: Vec<PathBuf>,
+    /// Correction factor of the bright frames
+    pub factor: f64,
+}
+
+/// Start the archival thread, with one processor per watch entry.
+pub fn spawn(
+    conf: &ArchiveConf,
+    procs: Vec<(String, Box<dyn Process>)>,
+) -> Result<Sender<Job>> {
+    conf.codec.check(false)?;
+    let codec = conf.codec.clone();
+    let mut dirs = vec![];
+    for (name, _) in &procs {
+        let dir = PathBuf::from(&conf.outpath).join(name);
+        fs::create_dir_all(&dir)
+            .context(format!("Cannot create archive folder {:?}", dir))?;
+        dirs.push(dir);
+    }
+
+    let (tx, rx) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        usage::rereading();
+        for job in rx {
+            let (name, proc) = &procs[job.entry];
+            let _ctx = logctx::enter(name);
+            debug!("Archiving shot {} from {:?}", job.shot, job.paths);
+            let dir = &dirs[job.entry];
+            let archived = proc
+                .accurate(&job.paths, dir, &job.shot, job.factor)
+                .and_then(|out| {
+                    out.iter()
+                        .map(|p| codec.compress(p))
+                        .collect::<Result<Vec<_>>>()
+                });
+            match archived {
+                Ok(out) => info!("Shot {} archived to {:?}", job.shot, out),
+                Err(e) => error!("Cannot archive shot {}: {:?}", job.shot, e),
+            }
+        }
+        debug!("Archive queue closed, stopping archival pass");
+    });
+
+    Ok(tx)
+}
@@ -0,0 +1,128 @@
+//! Back-pressure signal to acquire.py.
+//!
+//! When the number of event batches waiting to be processed, counting the
+//! shots waiting for a retry, reaches `high`, a flag file is created; it is
+//! removed when the backlog drains down to `low`. The backlog is checked
+//! about every second, also while no events arrive.
+//! acquire.py is expected to pause (or slow down) acquisition while the flag
+//! file exists. The file contains the queue length and the time it was last
+//! updated, for the benefit of humans looking at it.
+
+use std::{fs, path::PathBuf, time::SystemTime};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::textout;
+
+/// Configuration of the back-pressure flag
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackPressureConf {
+    /// Path of the flag file, must not be inside the watched folder
+    pub flagfile: String,
+    /// Queue length at which the flag is raised
+    pub high: usize,
+    /// Queue length at which the flag is cleared
+    pub low: usize,
+}
+
+/// State of the back-pressure flag
+#[derive(Debug)]
+pub struct BackPressure {
+    flagfile: PathBuf,
+    high: usize,
+    low: usize,
+    raised: bool,
+}
+
+impl BackPressure {
+    /// Create the back-pressure handler, removing any stale flag file.
+    pub fn new(conf: &BackPressureConf) -> Result<BackPressure> {
+        let flagfile = PathBuf::from(&conf.flagfile);
+        if flagfile.exists() {
+            warn!("Removing stale back-pressure flag {:?}", flagfile);
+            fs::remove_file(&flagfile)?;
+        }
+        let low = conf.low.min(conf.high);
+        debug!(
+            "Back-pressure flag {:?}, high {}, low {}",
+            flagfile, conf.high, low
+        );
+
+        Ok(BackPressure {
+            flagfile,
+            high: conf.high,
+            low,
+            raised: false,
+        })
+    }
+
+    /// Update the flag given the current queue length.
+    pub fn update(&mut self, queued: usize) -> Result<()> {
+        if queued >= self.high {
+            if !self.raised {
+                warn!(
+                    "Processing queue saturated ({} batches), asking \
+                     acquire.py to pause",
+                    queued
+                );
+            }
+            let content = format!(
+                "queued={}\nupdated={}\n",
+                queued,
+                textout::timestamp(SystemTime::now())
+            );
+            fs::write(&self.flagfile, content).context(format!(
+                "Cannot write back-pressure flag {:?}",
+                self.flagfile
+            ))?;
+            self.raised = true;
+        } else if self.raised && queued <= self.low {
+            info!("Processing queue drained, acquire.py can resume");
+            fs::remove_file(&self.flagfile).context(format!(
+                "Cannot remove back-pressure flag {:?}",
+                self.flagfile
+            ))?;
+            self.raised = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{BackPressure, BackPressureConf};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_hysteresis() {
+        let dir = TempDir::new("backpressure");
+        let flag = dir.join("pause");
+        fs::write(&flag, "stale").unwrap();
+        let conf = BackPressureConf {
+            flagfile: flag.to_string_lossy().into_owned(),
+            high: 10,
+            low: 3,
+        };
+        let mut bp = BackPressure::new(&conf).unwrap();
+        assert!(!flag.exists());
+
+        bp.update(9).unwrap();
+        assert!(!flag.exists());
+        bp.update(10).unwrap();
+        assert!(fs::read_to_string(&flag)
+            .unwrap()
+            .starts_with("queued=10\n"));
+        // Raised until the queue drains down to low
+        bp.update(5).unwrap();
+        assert!(flag.exists());
+        bp.update(3).unwrap();
+        assert!(!flag.exists());
+        bp.update(5).unwrap();
+        assert!(!flag.exists());
+    }
+}
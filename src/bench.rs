@@ -0,0 +1,157 @@
+//! Benchmark of the OD kernels.
+//!
+//! Every OD kernel is run on the same synthetic shot, and its timing and
+//! maximum deviation from the reference kernel (f64) are reported, to choose
+//! per-machine defaults. New kernels only need to be added to [`KERNELS`].
+//! The same synthetic shots are used to warm the processors up at startup.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use log::debug;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::absorption::{self, FrameConf, Role};
+
+fn default_size() -> usize {
+    1024
+}
+
+/// Configuration of the warm-up of the processors at startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WarmupConf {
+    /// Height of the synthetic raw frames
+    #[serde(default = "default_size")]
+    pub height: usize,
+    /// Width of the synthetic raw frames
+    #[serde(default = "default_size")]
+    pub width: usize,
+}
+
+/// An OD kernel: frame mapping, frames, and the correction factor of the
+/// bright frames
+type Kernel = fn(&[FrameConf], &[Array2<u16>], f64) -> Result<Array2<f64>>;
+
+fn od_f32(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+    factor: f64,
+) -> Result<Array2<f64>> {
+    Ok(
+        absorption::calc_od::<f32>(frames, imgs, factor as f32)?
+            .mapv(f64::from),
+    )
+}
+
+fn od_f64(
+    frames: &[FrameConf],
+    imgs: &[Array2<u16>],
+    factor: f64,
+) -> Result<Array2<f64>> {
+    absorption::calc_od(frames, imgs, factor)
+}
+
+/// Available kernels, the first one is the reference
+const KERNELS: [(&str, &str, Kernel); 2] = [
+    ("f64", "archival pass", od_f64),
+    ("f32", "live processing", od_f32),
+];
+
+/// Synthetic frame: background level plus noise, with a bright reference in
+/// the bottom half and a gaussian cloud absorbing it in the top half.
+fn frame(height: usize, width: usize, seed: u64, bright: bool) -> Array2<u16> {
+    // Small LCG, the data only has to be reproducible, not random
+    let mut state = seed;
+    let mut noise = move || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 59) as u16
+    };
+    let (cy, cx) = (height as f64 / 4.0, width as f64 / 2.0);
+    let sigma2 = (width as f64 / 8.0).powi(2);
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let mut val = 100.0;
+        if bright {
+            val += 3000.0;
+            if y < height / 2 {
+                let r2 = (y as f64 - cy).powi(2) + (x as f64 - cx).powi(2);
+                val -= 2500.0 * (-r2 / (2.0 * sigma2)).exp();
+            }
+        }
+        val as u16 + noise()
+    })
+}
+
+/// Synthetic shot for a frame mapping, one image per frame.
+pub fn shot(
+    frames: &[FrameConf],
+    height: usize,
+    width: usize,
+) -> Vec<Array2<u16>> {
+    frames
+        .iter()
+        .zip(1..)
+        .map(|(f, seed)| match f.role {
+            // Darks must stay below the other frames
+            Role::Dark | Role::DarkAtoms | Role::DarkBright => {
+                frame(height, width, seed, false).mapv(|x| x.min(100))
+            }
+            _ => frame(height, width, seed, true),
+        })
+        .collect()
+}
+
+/// Run all the kernels on a synthetic shot of our default frame mapping,
+/// and print the comparison.
+pub fn run(height: usize, width: usize, repeat: u32) -> Result<()> {
+    if height < 2 || width == 0 || repeat == 0 {
+        bail!("Benchmark needs height >= 2, width >= 1 and repeat >= 1");
+    }
+    let frames = absorption::default_frames();
+    let imgs = shot(&frames, height, width);
+    let factor = 1.0;
+    debug!("Benchmarking on {}x{} frames", height, width);
+
+    println!(
+        "{} x {} frames, {} repetitions, deviation from {}",
+        height, width, repeat, KERNELS[0].0
+    );
+    println!(
+        "{:<8} {:<16} {:>12} {:>12} {:>14}",
+        "kernel", "used for", "mean [ms]", "min [ms]", "max deviation"
+    );
+
+    let mut reference: Option<Array2<f64>> = None;
+    for (name, usage, kernel) in KERNELS {
+        let mut out = kernel(&frames, &imgs, factor)?;
+        let (mut total, mut min) = (Duration::ZERO, Duration::MAX);
+        for _ in 0..repeat {
+            let start = Instant::now();
+            out = kernel(&frames, &imgs, factor)?;
+            let elapsed = start.elapsed();
+            total += elapsed;
+            min = min.min(elapsed);
+        }
+
+        let deviation = match &reference {
+            Some(r) => (&out - r).fold(0.0, |m: f64, x| m.max(x.abs())),
+            None => 0.0,
+        };
+        if reference.is_none() {
+            reference = Some(out);
+        }
+        println!(
+            "{:<8} {:<16} {:>12.3} {:>12.3} {:>14.3e}",
+            name,
+            usage,
+            total.as_secs_f64() * 1000.0 / f64::from(repeat),
+            min.as_secs_f64() * 1000.0,
+            deviation
+        );
+    }
+
+    Ok(())
+}
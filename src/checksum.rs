@@ -0,0 +1,186 @@
+//! Checksum verification of inputs arriving with companion files.
+//!
+//! When a frame `x.sis` arrives together with `x.sis.md5` or `x.sis.sha256`
+//! (in the format written by `md5sum`/`sha256sum`), its content is verified
+//! before processing. A mismatch usually means that the transfer is still in
+//! progress, so the shot is retried a few times before being quarantined.
+//! The companion itself can arrive after the frame: a frame without one is
+//! waited for just as long, then processed unverified, with a warning.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Extensions of the companion checksum files
+const EXTENSIONS: [&str; 2] = ["md5", "sha256"];
+
+fn default_retries() -> u32 {
+    5
+}
+
+fn default_retry_s() -> f64 {
+    2.0
+}
+
+/// Configuration of checksum verification
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChecksumConf {
+    /// Number of retries before quarantining a shot
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Seconds to wait between retries
+    #[serde(default = "default_retry_s")]
+    pub retry_s: f64,
+    /// Folder where shots that never verify are moved
+    pub quarantine: String,
+}
+
+/// Result of the verification of a file
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Checksum matches
+    Ok,
+    /// There is no companion checksum file
+    NoChecksum,
+    /// Checksum does not match (yet)
+    Mismatch,
+}
+
+/// Whether the path is a companion checksum file.
+pub fn is_companion(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| EXTENSIONS.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+/// Existing companion checksum files of a path.
+pub fn companions(path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(".");
+            name.push(ext);
+            PathBuf::from(name)
+        })
+        .filter(|p| p.is_file())
+        .collect()
+}
+
+/// Verify a file against its companion checksum files, if there are any.
+///
+/// The checksum is computed on the file as it is on disk, i.e. before any
+/// decompression.
+pub fn verify(path: &Path) -> Result<Verdict> {
+    let comps = companions(path);
+    if comps.is_empty() {
+        return Ok(Verdict::NoChecksum);
+    }
+
+    let data = fs::read(path).context(format!("Cannot read {:?}", path))?;
+    for comp in comps {
+        let text = fs::read_to_string(&comp)
+            .context(format!("Cannot read checksum file {:?}", comp))?;
+        let expected = text
+            .split_whitespace()
+            .next()
+            .ok_or(anyhow!("Checksum file {:?} is empty", comp))?
+            .to_lowercase();
+        let actual = if comp.extension().is_some_and(|e| e == "md5") {
+            format!("{:x}", Md5::digest(&data))
+        } else {
+            format!("{:x}", Sha256::digest(&data))
+        };
+        debug!("{:?}: expected {}, actual {}", comp, expected, actual);
+        if expected != actual {
+            return Ok(Verdict::Mismatch);
+        }
+    }
+
+    Ok(Verdict::Ok)
+}
+
+/// Move the files of a shot, with their companions, to the quarantine folder.
+pub fn quarantine(conf: &ChecksumConf, paths: &[PathBuf]) -> Result<()> {
+    let dir = PathBuf::from(&conf.quarantine);
+    fs::create_dir_all(&dir)
+        .context(format!("Cannot create quarantine folder {:?}", dir))?;
+
+    for p in paths.iter().flat_map(|p| {
+        let mut all = vec![p.clone()];
+        all.extend(companions(p));
+        all
+    }) {
+        let Some(fname) = p.file_name() else {
+            continue;
+        };
+        let dest = dir.join(fname);
+        // Rename fails across filesystems, fall back to copy and remove
+        if fs::rename(&p, &dest).is_err() {
+            fs::copy(&p, &dest)
+                .and_then(|_| fs::remove_file(&p))
+                .context(format!("Cannot quarantine {:?}", p))?;
+        }
+        error!("Quarantined {:?} to {:?}", p, dest);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use md5::Md5;
+    use sha2::{Digest, Sha256};
+
+    use super::{is_companion, quarantine, verify, ChecksumConf, Verdict};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_verify_quarantine() {
+        let dir = TempDir::new("checksum");
+        let (a, b) = (dir.join("1-rawimg-0001.sis"), dir.join("1-raw.sis"));
+        fs::write(&a, "frame").unwrap();
+        fs::write(&b, "frame").unwrap();
+        assert_eq!(verify(&a).unwrap(), Verdict::NoChecksum);
+
+        let md5 = format!("{:x}  1-rawimg-0001.sis\n", Md5::digest(b"frame"));
+        fs::write(dir.join("1-rawimg-0001.sis.md5"), md5).unwrap();
+        assert_eq!(verify(&a).unwrap(), Verdict::Ok);
+        // Every companion must match, e.g. while the transfer is going on
+        let sha = format!("{:x}\n", Sha256::digest(b"fra")).to_uppercase();
+        fs::write(dir.join("1-rawimg-0001.sis.sha256"), sha).unwrap();
+        assert_eq!(verify(&a).unwrap(), Verdict::Mismatch);
+        assert!(is_companion(&dir.join("1-rawimg-0001.sis.SHA256")));
+        assert!(!is_companion(&a));
+
+        let conf = ChecksumConf {
+            retries: 5,
+            retry_s: 2.0,
+            quarantine: dir.join("quarantine").to_string_lossy().into_owned(),
+        };
+        quarantine(&conf, &[a.clone(), b.clone()]).unwrap();
+        let mut moved = fs::read_dir(dir.join("quarantine"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        moved.sort();
+        assert_eq!(
+            moved,
+            [
+                "1-raw.sis",
+                "1-rawimg-0001.sis",
+                "1-rawimg-0001.sis.md5",
+                "1-rawimg-0001.sis.sha256"
+            ]
+        );
+        assert!(!a.exists() && !b.exists());
+    }
+}
@@ -0,0 +1,263 @@
+//! Compression codecs of the archive sinks.
+//!
+//! OD images and raw frames compress very differently, and archive size has
+//! to be traded against write latency on each machine. Data can be filtered
+//! before compression: `byte` shuffle groups the n-th bytes of all elements
+//! together, `bit` shuffle groups their n-th bits, which helps a lot with
+//! noisy low bits. Filters change the layout of the data, so they are only
+//! accepted by sinks that record them in their metadata; plain archived
+//! files are only ever gzip-compressed, into standard `.gz` files. Only the
+//! gzip codec is built in (levels 0 to 9): zstd is accepted in the
+//! configuration but refused at startup, there is no zstd library in this
+//! build.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::usage;
+
+fn default_level() -> u32 {
+    6
+}
+
+/// Compression codec
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Stored as is
+    #[default]
+    None,
+    Gzip,
+    /// Not built in, refused by [`CodecConf::check`]
+    Zstd,
+}
+
+/// Filter applied before compression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shuffle {
+    #[default]
+    None,
+    /// Byte planes of the elements
+    Byte,
+    /// Bit planes of the elements
+    Bit,
+}
+
+/// Configuration of the compression of an archive sink
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CodecConf {
+    /// Compression codec
+    #[serde(default)]
+    pub codec: Codec,
+    /// Compression level, 0 (fastest) to 9 (smallest)
+    #[serde(default = "default_level")]
+    pub level: u32,
+    /// Filter applied before compression
+    #[serde(default)]
+    pub shuffle: Shuffle,
+}
+
+impl Default for CodecConf {
+    fn default() -> Self {
+        CodecConf {
+            codec: Codec::None,
+            level: default_level(),
+            shuffle: Shuffle::None,
+        }
+    }
+}
+
+/// Group the n-th bytes of all the elements together.
+fn byteshuffle(bytes: &[u8], elemsize: usize) -> Vec<u8> {
+    let n = bytes.len() / elemsize;
+    let mut out = Vec::with_capacity(bytes.len());
+    for b in 0..elemsize {
+        out.extend((0..n).map(|i| bytes[i * elemsize + b]));
+    }
+    // Trailing bytes of an incomplete element are kept as they are
+    out.extend_from_slice(&bytes[n * elemsize..]);
+    out
+}
+
+/// Group the n-th bits of all the elements together, in blocks of 8
+/// elements; trailing elements are byte shuffled.
+fn bitshuffle(bytes: &[u8], elemsize: usize) -> Vec<u8> {
+    let n = bytes.len() / elemsize / 8 * 8;
+    let mut out = vec![0u8; n * elemsize];
+    for bit in 0..8 * elemsize {
+        let (byte, shift) = (bit / 8, bit % 8);
+        for i in 0..n {
+            let set = (bytes[i * elemsize + byte] >> shift) & 1;
+            let pos = bit * n + i;
+            out[pos / 8] |= set << (pos % 8);
+        }
+    }
+    out.extend(byteshuffle(&bytes[n * elemsize..], elemsize));
+    out
+}
+
+impl CodecConf {
+    /// Check the configuration, for a sink recording filters or not.
+    pub fn check(&self, filters: bool) -> Result<()> {
+        if self.codec == Codec::Zstd {
+            bail!("The zstd codec is not built in, use gzip");
+        }
+        if self.level > 9 {
+            bail!("Compression level {} is not 0 to 9", self.level);
+        }
+        if self.shuffle != Shuffle::None && !filters {
+            bail!("Shuffle filters need a sink recording them in metadata");
+        }
+        if self.shuffle != Shuffle::None && self.codec == Codec::None {
+            warn!("Shuffle filter without a codec does not reduce the size");
+        }
+        Ok(())
+    }
+
+    /// Filter and compress data made of elements of `elemsize` bytes.
+    pub fn encode(&self, bytes: &[u8], elemsize: usize) -> Result<Vec<u8>> {
+        let filtered = match self.shuffle {
+            Shuffle::None => bytes.to_vec(),
+            Shuffle::Byte => byteshuffle(bytes, elemsize.max(1)),
+            Shuffle::Bit => bitshuffle(bytes, elemsize.max(1)),
+        };
+        match self.codec {
+            Codec::None => Ok(filtered),
+            Codec::Gzip => {
+                let level = Compression::new(self.level);
+                let mut enc = GzEncoder::new(vec![], level);
+                enc.write_all(&filtered)?;
+                Ok(enc.finish()?)
+            }
+            Codec::Zstd => bail!("The zstd codec is not built in"),
+        }
+    }
+
+    /// Compress an archived file into `<file>.gz`, removing the original.
+    /// Returns the path of the archived file.
+    pub fn compress(&self, path: &Path) -> Result<PathBuf> {
+        if self.codec == Codec::None {
+            return Ok(path.to_path_buf());
+        }
+        let bytes =
+            fs::read(path).context(format!("Cannot read {:?}", path))?;
+        let mut dest = path.as_os_str().to_os_string();
+        dest.push(".gz");
+        let dest = PathBuf::from(dest);
+        fs::write(&dest, self.encode(&bytes, 1)?)
+            .context(format!("Cannot write {:?}", dest))?;
+        usage::written(&dest);
+        fs::remove_file(path)
+            .context(format!("Cannot remove uncompressed {:?}", path))?;
+        debug!("Compressed {:?} to {:?}", path, dest);
+        Ok(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use flate2::read::GzDecoder;
+
+    use super::{bitshuffle, byteshuffle, Codec, CodecConf, Shuffle};
+    use crate::testutil::TempDir;
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        GzDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// Inverse of the byte shuffle, as done by the readers.
+    fn unbyteshuffle(bytes: &[u8], elemsize: usize) -> Vec<u8> {
+        let n = bytes.len() / elemsize;
+        let mut out = vec![0u8; bytes.len()];
+        for b in 0..elemsize {
+            for i in 0..n {
+                out[i * elemsize + b] = bytes[b * n + i];
+            }
+        }
+        out[n * elemsize..].copy_from_slice(&bytes[n * elemsize..]);
+        out
+    }
+
+    /// Inverse of the bit shuffle, as done by the readers.
+    fn unbitshuffle(bytes: &[u8], elemsize: usize) -> Vec<u8> {
+        let n = bytes.len() / elemsize / 8 * 8;
+        let mut out = vec![0u8; n * elemsize];
+        for bit in 0..8 * elemsize {
+            let (byte, shift) = (bit / 8, bit % 8);
+            for i in 0..n {
+                let pos = bit * n + i;
+                let set = (bytes[pos / 8] >> (pos % 8)) & 1;
+                out[i * elemsize + byte] |= set << shift;
+            }
+        }
+        out.extend(unbyteshuffle(&bytes[n * elemsize..], elemsize));
+        out
+    }
+
+    #[test]
+    fn test_shuffle() {
+        // u16 elements 1, 2, 3 and a trailing byte
+        let bytes = [1, 0, 2, 0, 3, 0, 9];
+        assert_eq!(byteshuffle(&bytes, 2), vec![1, 2, 3, 0, 0, 0, 9]);
+
+        // Bit 0 of 8 bytes into the first byte, bit 1 into the second...
+        let ones = [1u8; 8];
+        assert_eq!(bitshuffle(&ones, 1), vec![255, 0, 0, 0, 0, 0, 0, 0]);
+        let mut mixed = [0u8; 9];
+        mixed[3] = 0b10;
+        mixed[8] = 7;
+        assert_eq!(bitshuffle(&mixed, 1), vec![0, 8, 0, 0, 0, 0, 0, 0, 7]);
+
+        let conf = CodecConf {
+            shuffle: Shuffle::Bit,
+            ..CodecConf::default()
+        };
+        assert!(conf.check(false).is_err());
+        assert!(conf.check(true).is_ok());
+        assert_eq!(conf.encode(&ones, 1).unwrap()[0], 255);
+    }
+
+    #[test]
+    fn test_gzip_roundtrip() {
+        // u16 elements, with a partial block of 8 and a trailing byte
+        let bytes =
+            (0..43u32).map(|i| (i * 37 % 256) as u8).collect::<Vec<_>>();
+        let mut conf = CodecConf {
+            codec: Codec::Gzip,
+            ..CodecConf::default()
+        };
+        assert_eq!(gunzip(&conf.encode(&bytes, 2).unwrap()), bytes);
+        conf.shuffle = Shuffle::Byte;
+        let packed = gunzip(&conf.encode(&bytes, 2).unwrap());
+        assert_eq!(unbyteshuffle(&packed, 2), bytes);
+        conf.shuffle = Shuffle::Bit;
+        let packed = gunzip(&conf.encode(&bytes, 2).unwrap());
+        assert_eq!(unbitshuffle(&packed, 2), bytes);
+
+        // Plain files become standard .gz files
+        let dir = TempDir::new("codec");
+        let path = dir.join("1-od.sis");
+        fs::write(&path, &bytes).unwrap();
+        conf.shuffle = Shuffle::None;
+        let dest = conf.compress(&path).unwrap();
+        assert_eq!(dest, dir.join("1-od.sis.gz"));
+        assert!(!path.exists());
+        assert_eq!(gunzip(&fs::read(&dest).unwrap()), bytes);
+
+        conf.codec = Codec::Zstd;
+        assert!(conf.check(true).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! False-color mapping of previews, shared by all visual outputs.
+//!
+//! Values are first scaled to 256 levels, either within a fixed `range`
+//! (comparable from shot to shot) or within the percentile `window` of each
+//! shot, then mapped through a lookup table of 256 colors: `gray`, `viridis`,
+//! `inferno`, or a `lut` read from a text file of `r g b` lines (0 to 255),
+//! interpolated to 256 entries. The `[colormap]` section applies to every
+//! preview; each sink can override it with its own `colormap` table.
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::window::WindowConf;
+
+/// Samples of viridis at 0, 1/8, ..., 1
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 45, 123],
+    [59, 82, 139],
+    [44, 114, 142],
+    [33, 145, 140],
+    [40, 174, 128],
+    [94, 201, 98],
+    [173, 220, 48],
+    [253, 231, 37],
+];
+
+/// Samples of inferno at 0, 1/8, ..., 1
+const INFERNO: [[u8; 3]; 9] = [
+    [0, 0, 4],
+    [31, 12, 72],
+    [85, 15, 109],
+    [136, 34, 106],
+    [186, 54, 85],
+    [227, 89, 51],
+    [249, 142, 9],
+    [249, 203, 53],
+    [252, 255, 164],
+];
+
+/// Color scale
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    #[default]
+    Gray,
+    Viridis,
+    Inferno,
+    /// Lookup table from the `lut` file
+    Lut,
+}
+
+/// Configuration of the false-color mapping
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColormapConf {
+    /// Color scale
+    #[serde(default)]
+    pub scale: Scale,
+    /// Text file of the lookup table, for the `lut` scale
+    #[serde(default)]
+    pub lut: Option<String>,
+    /// Fixed values mapped to the ends of the scale, instead of the window
+    #[serde(default)]
+    pub range: Option<[f64; 2]>,
+    /// Percentiles of each shot mapped to the ends of the scale
+    #[serde(default)]
+    pub window: WindowConf,
+}
+
+/// Ready to use false-color mapping
+#[derive(Debug, Clone)]
+pub struct Colormap {
+    lut: [[u8; 3]; 256],
+    range: Option<[f64; 2]>,
+    window: WindowConf,
+}
+
+/// Interpolate colors to 256 entries.
+fn interpolate(colors: &[[u8; 3]]) -> [[u8; 3]; 256] {
+    let mut lut = [[0; 3]; 256];
+    let last = (colors.len() - 1) as f64;
+    for (i, entry) in lut.iter_mut().enumerate() {
+        let pos = i as f64 / 255.0 * last;
+        let k = (pos.floor() as usize).min(colors.len() - 2);
+        let frac = pos - k as f64;
+        for (c, x) in entry.iter_mut().enumerate() {
+            let a = f64::from(colors[k][c]);
+            let b = f64::from(colors[k + 1][c]);
+            *x = (a + frac * (b - a)).round() as u8;
+        }
+    }
+    lut
+}
+
+/// Read a lookup table of `r g b` lines, ignoring blank and `#` lines.
+fn readlut(path: &Path) -> Result<Vec<[u8; 3]>> {
+    let text = fs::read_to_string(path)
+        .context(format!("Cannot read colormap {:?}", path))?;
+    let mut colors = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rgb = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>();
+        match rgb.as_deref() {
+            Ok(&[r, g, b]) => colors.push([r, g, b]),
+            _ => bail!("Line {} of {:?} is not r g b: {:?}", n + 1, path, line),
+        }
+    }
+    if colors.len() < 2 || colors.len() > 256 {
+        bail!(
+            "Colormap {:?} has {} colors, not 2 to 256",
+            path,
+            colors.len()
+        );
+    }
+    Ok(colors)
+}
+
+impl Colormap {
+    /// Build the lookup table of the configured scale.
+    pub fn new(conf: &ColormapConf) -> Result<Colormap> {
+        let lut = match (conf.scale, &conf.lut) {
+            (Scale::Gray, _) => interpolate(&[[0; 3], [255; 3]]),
+            (Scale::Viridis, _) => interpolate(&VIRIDIS),
+            (Scale::Inferno, _) => interpolate(&INFERNO),
+            (Scale::Lut, Some(lut)) => interpolate(&readlut(Path::new(lut))?),
+            (Scale::Lut, None) => bail!("Colormap scale lut needs a lut file"),
+        };
+        Ok(Colormap {
+            lut,
+            range: conf.range,
+            window: conf.window,
+        })
+    }
+
+    /// The 256 colors of the scale.
+    pub fn lut(&self) -> &[[u8; 3]; 256] {
+        &self.lut
+    }
+
+    /// Levels of the scale of each pixel, indices into the lookup table.
+    pub fn levels(&self, img: &Array2<f64>) -> Array2<u8> {
+        let Some([lo, hi]) = self.range else {
+            return self.window.apply(img);
+        };
+        let span = if hi > lo { hi - lo } else { 1.0 };
+        img.mapv(|x| {
+            if x.is_finite() {
+                ((x - lo) / span * 255.0).round().clamp(0.0, 255.0) as u8
+            } else {
+                0
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::{interpolate, Colormap, ColormapConf, Scale, VIRIDIS};
+
+    #[test]
+    fn test_colormap() {
+        let lut = interpolate(&VIRIDIS);
+        assert_eq!((lut[0], lut[255]), (VIRIDIS[0], VIRIDIS[8]));
+        let gray = interpolate(&[[0; 3], [255; 3]]);
+        assert!(gray.iter().enumerate().all(|(i, c)| c[0] as usize == i));
+
+        let conf = ColormapConf {
+            scale: Scale::Viridis,
+            range: Some([0.0, 2.0]),
+            ..ColormapConf::default()
+        };
+        let cmap = Colormap::new(&conf).unwrap();
+        let levels = cmap.levels(&array![[-1.0, 1.0, 5.0]]);
+        assert_eq!(levels, array![[0, 128, 255]]);
+        assert_eq!(cmap.lut()[255], VIRIDIS[8]);
+
+        let lut = ColormapConf {
+            scale: Scale::Lut,
+            ..ColormapConf::default()
+        };
+        assert!(Colormap::new(&lut).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Consumers of the processed shots, each getting only the shots it wants.
+//!
+//! Every imaging station runs its own cam.py, which only cares about some
+//! shots, e.g. those of one species or one camera. Each `[[consumer]]` has a
+//! filter `when` on the attributes of a shot, in the language of the routing
+//! rules, and receives the shots passing it: their outputs are copied into
+//! its `dest` folder, and/or announced to its own multicast group
+//! `announce`. A consumer without `when` receives every shot. The
+//! attributes are `watch` and `shot`, the routing scalars `mean`, `max`,
+//! `sum` and `outputs`, and the acquire.py parameters of the shot, e.g.
+//! `species == "K" and camera == "side"`. Consumers are served after all the
+//! other sinks, and their copies are part of the transaction of the shot.
+//! Files already in `dest` with the same name, e.g. with fixed naming, are
+//! replaced; if the shot is rolled back the copies are removed, not the
+//! replaced files restored, as for the fixed-name outputs.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    announce::{AnnounceConf, Announcer},
+    hooks::{self, Attr, Filter},
+    textout::{self, Record, Value},
+    transaction, usage,
+};
+
+/// Configuration of a consumer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsumerConf {
+    /// Name of the consumer, for the log
+    pub name: String,
+    /// Condition on the attributes of the shot, every shot if not given
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Folder the outputs are copied into
+    #[serde(default)]
+    pub dest: Option<String>,
+    /// Multicast group the shots are announced to
+    #[serde(default)]
+    pub announce: Option<AnnounceConf>,
+}
+
+impl ConsumerConf {
+    /// Check the configuration, without opening anything.
+    pub fn check(&self) -> Result<Option<Filter>> {
+        if self.dest.is_none() && self.announce.is_none() {
+            bail!("Consumer {} has neither dest nor announce", self.name);
+        }
+        self.when.as_deref().map(Filter::new).transpose()
+    }
+}
+
+/// Consumer of the processed shots
+#[derive(Debug)]
+pub struct Consumer {
+    name: String,
+    filter: Option<Filter>,
+    dest: Option<PathBuf>,
+    announcer: Option<Announcer>,
+}
+
+impl Consumer {
+    /// Check the configuration, create the folder and open the socket.
+    pub fn new(conf: &ConsumerConf) -> Result<Consumer> {
+        let filter = conf.check()?;
+        if let Some(dest) = &conf.dest {
+            fs::create_dir_all(dest)
+                .context(format!("Cannot create consumer folder {}", dest))?;
+        }
+        let announcer = conf.announce.as_ref().map(Announcer::new);
+        Ok(Consumer {
+            name: conf.name.clone(),
+            filter,
+            dest: conf.dest.as_ref().map(PathBuf::from),
+            announcer: announcer.transpose()?,
+        })
+    }
+
+    /// Announce a shot delivered to the consumer.
+    pub fn notify(&self, watch: &str, shot: &str, output: &Path) -> Result<()> {
+        match &self.announcer {
+            Some(ann) => ann
+                .shot(watch, shot, output)
+                .context(format!("Cannot notify consumer {}", self.name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Attributes of a shot the filters are evaluated on.
+pub fn attributes(
+    watch: &str,
+    shot: &str,
+    outputs: &[PathBuf],
+    params: Option<Record>,
+) -> Result<Vec<(String, Attr)>> {
+    let mut attrs = vec![
+        (String::from("watch"), Attr::Str(String::from(watch))),
+        (String::from("shot"), Attr::Str(String::from(shot))),
+    ];
+    if let Some(main) = outputs.last() {
+        let scalars = hooks::scalars(main, outputs.len())?;
+        attrs.extend(scalars.into_iter().map(|(k, v)| (String::from(k), v)));
+    }
+    for (k, v) in params.unwrap_or_default() {
+        let attr = match v {
+            Value::Int(i) => Attr::Num(i as f64),
+            Value::Float(x) => Attr::Num(x),
+            Value::Str(s) => Attr::Str(s),
+            Value::Time(t) => Attr::Str(textout::timestamp(t)),
+        };
+        attrs.push((k, attr));
+    }
+    Ok(attrs)
+}
+
+/// Copy the outputs of a shot to the consumers whose filter it passes.
+/// Returns the index of each of them, with the path of the main output it
+/// is to be told about.
+pub fn deliver(
+    consumers: &[Consumer],
+    attrs: &[(String, Attr)],
+    outputs: &[PathBuf],
+) -> Result<Vec<(usize, PathBuf)>> {
+    let Some(main) = outputs.last() else {
+        return Ok(vec![]);
+    };
+    let mut matched = vec![];
+    for (n, c) in consumers.iter().enumerate() {
+        if !c.filter.as_ref().is_none_or(|f| f.matches(attrs)) {
+            continue;
+        }
+        let Some(dest) = &c.dest else {
+            matched.push((n, main.clone()));
+            continue;
+        };
+        let mut copied = main.clone();
+        for o in outputs {
+            let Some(name) = o.file_name() else {
+                continue;
+            };
+            let to = dest.join(name);
+            fs::copy(o, &to)
+                .context(format!("Cannot copy {:?} to {:?}", o, to))?;
+            usage::written(&to);
+            transaction::record(&to);
+            copied = to;
+        }
+        debug!("Shot delivered to consumer {} in {:?}", c.name, dest);
+        matched.push((n, copied));
+    }
+    if !consumers.is_empty() {
+        info!(
+            "Shot for {} of {} consumers",
+            matched.len(),
+            consumers.len()
+        );
+    }
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{deliver, Consumer, ConsumerConf};
+    use crate::{hooks::Attr, testutil::TempDir};
+
+    #[test]
+    fn test_deliver() {
+        let dir = TempDir::new("consumers");
+        let conf = |name: &str, when: &str| ConsumerConf {
+            name: String::from(name),
+            when: Some(String::from(when)),
+            dest: Some(dir.join(name).to_string_lossy().into_owned()),
+            announce: None,
+        };
+        let consumers = [
+            Consumer::new(&conf("k", "species == 'K'")).unwrap(),
+            Consumer::new(&conf("rb", "species == 'Rb'")).unwrap(),
+        ];
+        let bad = ConsumerConf {
+            dest: None,
+            ..conf("none", "species == 'K'")
+        };
+        assert!(bad.check().is_err());
+        assert!(conf("bad", "species < 'K'").check().is_err());
+
+        let (od, meta) = (dir.join("1-od.sis"), dir.join("1-meta.json"));
+        fs::write(&meta, "{}").unwrap();
+        fs::write(&od, "x").unwrap();
+        let attrs = vec![(String::from("species"), Attr::Str("K".into()))];
+        let outputs = vec![meta, od];
+        let matched = deliver(&consumers, &attrs, &outputs).unwrap();
+        assert_eq!(matched, vec![(0, dir.join("k/1-od.sis"))]);
+        assert!(dir.join("k/1-meta.json").exists());
+        assert!(!dir.join("rb/1-od.sis").exists());
+    }
+}
@@ -0,0 +1,108 @@
+//! Control commands to the running daemon.
+//!
+//! A command is sent by creating a file named after it in the control
+//! folder, e.g. `acqmidproc abort-run` creates `<dir>/abort-run`. The daemon
+//! looks for command files at least once a second, removes them and acts on
+//! them. The file holds who sent the command and when, for the log.
+//! The control folder must not be inside a watched folder.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::textout;
+
+/// Configuration of the control commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlConf {
+    /// Folder of the command files
+    pub dir: String,
+}
+
+/// Command to the daemon
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    /// Drop the pending work of the current run
+    AbortRun,
+}
+
+impl Request {
+    const ALL: [Request; 1] = [Request::AbortRun];
+
+    /// Name of the command file.
+    fn name(self) -> &'static str {
+        match self {
+            Request::AbortRun => "abort-run",
+        }
+    }
+
+    fn path(self, conf: &ControlConf) -> PathBuf {
+        Path::new(&conf.dir).join(self.name())
+    }
+}
+
+/// Send a command to the running daemon.
+pub fn send(conf: Option<&ControlConf>, req: Request) -> Result<()> {
+    let Some(conf) = conf else {
+        bail!("No [control] section configured, the daemon cannot be told");
+    };
+    fs::create_dir_all(&conf.dir)
+        .context(format!("Cannot create control folder {}", conf.dir))?;
+    let path = req.path(conf);
+    let text = format!(
+        "pid={}\ntime={}\n",
+        std::process::id(),
+        textout::timestamp(SystemTime::now())
+    );
+    fs::write(&path, text).context(format!("Cannot write {:?}", path))?;
+    println!("Sent {} to the daemon", req.name());
+    Ok(())
+}
+
+/// Take the commands sent since the last poll.
+pub fn poll(conf: &ControlConf) -> Vec<Request> {
+    let mut reqs = vec![];
+    for req in Request::ALL {
+        let path = req.path(conf);
+        let Ok(text) = fs::read_to_string(&path) else {
+            continue;
+        };
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Cannot remove command file {:?}: {}", path, e);
+            continue;
+        }
+        info!(
+            "Received {} ({})",
+            req.name(),
+            text.trim().replace('\n', ", ")
+        );
+        reqs.push(req);
+    }
+    reqs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{poll, send, ControlConf, Request};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_control() {
+        let dir = TempDir::new("control");
+        let conf = ControlConf {
+            dir: dir.to_string_lossy().into_owned(),
+        };
+        assert!(send(None, Request::AbortRun).is_err());
+        assert!(poll(&conf).is_empty());
+        send(Some(&conf), Request::AbortRun).unwrap();
+        assert_eq!(poll(&conf), vec![Request::AbortRun]);
+        // Acted on only once
+        assert!(poll(&conf).is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! Externally measured per-shot correction factors.
+//!
+//! An external program (e.g. the probe intensity monitor) keeps a file
+//! mapping shot ids to correction factors up to date during the run. The
+//! factor of a shot is multiplied into its bright frames by the processors.
+//! The file is either CSV, with `shot,factor` lines (a header line and `#`
+//! comments are skipped), or a JSON object `{"<shot>": <factor>, ...}`, and
+//! is reloaded whenever it changes on disk. Numeric shot ids are compared
+//! as numbers, so that `42` in the file matches the frames of shot `0042`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use figment::{
+    providers::{Format, Json},
+    Figment,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::shot;
+
+/// Configuration of the correction factors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorrectionsConf {
+    /// Path of the CSV or JSON file of correction factors
+    pub path: String,
+}
+
+/// Correction factors, as last loaded from the file
+#[derive(Debug)]
+pub struct Corrections {
+    path: PathBuf,
+    /// Modification time and length of the file when last loaded
+    loaded: Option<(SystemTime, u64)>,
+    factors: BTreeMap<String, f64>,
+}
+
+/// Parse `shot,factor` lines, skipping comments and a header line.
+fn parse_csv(text: &str) -> Result<BTreeMap<String, f64>> {
+    let mut factors = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((shot, factor)) = line.split_once(',') else {
+            bail!("Line {} is not `shot,factor`: {:?}", n + 1, line);
+        };
+        let factor = factor.trim().trim_matches('"');
+        match factor.parse::<f64>() {
+            Ok(f) => {
+                let shot = shot.trim().trim_matches('"');
+                factors.insert(String::from(shot), f);
+            }
+            Err(_) if factors.is_empty() => {
+                debug!("Skipping header line {:?}", line);
+            }
+            Err(e) => bail!("Invalid factor at line {}: {}", n + 1, e),
+        }
+    }
+    Ok(factors)
+}
+
+impl Corrections {
+    /// Start watching the file of correction factors.
+    pub fn new(conf: &CorrectionsConf) -> Corrections {
+        let path = PathBuf::from(&conf.path);
+        if !path.is_file() {
+            warn!("Correction factors {:?} do not exist (yet)", path);
+        }
+        Corrections {
+            path,
+            loaded: None,
+            factors: BTreeMap::new(),
+        }
+    }
+
+    fn load(path: &Path) -> Result<BTreeMap<String, f64>> {
+        let is_json = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        let factors: BTreeMap<String, f64> = if is_json {
+            Figment::from(Json::file(path)).extract()?
+        } else {
+            parse_csv(&fs::read_to_string(path)?)?
+        };
+        let mut canonical = BTreeMap::new();
+        for (id, f) in factors {
+            if canonical.insert(shot::canonical(&id), f).is_some() {
+                bail!("Shot {} listed more than once", id);
+            }
+        }
+        Ok(canonical)
+    }
+
+    /// Reload the file if it changed since it was last loaded.
+    fn refresh(&mut self) -> Result<()> {
+        let meta = match self.path.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Cannot stat correction factors: {}", e);
+                return Ok(());
+            }
+        };
+        let stamp = (meta.modified()?, meta.len());
+        if self.loaded == Some(stamp) {
+            return Ok(());
+        }
+
+        self.factors = Corrections::load(&self.path).context(format!(
+            "Cannot load correction factors from {:?}",
+            self.path
+        ))?;
+        self.loaded = Some(stamp);
+        debug!(
+            "Loaded {} correction factors from {:?}",
+            self.factors.len(),
+            self.path
+        );
+        Ok(())
+    }
+
+    /// Correction factor of a shot, if it was measured.
+    pub fn get(&mut self, shot: &str) -> Result<Option<f64>> {
+        self.refresh()?;
+        match self.factors.get(&shot::canonical(shot)) {
+            Some(&f) if f.is_finite() && f > 0.0 => Ok(Some(f)),
+            Some(f) => {
+                bail!("Invalid correction factor {} for shot {}", f, shot)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{parse_csv, Corrections, CorrectionsConf};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_parse_csv() {
+        let text = "shot,factor\n# monitor restarted\n12,0.98\n\"13\", 1.5\n";
+        let factors = parse_csv(text).unwrap();
+        assert_eq!(factors.len(), 2);
+        assert_eq!(factors["12"], 0.98);
+        assert_eq!(factors["13"], 1.5);
+
+        assert!(parse_csv("12,0.98\n13,abc\n").is_err());
+    }
+
+    #[test]
+    fn test_get_canonical() {
+        let dir = TempDir::new("corrections");
+        let path = dir.join("factors.csv");
+        fs::write(&path, "42,0.5\n0043,2\nrun-a,3\n").unwrap();
+        let conf = CorrectionsConf {
+            path: path.to_string_lossy().into_owned(),
+        };
+        let mut corr = Corrections::new(&conf);
+        assert_eq!(corr.get("0042").unwrap(), Some(0.5));
+        assert_eq!(corr.get("43").unwrap(), Some(2.0));
+        assert_eq!(corr.get("run-a").unwrap(), Some(3.0));
+        assert_eq!(corr.get("44").unwrap(), None);
+
+        // The same shot twice, once padded, is ambiguous
+        fs::write(&path, "42,0.5\n0042,0.7\n").unwrap();
+        corr.loaded = None;
+        assert!(corr.get("42").is_err());
+    }
+}
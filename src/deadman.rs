@@ -0,0 +1,211 @@
+//! Dead-man's switch on the shot cadence.
+//!
+//! If no shot is processed for `missed` times the expected interval, while
+//! the run flag file exists (or always, if no run flag is configured), an
+//! error is logged and the optional alert command is started, to be reaped
+//! by later checks once it exits. Events that do not complete a shot, e.g.
+//! stray or partial files, do not count. The alert is repeated only after
+//! shots have resumed and stopped again.
+//! The shots missed during a run, one per interval after the first, and the
+//! alerts are counted since startup, for the per-shot report.
+
+use std::{
+    path::Path,
+    process::{Child, Command},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::health;
+
+fn default_missed() -> u32 {
+    3
+}
+
+/// Shots missed during runs, since startup
+static MISSED_SHOTS: AtomicU64 = AtomicU64::new(0);
+/// Alerts of the switch, since startup
+static ALERTS: AtomicU64 = AtomicU64::new(0);
+
+/// Configuration of the dead-man's switch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadManConf {
+    /// Expected interval between shots in seconds
+    pub interval_s: f64,
+    /// Number of missed intervals before the alert
+    #[serde(default = "default_missed")]
+    pub missed: u32,
+    /// File whose existence means that a run is active
+    #[serde(default)]
+    pub runflag: Option<String>,
+    /// Command (program and arguments) started on alert
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+/// State of the dead-man's switch
+#[derive(Debug)]
+pub struct DeadMan {
+    conf: DeadManConf,
+    timeout: Duration,
+    last: Instant,
+    alerted: bool,
+    /// Shots already counted as missed since the last one
+    missed: u64,
+    /// Alert commands still running
+    alerts: Vec<Child>,
+}
+
+impl DeadMan {
+    /// Create the switch, with the timer starting now.
+    pub fn new(conf: &DeadManConf) -> DeadMan {
+        let timeout =
+            Duration::from_secs_f64(conf.interval_s * f64::from(conf.missed));
+        debug!("Dead-man's switch timeout {:?}", timeout);
+        DeadMan {
+            conf: conf.clone(),
+            timeout,
+            last: Instant::now(),
+            alerted: false,
+            missed: 0,
+            alerts: vec![],
+        }
+    }
+
+    fn armed(&self) -> bool {
+        match &self.conf.runflag {
+            Some(flag) => Path::new(flag).exists(),
+            None => true,
+        }
+    }
+
+    /// Count the intervals without shots since the last one, but the first.
+    fn count_missed(&mut self) {
+        let intervals =
+            self.last.elapsed().as_secs_f64() / self.conf.interval_s;
+        let missed = (intervals as u64).saturating_sub(1);
+        if missed > self.missed {
+            MISSED_SHOTS.fetch_add(missed - self.missed, Ordering::Relaxed);
+            self.missed = missed;
+        }
+    }
+
+    /// Reset the timer, a shot has arrived.
+    pub fn shot(&mut self) {
+        if self.armed() {
+            self.count_missed();
+        }
+        if self.alerted {
+            info!("Shots resumed after dead-man's switch alert");
+        }
+        self.last = Instant::now();
+        self.alerted = false;
+        self.missed = 0;
+    }
+
+    /// Check the timer, alerting if it expired.
+    pub fn check(&mut self) {
+        health::reap(&mut self.alerts);
+        if !self.armed() {
+            // The timer starts again when the run does
+            self.last = Instant::now();
+            self.missed = 0;
+            return;
+        }
+        self.count_missed();
+
+        let elapsed = self.last.elapsed();
+        if self.alerted || elapsed < self.timeout {
+            return;
+        }
+
+        error!(
+            "No shots received for {} s (expected every {} s)",
+            elapsed.as_secs(),
+            self.conf.interval_s
+        );
+        self.alerted = true;
+        ALERTS.fetch_add(1, Ordering::Relaxed);
+
+        if let Some((prog, args)) = self.conf.command.split_first() {
+            match Command::new(prog).args(args).spawn() {
+                Ok(child) => {
+                    debug!("Alert command {:?} started", self.conf.command);
+                    self.alerts.push(child);
+                }
+                Err(e) => error!("Cannot start alert command: {}", e),
+            }
+        }
+    }
+}
+
+/// Shots missed during runs and alerts, since startup.
+pub fn counts() -> (u64, u64) {
+    (
+        MISSED_SHOTS.load(Ordering::Relaxed),
+        ALERTS.load(Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use super::{counts, DeadMan, DeadManConf};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_timeout() {
+        let dir = TempDir::new("deadman");
+        let flag = dir.join("running");
+        let conf = DeadManConf {
+            interval_s: 0.01,
+            missed: 2,
+            runflag: Some(flag.to_string_lossy().into_owned()),
+            command: vec![String::from("true")],
+        };
+        let mut dm = DeadMan::new(&conf);
+        let (missed, alerts) = counts();
+        let wait = || thread::sleep(Duration::from_millis(30));
+
+        // Not armed outside of runs
+        wait();
+        dm.check();
+        assert!(!dm.alerted);
+        assert_eq!(counts(), (missed, alerts));
+
+        fs::write(&flag, "").unwrap();
+        dm.check();
+        assert!(!dm.alerted);
+        wait();
+        dm.check();
+        assert!(dm.alerted);
+
+        // Alerted again only after shots resumed
+        dm.shot();
+        assert!(!dm.alerted);
+        dm.check();
+        assert!(!dm.alerted);
+        wait();
+        dm.check();
+        assert!(dm.alerted);
+
+        // At least two intervals missed after the first, in each gap
+        let (now_missed, now_alerts) = counts();
+        assert!(now_missed - missed >= 4, "{} missed", now_missed - missed);
+        assert_eq!(now_alerts - alerts, 2);
+
+        // The alert commands are reaped once they exited
+        for _ in 0..100 {
+            if dm.alerts.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            dm.check();
+        }
+        assert!(dm.alerts.is_empty());
+    }
+}
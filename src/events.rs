@@ -0,0 +1,216 @@
+//! Classification of debounced events by kind.
+//!
+//! Only creations and content modifications make a file a shot candidate.
+//! Renames can be tracked (the new path becomes a candidate), and deletions
+//! can cancel the pending retries of the deleted files. Everything else
+//! (accesses, metadata changes) is ignored.
+//!
+//! Candidates that cannot be part of a shot are then dropped: directories,
+//! paths gone in the meantime, hidden files (like the probe of the health
+//! check), checksum files, and optionally empty files. Batches left without
+//! candidates never reach the processors; they are counted, as are the
+//! dropped paths. Lock files come and go with every frame, ours included, so
+//! they are dropped up front and not counted.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use log::debug;
+use notify::{
+    event::{CreateKind, ModifyKind, RenameMode},
+    Event, EventKind,
+};
+use notify_debouncer_full::DebouncedEvent;
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum, lockfile};
+
+/// Batches of events without any candidate, since startup
+static EMPTY_BATCHES: AtomicU64 = AtomicU64::new(0);
+/// Candidate paths dropped as spurious, since startup
+static SPURIOUS_PATHS: AtomicU64 = AtomicU64::new(0);
+
+/// Configuration of event handling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventsConf {
+    /// Treat files renamed into place as shot candidates, under their new name
+    pub renames: bool,
+    /// Cancel the pending retries of deleted files
+    pub deletions: bool,
+    /// Drop files that are empty when their event is handled
+    pub ignore_empty: bool,
+}
+
+impl Default for EventsConf {
+    fn default() -> Self {
+        EventsConf {
+            renames: true,
+            deletions: true,
+            ignore_empty: false,
+        }
+    }
+}
+
+/// Paths of a set of events, split by what should be done with them
+#[derive(Debug, Default)]
+pub struct Classified {
+    /// Paths that are shot candidates
+    pub candidates: Vec<PathBuf>,
+    /// Paths that do not exist anymore
+    pub removed: Vec<PathBuf>,
+}
+
+/// Split the paths of the events in shot candidates and removed paths.
+pub fn classify(conf: &EventsConf, events: &[DebouncedEvent]) -> Classified {
+    let mut out = Classified::default();
+    let mut locks_only = true;
+    for ev in events {
+        let paths = &ev.paths;
+        if paths.iter().all(|p| lockfile::is_lock(p)) {
+            continue;
+        }
+        locks_only = false;
+        match ev.kind {
+            EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Any)
+            | EventKind::Modify(ModifyKind::Other) => {
+                out.candidates.extend(paths.iter().cloned());
+            }
+            EventKind::Modify(ModifyKind::Name(mode)) => {
+                // With Both, paths are [from, to]
+                let (from, to) = match mode {
+                    RenameMode::Both => (paths.first(), paths.get(1)),
+                    RenameMode::From => (paths.first(), None),
+                    RenameMode::To => (None, paths.first()),
+                    _ => match paths.first() {
+                        Some(p) if p.exists() => (None, Some(p)),
+                        p => (p, None),
+                    },
+                };
+                if conf.deletions {
+                    out.removed.extend(from.cloned());
+                }
+                if conf.renames {
+                    out.candidates.extend(to.cloned());
+                }
+            }
+            EventKind::Remove(_) => {
+                if conf.deletions {
+                    out.removed.extend(paths.iter().cloned());
+                }
+            }
+            EventKind::Any | EventKind::Other => {
+                out.candidates
+                    .extend(paths.iter().filter(|p| p.exists()).cloned());
+            }
+            _ => debug!("Ignoring {:?} event on {:?}", ev.kind, paths),
+        }
+    }
+
+    out.candidates.retain(|p| !lockfile::is_lock(p));
+    out.removed.retain(|p| !lockfile::is_lock(p));
+    // A file removed and then recreated in the same batch is still there
+    out.removed.retain(|p| !p.exists());
+    out.candidates.retain(|p| !out.removed.contains(p));
+
+    let before = out.candidates.len();
+    out.candidates.retain(|p| match spurious(conf, p) {
+        Some(why) => {
+            debug!("Ignoring {:?}: {}", p, why);
+            false
+        }
+        None => true,
+    });
+    let dropped = (before - out.candidates.len()) as u64;
+    SPURIOUS_PATHS.fetch_add(dropped, Ordering::Relaxed);
+    if out.candidates.is_empty() && out.removed.is_empty() && !locks_only {
+        EMPTY_BATCHES.fetch_add(1, Ordering::Relaxed);
+        debug!("No candidates in batch of {} events", events.len());
+    }
+    out
+}
+
+/// Why a candidate path cannot be part of a shot, if it cannot.
+fn spurious(conf: &EventsConf, path: &Path) -> Option<&'static str> {
+    let hidden = path
+        .file_name()
+        .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+    if hidden {
+        return Some("hidden file");
+    }
+    if checksum::is_companion(path) {
+        return Some("checksum file");
+    }
+    match path.metadata() {
+        Err(_) => Some("gone"),
+        Ok(m) if m.is_dir() => Some("directory"),
+        Ok(m) if conf.ignore_empty && m.len() == 0 => Some("empty"),
+        Ok(_) => None,
+    }
+}
+
+/// Number of batches without candidates and of spurious paths dropped,
+/// since startup.
+pub fn spurious_counts() -> (u64, u64) {
+    (
+        EMPTY_BATCHES.load(Ordering::Relaxed),
+        SPURIOUS_PATHS.load(Ordering::Relaxed),
+    )
+}
+
+/// Synthetic creation event for files that did not come from the watcher.
+pub fn created(paths: Vec<PathBuf>) -> DebouncedEvent {
+    let event = paths
+        .into_iter()
+        .fold(Event::new(EventKind::Create(CreateKind::File)), |ev, p| {
+            ev.add_path(p)
+        });
+    DebouncedEvent::new(event, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{classify, created, spurious, spurious_counts, EventsConf};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_spurious() {
+        let dir = TempDir::new("events");
+        let frame = dir.join("rawimg-0001.sis");
+        fs::write(&frame, b"").unwrap();
+        let mut conf = EventsConf::default();
+
+        assert_eq!(spurious(&conf, &frame), None);
+        assert_eq!(spurious(&conf, &dir), Some("directory"));
+        assert_eq!(spurious(&conf, &dir.join(".probe")), Some("hidden file"));
+        assert_eq!(spurious(&conf, &dir.join("gone.sis")), Some("gone"));
+        conf.ignore_empty = true;
+        assert_eq!(spurious(&conf, &frame), Some("empty"));
+    }
+
+    #[test]
+    fn test_locks() {
+        let dir = TempDir::new("events-locks");
+        let frame = dir.join("rawimg-0001.sis");
+        let lock = dir.join("rawimg-0001.sis.lock");
+        fs::write(&frame, b"frame").unwrap();
+        fs::write(&lock, b"pid=1").unwrap();
+        let conf = EventsConf::default();
+
+        let before = spurious_counts();
+        let out = classify(&conf, &[created(vec![lock.clone()])]);
+        assert!(out.candidates.is_empty() && out.removed.is_empty());
+        assert_eq!(spurious_counts(), before);
+
+        let out = classify(&conf, &[created(vec![lock, frame.clone()])]);
+        assert_eq!(out.candidates, vec![frame]);
+        assert_eq!(spurious_counts(), before);
+    }
+}
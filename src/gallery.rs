@@ -0,0 +1,353 @@
+//! Minimal HTTP gallery of the last processed shots.
+//!
+//! A background thread serves, on a plain TCP socket, a self-refreshing HTML
+//! page with a thumbnail and a few scalars for each of the last shots, so
+//! that anyone on the lab LAN can follow the experiment with just a browser.
+//! Thumbnails are served as grayscale BMP, which every browser shows and
+//! which needs no encoder. Only `GET /` and `GET /thumb/<n>.bmp` are served,
+//! one connection at a time: this is a monitor, not a web server.
+
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    colormap::{Colormap, ColormapConf},
+    textout, SisImg,
+};
+
+fn default_bind() -> String {
+    String::from("0.0.0.0:8080")
+}
+
+fn default_shots() -> usize {
+    12
+}
+
+fn default_refresh_s() -> u32 {
+    5
+}
+
+fn default_thumb() -> usize {
+    192
+}
+
+/// Configuration of the gallery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GalleryConf {
+    /// Address and port to listen on
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Number of shots shown
+    #[serde(default = "default_shots")]
+    pub shots: usize,
+    /// Seconds between page refreshes in the browser
+    #[serde(default = "default_refresh_s")]
+    pub refresh_s: u32,
+    /// Largest side of the thumbnails, in pixels
+    #[serde(default = "default_thumb")]
+    pub thumb: usize,
+    /// Colormap of the thumbnails, instead of the global one
+    #[serde(default)]
+    pub colormap: Option<ColormapConf>,
+}
+
+/// A shot shown in the gallery
+#[derive(Debug)]
+struct Shot {
+    /// Sequence number, naming the thumbnail
+    seq: u64,
+    watch: String,
+    shot: String,
+    time: SystemTime,
+    /// Name and value of the scalars shown
+    scalars: Vec<(&'static str, f64)>,
+    bmp: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Shots {
+    next: u64,
+    list: VecDeque<Shot>,
+}
+
+/// Handle to the gallery, fed with the processed shots
+#[derive(Debug)]
+pub struct Gallery {
+    shots: Arc<Mutex<Shots>>,
+    keep: usize,
+    thumb: usize,
+    colormap: Colormap,
+}
+
+/// Average of `f` x `f` blocks, so that the largest side is at most `size`.
+fn downsample(img: &Array2<f64>, size: usize) -> Array2<f64> {
+    let (h, w) = img.dim();
+    let f = h.max(w).div_ceil(size.max(1)).max(1);
+    let (th, tw) = (h.div_ceil(f), w.div_ceil(f));
+    let mut sum = Array2::<f64>::zeros((th, tw));
+    let mut count = Array2::<f64>::zeros((th, tw));
+    for ((y, x), v) in img.indexed_iter() {
+        sum[[y / f, x / f]] += v;
+        count[[y / f, x / f]] += 1.0;
+    }
+    sum / count
+}
+
+/// Encode an image of levels as an 8 bit BMP, with the colors of the lookup
+/// table as palette.
+fn bmp(img: &Array2<u8>, lut: &[[u8; 3]; 256]) -> Vec<u8> {
+    let (h, w) = img.dim();
+    let stride = w.div_ceil(4) * 4;
+    let offset = 14 + 40 + 4 * 256;
+    let size = offset + stride * h;
+    let mut out = Vec::with_capacity(size);
+    // File header
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(offset as u32).to_le_bytes());
+    // Info header, 8 bits per pixel with a palette
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(w as i32).to_le_bytes());
+    out.extend_from_slice(&(h as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&8u16.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&((stride * h) as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&256u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    for [r, g, b] in lut {
+        out.extend_from_slice(&[*b, *g, *r, 0]);
+    }
+    // Rows bottom-up, padded to 4 bytes
+    for row in img.outer_iter().rev() {
+        out.extend(row.iter());
+        out.resize(out.len() + stride - w, 0);
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The gallery page.
+fn page(shots: &Shots, refresh_s: u32) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\">\
+         <title>acqmidproc</title><style>\
+         body {{ font-family: sans-serif; background: #222; color: #ddd; }}\
+         figure {{ display: inline-block; margin: 8px; }}\
+         img {{ image-rendering: pixelated; width: 256px; }}\
+         </style></head><body>\n<h1>Last shots</h1>\n",
+        refresh_s
+    );
+    for s in shots.list.iter().rev() {
+        let scalars = s
+            .scalars
+            .iter()
+            .map(|(k, v)| format!("{} {:.3}", k, v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        html.push_str(&format!(
+            "<figure><img src=\"/thumb/{}.bmp\"><figcaption>{} {}<br>\
+             {}<br>{}</figcaption></figure>\n",
+            s.seq,
+            escape(&s.watch),
+            escape(&s.shot),
+            textout::timestamp(s.time),
+            scalars
+        ));
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    ctype: &str,
+    body: &[u8],
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        ctype,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+/// Answer a single request.
+fn serve(
+    mut stream: TcpStream,
+    shots: &Mutex<Shots>,
+    refresh_s: u32,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let path = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", path, ..] => path,
+        _ => {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                b"",
+            )
+        }
+    };
+    debug!("Gallery request for {}", path);
+
+    let shots = shots.lock().unwrap_or_else(|e| e.into_inner());
+    if path == "/" {
+        let html = page(&shots, refresh_s);
+        drop(shots);
+        return respond(&mut stream, "200 OK", "text/html", html.as_bytes());
+    }
+    let seq = path
+        .strip_prefix("/thumb/")
+        .and_then(|p| p.strip_suffix(".bmp"))
+        .and_then(|n| n.parse::<u64>().ok());
+    match seq.and_then(|n| shots.list.iter().find(|s| s.seq == n)) {
+        Some(s) => {
+            let bmp = s.bmp.clone();
+            drop(shots);
+            respond(&mut stream, "200 OK", "image/bmp", &bmp)
+        }
+        None => respond(&mut stream, "404 Not Found", "text/plain", b""),
+    }
+}
+
+impl Gallery {
+    /// Start serving the gallery, with the global colormap unless the
+    /// gallery has its own.
+    pub fn new(conf: &GalleryConf, colormap: &ColormapConf) -> Result<Gallery> {
+        let colormap =
+            Colormap::new(conf.colormap.as_ref().unwrap_or(colormap))?;
+        let listener = TcpListener::bind(&conf.bind)
+            .context(format!("Cannot serve gallery on {}", conf.bind))?;
+        let shots = Arc::new(Mutex::new(Shots::default()));
+        let served = Arc::clone(&shots);
+        let refresh_s = conf.refresh_s;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(|s| serve(s, &served, refresh_s));
+                if let Err(e) = res {
+                    debug!("Gallery request failed: {:?}", e);
+                }
+            }
+        });
+        debug!("Serving gallery on {}", conf.bind);
+
+        Ok(Gallery {
+            shots,
+            keep: conf.shots,
+            thumb: conf.thumb,
+            colormap,
+        })
+    }
+
+    /// Add a processed shot, from its main output.
+    pub fn shot(&self, watch: &str, shot: &str, output: &Path) -> Result<()> {
+        let img = SisImg::read(output)
+            .context(format!("Cannot read {:?} for the gallery", output))?;
+        // OD images are shown in OD, anything else in counts
+        let (img, scalars) = match img.values() {
+            (od, true) => {
+                let scalars = vec![
+                    ("mean OD", od.mean().unwrap_or(0.0)),
+                    ("max OD", od.fold(f64::NEG_INFINITY, |m, &x| m.max(x))),
+                    ("sum OD", od.sum()),
+                ];
+                (od, scalars)
+            }
+            (img, false) => {
+                let scalars = vec![
+                    ("mean", img.mean().unwrap_or(0.0)),
+                    ("max", img.fold(0.0, |m: f64, &x| m.max(x))),
+                ];
+                (img, scalars)
+            }
+        };
+        let levels = self.colormap.levels(&downsample(&img, self.thumb));
+        let bmp = bmp(&levels, self.colormap.lut());
+
+        let mut shots = self.shots.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = shots.next;
+        shots.next += 1;
+        shots.list.push_back(Shot {
+            seq,
+            watch: String::from(watch),
+            shot: String::from(shot),
+            time: SystemTime::now(),
+            scalars,
+            bmp,
+        });
+        while shots.list.len() > self.keep {
+            shots.list.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{bmp, downsample};
+    use crate::{
+        colormap::{Colormap, ColormapConf},
+        window::WindowConf,
+    };
+
+    #[test]
+    fn test_thumbnail_bmp() {
+        let img = Array2::from_shape_fn((10, 7), |(y, x)| (y * 7 + x) as f64);
+        let thumb = downsample(&img, 4);
+        assert_eq!(thumb.dim(), (4, 3));
+        assert_eq!(thumb[[0, 0]], 8.0);
+        assert_eq!(thumb[[3, 2]], 69.0);
+
+        let minmax = WindowConf {
+            low: 0.0,
+            high: 100.0,
+        };
+        let conf = ColormapConf {
+            window: minmax,
+            ..ColormapConf::default()
+        };
+        let gray = Colormap::new(&conf).unwrap();
+        let out = bmp(&gray.levels(&thumb), gray.lut());
+        assert_eq!(&out[..2], b"BM");
+        assert_eq!(out.len(), 14 + 40 + 1024 + 4 * 4);
+        let size = u32::from_le_bytes([out[2], out[3], out[4], out[5]]);
+        assert_eq!(size as usize, out.len());
+        // Bottom-up: the last row, holding the maximum, comes first
+        assert_eq!(out[1078 + 2], 255);
+        assert_eq!(out[out.len() - 4], 0);
+    }
+}
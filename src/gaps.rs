@@ -0,0 +1,128 @@
+//! Detection of gaps in the sequence of shot ids of a run.
+//!
+//! Numeric shot ids are expected to increase by one from shot to shot. When
+//! an id is skipped, the skipped ids are reported as missing right away, and
+//! are forgotten if they arrive late. Quarantined shots count as missing too.
+//! Jumps larger than `MAX_GAP` are taken as a restart of the counter of
+//! acquire.py rather than as lost shots. Non numeric ids are not tracked.
+
+use std::collections::BTreeSet;
+
+use log::{info, warn};
+
+/// Largest jump of the shot id counted as missing shots
+const MAX_GAP: u64 = 1000;
+
+/// Gaps in the shot ids of a watch entry
+#[derive(Debug, Default)]
+pub struct Gaps {
+    last: Option<u64>,
+    missing: BTreeSet<u64>,
+    quarantined: BTreeSet<u64>,
+}
+
+/// Compact description of a set of ids, as ranges.
+pub fn ranges(ids: &BTreeSet<u64>) -> String {
+    let mut out: Vec<(u64, u64)> = vec![];
+    for &id in ids {
+        match out.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => out.push((id, id)),
+        }
+    }
+    out.iter()
+        .map(|&(a, b)| match a == b {
+            true => a.to_string(),
+            false => format!("{}-{}", a, b),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Gaps {
+    /// Record a received shot, returning the ids newly found missing
+    /// before it.
+    pub fn shot(&mut self, shot: &str) -> BTreeSet<u64> {
+        let Ok(id) = shot.parse::<u64>() else {
+            return BTreeSet::new();
+        };
+        let mut new = BTreeSet::new();
+        match self.last {
+            Some(last) if id > last + 1 && id - last - 1 > MAX_GAP => {
+                warn!(
+                    "Shot id jumped from {} to {}, not counted as missing",
+                    last, id
+                );
+            }
+            Some(last) if id > last + 1 => {
+                new = (last + 1..id).collect();
+                warn!("Missing shots: {}", ranges(&new));
+                self.missing.extend(&new);
+            }
+            Some(last) if id <= last => {
+                if self.missing.remove(&id) {
+                    info!("Shot {} arrived late, no longer missing", id);
+                }
+                return new;
+            }
+            _ => {}
+        }
+        self.last = Some(id);
+        new
+    }
+
+    /// Record a quarantined shot.
+    pub fn quarantined(&mut self, shot: &str) {
+        if let Ok(id) = shot.parse::<u64>() {
+            self.quarantined.insert(id);
+        }
+    }
+
+    /// Number of shots missing or quarantined in the run.
+    pub fn count(&self) -> usize {
+        self.missing.union(&self.quarantined).count()
+    }
+
+    /// Log the gaps of the run, and start a new one.
+    pub fn close(&mut self) {
+        if self.last.is_some() {
+            match self.count() {
+                0 => info!("No shots missing in the run"),
+                n => warn!(
+                    "{} shots missing in the run: {}; quarantined: {}",
+                    n,
+                    ranges(&self.missing),
+                    ranges(&self.quarantined)
+                ),
+            }
+        }
+        *self = Gaps::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{ranges, Gaps};
+
+    #[test]
+    fn test_gaps() {
+        let mut gaps = Gaps::default();
+        assert!(gaps.shot("00000010").is_empty());
+        assert_eq!(gaps.shot("00000014"), BTreeSet::from([11, 12, 13]));
+        gaps.shot("15");
+        gaps.shot("17");
+        gaps.quarantined("18");
+        assert_eq!(ranges(&gaps.missing), "11-13, 16");
+        assert_eq!(gaps.count(), 5);
+
+        // Late arrivals, restarts and other ids
+        gaps.shot("12");
+        assert!(gaps.shot("90000").is_empty());
+        assert!(gaps.shot("abc").is_empty());
+        assert_eq!(ranges(&gaps.missing), "11, 13, 16");
+        gaps.close();
+        assert_eq!(gaps.count(), 0);
+    }
+}
@@ -0,0 +1,280 @@
+//! Health probe of the watched folders.
+//!
+//! A watched folder on a network share can be unmounted or remounted under
+//! our feet, and the watcher then silently stops receiving events. Each
+//! watched folder is probed periodically: it must exist, stay on the same
+//! device (checked on unix only), and (if configured) contain the probe
+//! file, which only exists on the share and not on the bare mount point.
+//! When the probe fails an error is logged and the optional alert command is
+//! started, and reaped by later probes; when it succeeds again the folder
+//! must be watched again, and the files written in the meantime caught up.
+
+use std::{
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+fn default_interval_s() -> f64 {
+    5.0
+}
+
+/// Configuration of the health probe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthConf {
+    /// Seconds between probes
+    #[serde(default = "default_interval_s")]
+    pub interval_s: f64,
+    /// Name of a file that must exist in every watched folder
+    #[serde(default)]
+    pub probe: Option<String>,
+    /// Command (program and arguments) started when a folder is lost
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+/// Change of the health of a watched folder
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// The folder disappeared
+    Lost,
+    /// The folder is back, files written since then must be caught up
+    Back(SystemTime),
+}
+
+/// Health probe of a watched folder
+#[derive(Debug)]
+pub struct Probe {
+    path: PathBuf,
+    probe: Option<PathBuf>,
+    command: Vec<String>,
+    /// Device id of the folder when last seen healthy
+    dev: Option<u64>,
+    /// Time of the last successful probe
+    last_ok: SystemTime,
+    /// Time of the last successful probe before the folder was lost
+    lost: Option<SystemTime>,
+    /// Alert commands still running
+    alerts: Vec<Child>,
+}
+
+/// Device id of a file.
+#[cfg(unix)]
+fn device_id(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.dev()
+}
+
+/// Device ids are not portably available elsewhere: remounts are only
+/// caught by the probe file.
+#[cfg(not(unix))]
+fn device_id(_meta: &Metadata) -> u64 {
+    0
+}
+
+impl Probe {
+    /// Start probing a folder, which is assumed healthy now.
+    pub fn new(path: &Path, conf: &HealthConf) -> Probe {
+        let mut probe = Probe {
+            path: path.to_path_buf(),
+            probe: conf.probe.as_ref().map(|p| path.join(p)),
+            command: conf.command.clone(),
+            dev: None,
+            last_ok: SystemTime::now(),
+            lost: None,
+            alerts: vec![],
+        };
+        probe.dev = probe.device().ok();
+        debug!("Probing {:?} on device {:?}", probe.path, probe.dev);
+        probe
+    }
+
+    /// Device id of the folder, if it is healthy.
+    fn device(&self) -> Result<u64> {
+        let meta = self.path.metadata()?;
+        if !meta.is_dir() {
+            bail!("{:?} is not a folder", self.path);
+        }
+        if let Some(probe) = &self.probe {
+            if !probe.exists() {
+                bail!("Probe file {:?} does not exist", probe);
+            }
+        }
+        Ok(device_id(&meta))
+    }
+
+    fn alert(&mut self) {
+        if let Some((prog, args)) = self.command.split_first() {
+            match Command::new(prog).args(args).spawn() {
+                Ok(child) => {
+                    debug!("Alert command {:?} started", self.command);
+                    self.alerts.push(child);
+                }
+                Err(e) => error!("Cannot start alert command: {}", e),
+            }
+        }
+    }
+
+    /// Probe the folder, returning how its health changed, if it did.
+    pub fn check(&mut self) -> Option<Change> {
+        reap(&mut self.alerts);
+        let dev = match self.device() {
+            Ok(dev) => dev,
+            Err(e) => {
+                if self.lost.is_some() {
+                    return None;
+                }
+                error!("Watched folder lost, pausing: {:?}", e);
+                self.lost = Some(self.last_ok);
+                self.alert();
+                return Some(Change::Lost);
+            }
+        };
+
+        let since = match self.lost.take() {
+            Some(t) => Some(t),
+            // Remounted between two probes
+            None if self.dev.is_some_and(|d| d != dev) => {
+                warn!("Watched folder {:?} changed device", self.path);
+                Some(self.last_ok)
+            }
+            None => None,
+        };
+        self.dev = Some(dev);
+        self.last_ok = SystemTime::now();
+        if since.is_some() {
+            info!("Watched folder {:?} is back", self.path);
+        }
+        since.map(Change::Back)
+    }
+}
+
+/// Wait for the alert commands that exited, so that they do not linger as
+/// zombies, and forget them.
+pub fn reap(children: &mut Vec<Child>) {
+    children.retain_mut(|c| match c.try_wait() {
+        Ok(Some(status)) => {
+            if !status.success() {
+                warn!("Alert command {} exited with {}", c.id(), status);
+            }
+            false
+        }
+        Ok(None) => true,
+        Err(e) => {
+            warn!("Cannot wait for alert command {}: {}", c.id(), e);
+            false
+        }
+    });
+}
+
+/// Files under a folder modified since the given time, recursively.
+pub fn catchup(path: &Path, since: SystemTime) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let p = entry.path();
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            files.extend(catchup(&p, since)?);
+        } else if meta.modified()? >= since {
+            files.push(p);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs, thread,
+        time::{Duration, SystemTime},
+    };
+
+    use regex::Regex;
+
+    use super::{catchup, Change, HealthConf, Probe};
+    use crate::{
+        shot::{self, default_shotid},
+        testutil::TempDir,
+    };
+
+    #[test]
+    fn test_catchup() {
+        let dir = TempDir::new("health");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let names = [
+            "10-rawimg-0001.sis",
+            "9-rawimg-0002.sis",
+            "sub/10-rawimg-0002.sis",
+            "9-rawimg-0001.sis",
+            "notes.txt",
+        ];
+        for n in names {
+            fs::write(dir.join(n), "x").unwrap();
+        }
+        let files = catchup(&dir, SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(files.len(), names.len());
+        assert!(catchup(&dir, SystemTime::now() + Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+
+        // One batch per shot, oldest first
+        let re = Regex::new(&default_shotid()).unwrap();
+        let mut groups = shot::group(&re, files);
+        groups.iter_mut().for_each(|g| g.sort());
+        assert_eq!(
+            groups,
+            vec![
+                vec![
+                    dir.join("9-rawimg-0001.sis"),
+                    dir.join("9-rawimg-0002.sis")
+                ],
+                vec![
+                    dir.join("10-rawimg-0001.sis"),
+                    dir.join("sub/10-rawimg-0002.sis")
+                ],
+                vec![dir.join("notes.txt")],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lost_back() {
+        let dir = TempDir::new("health-probe");
+        let mounted = dir.join("mounted");
+        fs::write(&mounted, "").unwrap();
+        let conf = HealthConf {
+            interval_s: 5.0,
+            probe: Some(String::from("mounted")),
+            command: vec![String::from("true")],
+        };
+        let mut probe = Probe::new(&dir, &conf);
+        assert_eq!(probe.check(), None);
+        let last_ok = probe.last_ok;
+
+        // Lost once, however many probes fail
+        fs::remove_file(&mounted).unwrap();
+        assert_eq!(probe.check(), Some(Change::Lost));
+        assert_eq!(probe.check(), None);
+
+        // The alert command is reaped once it exited
+        for _ in 0..100 {
+            if probe.alerts.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            probe.check();
+        }
+        assert!(probe.alerts.is_empty());
+
+        // Back, catching up from the last successful probe
+        fs::write(&mounted, "").unwrap();
+        assert_eq!(probe.check(), Some(Change::Back(last_ok)));
+        assert_eq!(probe.check(), None);
+    }
+}
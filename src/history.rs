@@ -0,0 +1,170 @@
+//! Changelog of the processing behavior, built into the binary.
+//!
+//! Every change to how outputs are computed from the same inputs gets a new
+//! entry, with a behavior revision one more than the last. The current
+//! revision is recorded with every shot (`behavior_rev` in the acquire.py
+//! metadata, the report, the Zarr attributes and the provenance of the OD
+//! images), so that data taken months apart can be told apart; `acqmidproc
+//! history` prints the table, and `acqmidproc history --json` one JSON
+//! object per entry.
+
+use crate::textout::{self, NumFmt, Record, Value};
+
+/// Change of the processing behavior
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+    /// Behavior revision introduced by the change
+    pub rev: u32,
+    /// Version of acqmidproc that has the change first
+    pub version: &'static str,
+    /// What changed in the outputs
+    pub summary: &'static str,
+}
+
+/// Behavior changes, oldest first
+pub const HISTORY: &[Change] = &[
+    Change {
+        rev: 1,
+        version: "0.1.0",
+        summary: "OD images stamped with format version 1, recording their \
+                  OD scale and offset; before, always scale 1000, offset 1",
+    },
+    Change {
+        rev: 2,
+        version: "0.1.0",
+        summary: "OD scale and offset configurable (processors.fkspecies)",
+    },
+    Change {
+        rev: 3,
+        version: "0.1.0",
+        summary: "Gzip-compressed input frames decompressed before use",
+    },
+    Change {
+        rev: 4,
+        version: "0.1.0",
+        summary: "Borders of the raw frames trimmed off before processing \
+                  ([trim])",
+    },
+    Change {
+        rev: 5,
+        version: "0.1.0",
+        summary: "Bright frames multiplied by per-shot correction factors \
+                  ([corrections])",
+    },
+    Change {
+        rev: 6,
+        version: "0.1.0",
+        summary: "Shots assembled from a configurable frame mapping, the \
+                  default being two kinetics frames and a shared dark",
+    },
+    Change {
+        rev: 7,
+        version: "0.1.0",
+        summary: "Kinetics frames of odd height (after the trim) refused; \
+                  before, the processor panicked",
+    },
+    Change {
+        rev: 8,
+        version: "0.1.0",
+        summary: "Separate darks subtracted from atoms and bright exposures",
+    },
+    Change {
+        rev: 9,
+        version: "0.1.0",
+        summary: "Of several files matching a frame pattern, the newest is \
+                  taken (multimatch = \"newest\"); before, the first \
+                  listed",
+    },
+    Change {
+        rev: 10,
+        version: "0.1.0",
+        summary: "Float OD written to a separate archive root with \
+                  versioned names (archive_root)",
+    },
+    Change {
+        rev: 11,
+        version: "0.1.0",
+        summary: "Of several files matching a frame pattern, the one whose \
+                  event was received last is taken (multimatch = \
+                  \"received\"); before, the newest",
+    },
+    Change {
+        rev: 12,
+        version: "0.1.0",
+        summary: "Atoms half of kinetics frames and vertical OD flip \
+                  configurable",
+    },
+    Change {
+        rev: 13,
+        version: "0.1.0",
+        summary: "Per-pixel OD variance written from a camera noise model \
+                  (processors.fkspecies.variance)",
+    },
+    Change {
+        rev: 14,
+        version: "0.1.0",
+        summary: "OD measured in a region of interest, optionally following \
+                  the cloud centroid \
+                  ([processors.fkspecies.roi])",
+    },
+    Change {
+        rev: 15,
+        version: "0.1.0",
+        summary: "Frames locked by acquire.py not read until their lock is \
+                  released ([lock])",
+    },
+    Change {
+        rev: 16,
+        version: "0.1.0",
+        summary: "OD pixels out of the u16 range clipped explicitly and \
+                  counted, NaN written as 0; shots optionally failed \
+                  (max_clipped)",
+    },
+];
+
+/// Current behavior revision.
+pub fn current() -> u32 {
+    HISTORY.last().map(|c| c.rev).unwrap_or_default()
+}
+
+/// Print the changelog, as a table or as JSON lines.
+pub fn print(json: bool) {
+    for c in HISTORY {
+        if json {
+            let rec: Record = vec![
+                (String::from("rev"), Value::Int(i64::from(c.rev))),
+                (String::from("version"), Value::Str(String::from(c.version))),
+                (String::from("summary"), Value::Str(String::from(c.summary))),
+            ];
+            println!("{}", textout::json_object(&NumFmt::default(), &rec));
+        } else {
+            println!("{:>3}  {:<8} {}", c.rev, c.version, c.summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, HISTORY};
+
+    /// Version as comparable numbers.
+    fn semver(v: &str) -> Vec<u32> {
+        v.split('.').map(|n| n.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_history() {
+        // Revisions are never reused or reordered, and versions go up to
+        // the one being built
+        let built = semver(env!("CARGO_PKG_VERSION"));
+        let mut last = vec![0, 0, 0];
+        for (n, c) in HISTORY.iter().enumerate() {
+            assert_eq!(c.rev as usize, n + 1);
+            assert!(!c.summary.is_empty());
+            let version = semver(c.version);
+            assert!(last <= version && version <= built, "{}", c.version);
+            last = version;
+        }
+        assert_eq!(current() as usize, HISTORY.len());
+    }
+}
@@ -0,0 +1,394 @@
+//! Routing hooks, moving the outputs of a shot according to small rules.
+//!
+//! Each rule is a condition on the scalars of the main output of a shot and a
+//! destination folder, relative to the output folder of the watch entry, e.g.
+//! `when = "max < 0.05"`, `dest = "discard"`. The first matching rule
+//! wins; shots matching no rule stay where they were written. Conditions are
+//! comparisons of a scalar with a number, joined by `and`, `or` and `not`,
+//! with parentheses. The scalars are `mean`, `max` and `sum` of the image,
+//! in OD for OD images and in counts otherwise, and `outputs`, the number of
+//! outputs of the shot. Policy tweaks then need neither a rebuild nor a new
+//! processor. The same conditions, on any attribute of a shot and also with
+//! quoted strings (`species == "K"`), are the filters of the consumers.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{transaction, SisImg};
+
+/// Configuration of a routing rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConf {
+    /// Condition on the scalars of the shot
+    pub when: String,
+    /// Destination folder, relative to the output folder
+    pub dest: String,
+    /// Watch entry the rule applies to, all if not given
+    #[serde(default)]
+    pub watch: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// Value compared with an attribute
+#[derive(Debug, Clone, PartialEq)]
+enum Lit {
+    Num(f64),
+    Str(String),
+}
+
+/// Value of an attribute of a shot
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attr {
+    Num(f64),
+    Str(String),
+}
+
+/// Parsed condition
+#[derive(Debug, Clone, PartialEq)]
+enum Cond {
+    Cmp(String, Cmp, Lit),
+    Not(Box<Cond>),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+const SCALARS: [&str; 4] = ["mean", "max", "sum", "outputs"];
+
+fn tokenize(text: &str) -> Result<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            // Strings are kept with a leading '"' telling them from names
+            chars.next();
+            let mut word = String::from('"');
+            loop {
+                match chars.next() {
+                    Some(q) if q == c => break,
+                    Some(ch) => word.push(ch),
+                    None => bail!("Unterminated string {}", word),
+                }
+            }
+            tokens.push(word);
+        } else if "<>=!".contains(c) {
+            let mut op = c.to_string();
+            chars.next();
+            if chars.peek() == Some(&'=') {
+                op.push('=');
+                chars.next();
+            }
+            tokens.push(op);
+        } else if c.is_alphanumeric() || "_.-+".contains(c) {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || "_.-+".contains(c)) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            bail!("Unexpected character {:?}", c);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser of conditions.
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+    /// Only the scalars can be compared, not any attribute
+    scalars: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(anyhow!("Unexpected end of condition"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn or(&mut self) -> Result<Cond> {
+        let mut cond = self.and()?;
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            cond = Cond::Or(Box::new(cond), Box::new(self.and()?));
+        }
+        Ok(cond)
+    }
+
+    fn and(&mut self) -> Result<Cond> {
+        let mut cond = self.unary()?;
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            cond = Cond::And(Box::new(cond), Box::new(self.unary()?));
+        }
+        Ok(cond)
+    }
+
+    fn unary(&mut self) -> Result<Cond> {
+        match self.next()?.as_str() {
+            "not" => Ok(Cond::Not(Box::new(self.unary()?))),
+            "(" => {
+                let cond = self.or()?;
+                match self.next()?.as_str() {
+                    ")" => Ok(cond),
+                    t => bail!("Expected ')', found {:?}", t),
+                }
+            }
+            name => {
+                if self.scalars && !SCALARS.contains(&name) {
+                    bail!("Unknown scalar {}, known are {:?}", name, SCALARS);
+                }
+                let cmp = match self.next()?.as_str() {
+                    "<" => Cmp::Lt,
+                    "<=" => Cmp::Le,
+                    ">" => Cmp::Gt,
+                    ">=" => Cmp::Ge,
+                    "==" => Cmp::Eq,
+                    "!=" => Cmp::Ne,
+                    t => bail!("Expected a comparison, found {:?}", t),
+                };
+                let lit = self.next()?;
+                let lit = match lit.strip_prefix('"') {
+                    Some(_) if self.scalars => {
+                        bail!("Expected a number, found {:?}", lit)
+                    }
+                    Some(_) if !matches!(cmp, Cmp::Eq | Cmp::Ne) => {
+                        bail!("Strings can only be compared with == and !=")
+                    }
+                    Some(s) => Lit::Str(String::from(s)),
+                    None => Lit::Num(lit.parse::<f64>().map_err(|_| {
+                        anyhow!("Expected a number, found {:?}", lit)
+                    })?),
+                };
+                Ok(Cond::Cmp(String::from(name), cmp, lit))
+            }
+        }
+    }
+}
+
+fn parse(text: &str, scalars: bool) -> Result<Cond> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        scalars,
+    };
+    let cond = parser.or()?;
+    if let Some(t) = parser.peek() {
+        bail!("Unexpected {:?} after the condition", t);
+    }
+    Ok(cond)
+}
+
+impl Cond {
+    /// Whether the attributes satisfy the condition. Comparisons with a
+    /// missing attribute, or of a number with a string, are false; string
+    /// attributes holding numbers compare as numbers.
+    fn eval<S: AsRef<str>>(&self, attrs: &[(S, Attr)]) -> bool {
+        match self {
+            Cond::Cmp(name, cmp, lit) => {
+                let Some((_, attr)) =
+                    attrs.iter().find(|a| a.0.as_ref() == name)
+                else {
+                    return false;
+                };
+                let num = match attr {
+                    Attr::Num(x) => Some(*x),
+                    Attr::Str(s) => s.trim().parse::<f64>().ok(),
+                };
+                match (lit, num, attr) {
+                    (Lit::Num(n), Some(x), _) => match cmp {
+                        Cmp::Lt => x < *n,
+                        Cmp::Le => x <= *n,
+                        Cmp::Gt => x > *n,
+                        Cmp::Ge => x >= *n,
+                        Cmp::Eq => x == *n,
+                        Cmp::Ne => x != *n,
+                    },
+                    (Lit::Str(s), _, Attr::Str(a)) => {
+                        (a == s) == (*cmp == Cmp::Eq)
+                    }
+                    _ => false,
+                }
+            }
+            Cond::Not(c) => !c.eval(attrs),
+            Cond::And(a, b) => a.eval(attrs) && b.eval(attrs),
+            Cond::Or(a, b) => a.eval(attrs) || b.eval(attrs),
+        }
+    }
+}
+
+/// Filter on the attributes of a shot
+#[derive(Debug)]
+pub struct Filter {
+    cond: Cond,
+}
+
+impl Filter {
+    /// Parse a condition on any attribute.
+    pub fn new(text: &str) -> Result<Filter> {
+        let cond =
+            parse(text, false).context(format!("Invalid filter {:?}", text))?;
+        Ok(Filter { cond })
+    }
+
+    /// Whether the shot with these attributes passes the filter.
+    pub fn matches<S: AsRef<str>>(&self, attrs: &[(S, Attr)]) -> bool {
+        self.cond.eval(attrs)
+    }
+}
+
+/// A routing rule, ready to be evaluated
+#[derive(Debug)]
+pub struct Route {
+    cond: Cond,
+    dest: String,
+    watch: Option<String>,
+}
+
+impl Route {
+    /// Parse the condition of the rule.
+    pub fn new(conf: &RouteConf) -> Result<Route> {
+        let cond = parse(&conf.when, true)
+            .context(format!("Invalid condition {:?}", conf.when))?;
+        if Path::new(&conf.dest).is_absolute() {
+            bail!("Destination {} is not relative", conf.dest);
+        }
+        Ok(Route {
+            cond,
+            dest: conf.dest.clone(),
+            watch: conf.watch.clone(),
+        })
+    }
+}
+
+/// Scalars of the main output of a shot.
+pub fn scalars(
+    output: &Path,
+    noutputs: usize,
+) -> Result<Vec<(&'static str, Attr)>> {
+    let img = SisImg::read(output)
+        .context(format!("Cannot read {:?} for routing", output))?;
+    let (img, _) = img.values();
+    Ok(vec![
+        ("mean", Attr::Num(img.mean().unwrap_or(0.0))),
+        (
+            "max",
+            Attr::Num(img.fold(f64::NEG_INFINITY, |m, &x| m.max(x))),
+        ),
+        ("sum", Attr::Num(img.sum())),
+        ("outputs", Attr::Num(noutputs as f64)),
+    ])
+}
+
+/// Move the outputs of a shot to the destination of the first matching
+/// rule, returning their new paths. Outputs outside the output folder, e.g.
+/// in the archive root, are left alone.
+pub fn route(
+    routes: &[Route],
+    watch: &str,
+    outpath: &Path,
+    outputs: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    let routes = routes
+        .iter()
+        .filter(|r| r.watch.as_deref().is_none_or(|w| w == watch))
+        .collect::<Vec<_>>();
+    let Some(main) = outputs.last() else {
+        return Ok(vec![]);
+    };
+    if routes.is_empty() {
+        return Ok(outputs.to_vec());
+    }
+    let scalars = scalars(main, outputs.len())?;
+    debug!("Routing scalars: {:?}", scalars);
+    let Some(route) = routes.iter().find(|r| r.cond.eval(&scalars)) else {
+        return Ok(outputs.to_vec());
+    };
+
+    let dest = outpath.join(&route.dest);
+    fs::create_dir_all(&dest)
+        .context(format!("Cannot create routing folder {:?}", dest))?;
+    let mut moved = vec![];
+    for o in outputs {
+        let Some(name) = o.file_name().filter(|_| o.starts_with(outpath))
+        else {
+            moved.push(o.clone());
+            continue;
+        };
+        let to = dest.join(name);
+        fs::rename(o, &to)
+            .context(format!("Cannot move {:?} to {:?}", o, to))?;
+        transaction::record(&to);
+        moved.push(to);
+    }
+    info!("Shot routed to {}", route.dest);
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Attr, Filter};
+
+    #[test]
+    fn test_conditions() {
+        let scalars = [
+            ("mean", Attr::Num(0.01)),
+            ("max", Attr::Num(0.04)),
+            ("outputs", Attr::Num(3.0)),
+        ];
+        let eval = |text: &str| parse(text, true).unwrap().eval(&scalars);
+        assert!(eval("max < 0.05"));
+        assert!(!eval("max >= 0.05"));
+        assert!(eval("max < 0.05 and outputs == 3"));
+        assert!(eval("mean > 1 or not (max > 1e-1)"));
+        assert!(!eval("mean > 1 or max > 0.01 and outputs != 3"));
+        assert!(parse("max <", true).is_err());
+        assert!(parse("peak > 1", true).is_err());
+        assert!(parse("max > 1 )", true).is_err());
+        assert!(parse("max == 'K'", true).is_err());
+
+        let attrs = [
+            ("species", Attr::Str(String::from("K"))),
+            ("detuning", Attr::Str(String::from("-2.5"))),
+            ("max", Attr::Num(0.04)),
+        ];
+        let filter = |text: &str| Filter::new(text).unwrap().matches(&attrs);
+        assert!(filter("species == 'K' and detuning < 0"));
+        assert!(filter("species != \"Rb\" or max > 1"));
+        assert!(!filter("camera == 'side' or species == 5"));
+        assert!(Filter::new("species < 'K'").is_err());
+        assert!(Filter::new("species == 'K").is_err());
+    }
+}
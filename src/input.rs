@@ -0,0 +1,177 @@
+//! Reading of input files, with transparent gzip decompression.
+//!
+//! Inputs are recognized as gzip-compressed by their `.gz` extension or by
+//! the gzip magic bytes, so `.sis.gz` frames are read like plain ones.
+//! Raw frames read for processing have the configured border trimmed off
+//! right away, so that no processor ever sees the sensor artifact rows.
+//! Each input read for processing is counted once in the usage accounting;
+//! later reads of the same input, and reads of our own outputs, are not.
+
+use std::{ffi::OsString, fs, io::Read, path::Path};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use log::debug;
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+
+use crate::{usage, SisImg};
+
+/// Pixels trimmed off each border of the raw frames
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Trim {
+    /// Rows removed at the top
+    pub top: usize,
+    /// Rows removed at the bottom
+    pub bottom: usize,
+    /// Columns removed on the left
+    pub left: usize,
+    /// Columns removed on the right
+    pub right: usize,
+}
+
+impl Trim {
+    /// Remove the borders from a frame.
+    pub fn apply(&self, img: Array2<u16>) -> Result<Array2<u16>> {
+        if *self == Trim::default() {
+            return Ok(img);
+        }
+        let (h, w) = img.dim();
+        if self.top + self.bottom >= h || self.left + self.right >= w {
+            bail!("Cannot trim {:?} from a {}x{} frame", self, h, w);
+        }
+        Ok(img
+            .slice(s![self.top..h - self.bottom, self.left..w - self.right])
+            .to_owned())
+    }
+}
+
+/// First two bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+fn has_gz_ext(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gz"))
+}
+
+/// Read the whole content of an input file, decompressing it if needed,
+/// and count it as read.
+pub fn readbytes(path: &Path) -> Result<Vec<u8>> {
+    let bytes = reread(path)?;
+    usage::read(path);
+    Ok(bytes)
+}
+
+/// Read a file again, or one of our outputs, without counting it.
+pub fn reread(path: &Path) -> Result<Vec<u8>> {
+    let raw = fs::read(path).context(format!("Cannot read {:?}", path))?;
+    if !has_gz_ext(path) && !raw.starts_with(&GZIP_MAGIC) {
+        return Ok(raw);
+    }
+
+    debug!("Decompressing gzip input {:?}", path);
+    let mut out = vec![];
+    GzDecoder::new(&raw[..])
+        .read_to_end(&mut out)
+        .context(format!("Cannot decompress {:?}", path))?;
+    Ok(out)
+}
+
+/// File name of an input once decompressed, i.e. without `.gz`.
+pub fn plainname(path: &Path) -> Option<OsString> {
+    let fname = path.file_name()?;
+    if has_gz_ext(path) {
+        path.file_stem().map(OsString::from)
+    } else {
+        Some(fname.to_os_string())
+    }
+}
+
+/// Read a raw sis frame for processing, trimming its borders.
+pub fn readframe(path: &Path, trim: &Trim) -> Result<Array2<u16>> {
+    let img: Array2<u16> = SisImg::decode(readbytes(path)?, path)?.into();
+    trim.apply(img)
+        .context(format!("Cannot trim borders of {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs, io::Write, path::Path};
+
+    use flate2::{write::GzEncoder, Compression};
+    use ndarray::{array, Array2};
+
+    use super::{plainname, readframe, Trim};
+    use crate::{testutil::TempDir, usage::Usage, SisImg};
+
+    #[test]
+    fn test_trim() {
+        let img = Array2::from_shape_fn((4, 3), |(i, j)| (i * 3 + j) as u16);
+        let trim = Trim {
+            top: 2,
+            bottom: 0,
+            left: 0,
+            right: 1,
+        };
+        assert_eq!(trim.apply(img.clone()).unwrap(), array![[6, 7], [9, 10]]);
+
+        let trim = Trim {
+            top: 2,
+            bottom: 2,
+            ..Trim::default()
+        };
+        assert!(trim.apply(img).is_err());
+    }
+
+    #[test]
+    fn test_read_counted_once() {
+        let dir = TempDir::new("input");
+        let path = dir.join("0001-rawimg-0001.sis");
+        let img = Array2::from_shape_fn((4, 3), |(i, j)| (i * 3 + j) as u16);
+        SisImg::new(img.clone())
+            .unwrap()
+            .write(path.clone())
+            .unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+
+        let before = Usage::now();
+        assert_eq!(readframe(&path, &Trim::default()).unwrap(), img);
+        // Read-backs and re-reads are not inputs
+        SisImg::read(&path).unwrap();
+        let used = Usage::now().since(&before);
+        assert_eq!((used.files_read, used.bytes_read), (1, len));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let dir = TempDir::new("input-gzip");
+        let plain = dir.join("0001-rawimg-0001.sis");
+        let img = Array2::from_shape_fn((4, 3), |(i, j)| (i * 3 + j) as u16);
+        SisImg::new(img.clone())
+            .unwrap()
+            .write(plain.clone())
+            .unwrap();
+        let mut enc = GzEncoder::new(vec![], Compression::fast());
+        enc.write_all(&fs::read(&plain).unwrap()).unwrap();
+        let gz = enc.finish().unwrap();
+
+        // Recognized by the extension, and by the magic bytes alone
+        let named = dir.join("0001-rawimg-0002.sis.gz");
+        let magic = dir.join("0001-rawimg-0003.sis");
+        for p in [&named, &magic] {
+            fs::write(p, &gz).unwrap();
+            assert_eq!(readframe(p, &Trim::default()).unwrap(), img);
+        }
+    }
+
+    #[test]
+    fn test_plainname() {
+        let name = |p: &str| plainname(Path::new(p));
+        let plain = Some(OsString::from("0001-rawimg-0001.sis"));
+        assert_eq!(name("in/0001-rawimg-0001.sis.gz"), plain);
+        assert_eq!(name("in/0001-rawimg-0001.sis.GZ"), plain);
+        assert_eq!(name("in/0001-rawimg-0001.sis"), plain);
+        assert_eq!(name("/"), None);
+    }
+}
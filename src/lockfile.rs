@@ -0,0 +1,180 @@
+//! Lock files shared with acquire.py, on filesystems without mandatory locks.
+//!
+//! The protocol is the same on both sides. Before touching a file `x.sis`, a
+//! program creates `x.sis.lock` exclusively (`O_CREAT | O_EXCL`, which SMB
+//! and NFS honor), writes `pid=<pid>`, `time=<unix seconds>` and
+//! `owner=<program>` lines into it, and removes it when done: acquire.py
+//! while writing a frame, acqmidproc while reading it. Whoever finds the lock
+//! taken waits and tries again. A lock older than `stale_s` seconds is left
+//! by a crashed program and ignored, with a warning; it is never removed by
+//! the other side. Lock files are never processed as frames.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Extension of the lock files
+const EXTENSION: &str = "lock";
+
+fn default_retry_s() -> f64 {
+    0.5
+}
+
+fn default_stale_s() -> f64 {
+    60.0
+}
+
+/// Configuration of the lock protocol
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockConf {
+    /// Seconds between attempts when a frame is locked
+    #[serde(default = "default_retry_s")]
+    pub retry_s: f64,
+    /// Age in seconds after which a lock is considered abandoned
+    #[serde(default = "default_stale_s")]
+    pub stale_s: f64,
+}
+
+/// Whether the path is a lock file.
+pub fn is_lock(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == EXTENSION)
+}
+
+/// Lock file of a path.
+fn lockpath(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Time a lock was taken, from its content or else from its mtime.
+fn taken(lock: &Path) -> Option<SystemTime> {
+    let text = fs::read_to_string(lock).ok()?;
+    let time = text
+        .lines()
+        .filter_map(|l| l.strip_prefix("time="))
+        .find_map(|t| t.trim().parse::<f64>().ok());
+    match time {
+        Some(t) if t.is_finite() && t >= 0.0 => {
+            Some(UNIX_EPOCH + Duration::from_secs_f64(t))
+        }
+        _ => fs::metadata(lock).and_then(|m| m.modified()).ok(),
+    }
+}
+
+/// Whether the lock of a path is held by someone, ignoring stale locks.
+fn held(path: &Path, conf: &LockConf) -> bool {
+    let lock = lockpath(path);
+    if !lock.exists() {
+        return false;
+    }
+    let age = taken(&lock)
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .unwrap_or_default();
+    if age.as_secs_f64() > conf.stale_s {
+        warn!(
+            "Ignoring stale lock {:?}, {:.0} s old",
+            lock,
+            age.as_secs_f64()
+        );
+        return false;
+    }
+    true
+}
+
+/// Locks held by acqmidproc, released when dropped
+#[derive(Debug)]
+pub struct Guard {
+    locks: Vec<PathBuf>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        for lock in &self.locks {
+            if let Err(e) = fs::remove_file(lock) {
+                warn!("Cannot release lock {:?}: {}", lock, e);
+            }
+        }
+    }
+}
+
+/// Take the locks of all the paths, or none of them. Returns `None` if any
+/// is held by someone else.
+pub fn acquire(paths: &[PathBuf], conf: &LockConf) -> Result<Option<Guard>> {
+    let mut guard = Guard { locks: vec![] };
+    let content = format!(
+        "pid={}\ntime={:.3}\nowner=acqmidproc\n",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    );
+    for path in paths {
+        let lock = lockpath(path);
+        let file = OpenOptions::new().write(true).create_new(true).open(&lock);
+        match file {
+            Ok(mut f) => {
+                guard.locks.push(lock.clone());
+                f.write_all(content.as_bytes())
+                    .context(format!("Cannot write lock {:?}", lock))?;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if held(path, conf) {
+                    debug!("{:?} is locked, waiting", path);
+                    return Ok(None);
+                }
+            }
+            Err(e) => {
+                return Err(e).context(format!("Cannot take lock {:?}", lock))
+            }
+        }
+    }
+    Ok(Some(guard))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{acquire, is_lock, lockpath, LockConf};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_protocol() {
+        let dir = TempDir::new("lock");
+        let frames = [dir.join("rawimg-0001.sis")];
+        let lock = lockpath(&frames[0]);
+        assert!(is_lock(&lock) && !is_lock(&frames[0]));
+        let conf = LockConf {
+            retry_s: 0.5,
+            stale_s: 60.0,
+        };
+
+        // Held by acquire.py
+        let held = "pid=1\ntime=9999999999\nowner=acquire.py\n";
+        fs::write(&lock, held).unwrap();
+        assert!(acquire(&frames, &conf).unwrap().is_none());
+
+        // Abandoned long ago
+        fs::write(&lock, "pid=1\ntime=1000\nowner=acquire.py\n").unwrap();
+        assert!(acquire(&frames, &conf).unwrap().is_some());
+        fs::remove_file(&lock).unwrap();
+
+        // Ours until dropped
+        let guard = acquire(&frames, &conf).unwrap().unwrap();
+        assert!(fs::read_to_string(&lock)
+            .unwrap()
+            .contains("owner=acqmidproc"));
+        drop(guard);
+        assert!(!lock.exists());
+    }
+}
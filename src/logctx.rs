@@ -0,0 +1,59 @@
+//! Per-watch-entry logging context.
+//!
+//! While a watch entry is being handled its name is stored in a thread-local
+//! context, and the log format prefixes every line with it, so that the
+//! output of several watch entries stays attributable.
+
+use std::{cell::RefCell, io::Write};
+
+use flexi_logger::DeferredNow;
+use log::Record;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous context when dropped.
+pub struct Guard {
+    prev: Option<String>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CONTEXT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Enter the context of a watch entry, until the guard is dropped.
+pub fn enter(name: &str) -> Guard {
+    let prev = CONTEXT.with(|c| c.borrow_mut().replace(String::from(name)));
+    Guard { prev }
+}
+
+/// Name of the current watch entry, if any.
+pub fn current() -> Option<String> {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+/// Log format: like the flexi_logger default, with the context added.
+pub fn format(
+    w: &mut dyn Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    let module = record.module_path().unwrap_or("<unnamed>");
+    match current() {
+        Some(ctx) => write!(
+            w,
+            "{} [{}] [{}] {}",
+            record.level(),
+            ctx,
+            module,
+            record.args()
+        ),
+        None => {
+            write!(w, "{} [{}] {}", record.level(), module, record.args())
+        }
+    }
+}
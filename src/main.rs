@@ -2,29 +2,107 @@
 //! Preprocess images from acquire.py, and feed them to cam.py.
 
 use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
-    time::{Duration, Instant},
+    fs::{self, File},
+    io::{Cursor, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
+use absorption::FrameConf;
+use acqlog::{AcqLog, AcqLogConf};
+use announce::{AnnounceConf, Announcer};
 use anyhow::{anyhow, bail, Context, Result};
+use appendsrc::AppendConf;
+use archive::{ArchiveConf, Job};
+use backpressure::{BackPressure, BackPressureConf};
+use bench::WarmupConf;
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use clap::{ArgAction, Parser};
-use colored::Colorize;
+use checksum::{ChecksumConf, Verdict};
+use clap::{ArgAction, Parser, Subcommand};
+use colormap::ColormapConf;
+use consumers::{Consumer, ConsumerConf};
+use control::{ControlConf, Request};
+use corrections::{Corrections, CorrectionsConf};
+use deadman::{DeadMan, DeadManConf};
+use events::EventsConf;
 use figment::{
     providers::{Format, Serialized, Toml},
     Figment,
 };
 use flexi_logger::{LogSpecification, Logger};
+use gallery::{Gallery, GalleryConf};
+use gaps::Gaps;
+use health::{Change, HealthConf, Probe};
+use hooks::{Route, RouteConf};
+use input::Trim;
+use lockfile::LockConf;
 use log::{debug, error, info, warn};
-use ndarray::{s, Array2};
+use ndarray::Array2;
+use noise::NoiseConf;
 use notify::{RecursiveMode, Watcher};
 use notify_debouncer_full::{self, DebouncedEvent};
-use serde::{Deserialize, Serialize};
-use std::fs;
+use num_traits::Float;
+use output::{Naming, Writer};
+use preview::{Preview, TerminalConf};
+use receipt::SkewConf;
+use regex::Regex;
+use roi::RoiConf;
+use runs::{Runs, RunsConf};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use shot::MultiMatch;
+use std::collections::{BTreeMap, VecDeque};
 use std::option::Option;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use tcpsrc::TcpSrcConf;
+use textout::{NumFmt, Value};
+use transaction::TransactionConf;
+use usage::Usage;
+use version::{Provenance, Stamp, FORMAT_VERSION};
+use zarr::{Zarr, ZarrConf};
+
+mod absorption;
+mod acqlog;
+mod announce;
+mod appendsrc;
+mod archive;
+mod backpressure;
+mod bench;
+mod checksum;
+mod codec;
+mod colormap;
+mod consumers;
+mod control;
+mod corrections;
+mod deadman;
+mod events;
+mod gallery;
+mod gaps;
+mod health;
+mod history;
+mod hooks;
+mod input;
+mod lockfile;
+mod logctx;
+mod noise;
+mod output;
+mod preview;
+mod receipt;
+mod regress;
+mod roi;
+mod runs;
+mod shot;
+mod shotdb;
+mod soak;
+mod tcpsrc;
+#[cfg(test)]
+mod testutil;
+mod textout;
+mod transaction;
+mod usage;
+mod version;
+mod window;
+mod zarr;
 
 #[derive(Debug, Parser, Serialize)]
 struct Cli {
@@ -46,32 +124,475 @@ struct Cli {
     #[arg(long, short)]
     quiet: bool,
 
+    /// Print a preview of each processed shot in the terminal
+    #[arg(long)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    preview: bool,
+
+    /// Reconcile the shot index with the output folders at startup
+    #[arg(long)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    repair: bool,
+
     /// Processor name
     #[arg(long)]
     #[serde(skip_serializing_if = "::std::option::Option::is_none")]
     proc: Option<String>,
+
+    /// Override a configuration key (e.g. processors.fkspecies.od_scale=500)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    #[serde(skip)]
+    set: Vec<String>,
+
+    /// Run a maintenance command instead of watching
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Show how a file would be routed and processed, without processing it
+    Explain {
+        /// Path of the file
+        path: PathBuf,
+    },
+
+    /// Upgrade archived outputs to the current format version
+    Migrate {
+        /// Files or folders to migrate (recursively)
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Only report what would be migrated
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Compare timing and accuracy of the OD kernels on synthetic data
+    Bench {
+        /// Height of the synthetic frames
+        #[arg(long, default_value_t = 1024)]
+        height: usize,
+
+        /// Width of the synthetic frames
+        #[arg(long, default_value_t = 1024)]
+        width: usize,
+
+        /// Number of runs of each kernel
+        #[arg(long, default_value_t = 20)]
+        repeat: u32,
+    },
+
+    /// Replay a recorded session, comparing the hashes of its outputs with a
+    /// baseline (or recording the baseline)
+    Regress {
+        /// Folder of the recorded session
+        session: PathBuf,
+
+        /// Baseline CSV file
+        baseline: PathBuf,
+
+        /// Watch entry whose processor is used
+        #[arg(long, default_value = "main")]
+        entry: String,
+
+        /// Record the baseline instead of comparing with it
+        #[arg(long)]
+        record: bool,
+    },
+
+    /// Tell the running daemon to drop the queued shots of the current run
+    AbortRun,
+
+    /// Print the changelog of the processing behavior
+    History {
+        /// One JSON object per change instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Estimate read noise and gain of a camera from calibration frames
+    CalibrateNoise {
+        /// Folder of dark frames, taken in pairs
+        #[arg(long)]
+        darks: PathBuf,
+
+        /// Folder of flat frames, taken in pairs at the same intensity
+        #[arg(long)]
+        flats: PathBuf,
+
+        /// Calibration file to write, for `calibration` in `[noise]`
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Run the daemon for hours on synthetic shots with injected faults,
+    /// checking that it neither crashes nor leaks memory
+    Soak(soak::SoakArgs),
+}
+
+fn default_name() -> String {
+    String::from("main")
+}
+
+/// Configuration of the dumps of intermediate stages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DebugConf {
+    /// Shot ids whose intermediate stages are written
+    shots: Vec<String>,
+    /// Folder of the dumps, `debug` in the output folder if not given
+    #[serde(default)]
+    folder: Option<String>,
+}
+
+fn default_complete_retry_s() -> f64 {
+    0.5
+}
+
+fn default_complete_retries() -> u32 {
+    60
+}
+
+/// Configuration of the wait for files still being written
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompleteConf {
+    /// Wait for sis files to reach the size announced by their header
+    enabled: bool,
+    /// Seconds between checks
+    #[serde(default = "default_complete_retry_s")]
+    retry_s: f64,
+    /// Number of checks before processing anyway
+    #[serde(default = "default_complete_retries")]
+    retries: u32,
+}
+
+impl Default for CompleteConf {
+    fn default() -> Self {
+        CompleteConf {
+            enabled: true,
+            retry_s: default_complete_retry_s(),
+            retries: default_complete_retries(),
+        }
+    }
 }
 
 /// Holder for configuration
 #[derive(Serialize, Deserialize)]
 struct Config {
+    /// Name of the main watch entry, used to tag logs and reports
+    #[serde(default = "default_name")]
+    name: String,
     /// Input folder path
     inpath: String,
     /// Output folder path
     outpath: String,
+    /// Optional archive folder path, for float outputs with versioned names
+    #[serde(default)]
+    archive_root: Option<String>,
     /// Verbosity
     verbose: u8,
     /// Quiet (overrides verbose)
     quiet: bool,
+    /// Print a preview of each processed shot in the terminal
+    #[serde(default)]
+    preview: bool,
+    /// Look of the terminal preview
+    #[serde(default)]
+    terminal: TerminalConf,
     /// Processor name
     proc: String,
+    /// Number formatting for text outputs
+    #[serde(default)]
+    format: NumFmt,
+    /// Optional per-shot report file (.csv or .jsonl)
+    #[serde(default)]
+    report: Option<String>,
+    /// Regex extracting the shot id from file names (`shot` named group)
+    #[serde(default = "shot::default_shotid")]
+    shotid: String,
+    /// Optional pairing of acquire.py log lines with shots
+    #[serde(default)]
+    acqlog: Option<AcqLogConf>,
+    /// Optional control commands to the running daemon
+    #[serde(default)]
+    control: Option<ControlConf>,
+    /// Keep an index of the files of every shot in each output folder
+    #[serde(default)]
+    shotdb: bool,
+    /// Reconcile the shot index with the output folders at startup
+    #[serde(default)]
+    repair: bool,
+    /// Optional back-pressure flag for acquire.py
+    #[serde(default)]
+    backpressure: Option<BackPressureConf>,
+    /// Optional multicast announcement of processed shots
+    #[serde(default)]
+    announce: Option<AnnounceConf>,
+    /// Optional all-or-nothing publication of each shot to its sinks
+    #[serde(default)]
+    transaction: Option<TransactionConf>,
+    /// Optional source tailing a single growing file
+    #[serde(default)]
+    append: Option<AppendConf>,
+    /// Optional source receiving frames over TCP
+    #[serde(default)]
+    tcp: Option<TcpSrcConf>,
+    /// Optional alert when shots stop arriving
+    #[serde(default)]
+    deadman: Option<DeadManConf>,
+    /// Optional detection of runs, snapshotting the configuration of each
+    #[serde(default)]
+    runs: Option<RunsConf>,
+    /// Additional watch entries, besides the main one
+    #[serde(default)]
+    watch: Vec<WatchConf>,
+    /// Parameters of the processors
+    #[serde(default)]
+    processors: Processors,
+    /// Optional verification of companion checksum files
+    #[serde(default)]
+    checksum: Option<ChecksumConf>,
+    /// Handling of the different event kinds
+    #[serde(default)]
+    events: EventsConf,
+    /// Wait for files still being written
+    #[serde(default)]
+    complete: CompleteConf,
+    /// Optional lock files shared with acquire.py
+    #[serde(default)]
+    lock: Option<LockConf>,
+    /// Read back every output after writing it, and compare
+    #[serde(default)]
+    verify_writes: bool,
+    /// Naming scheme of processed outputs
+    #[serde(default)]
+    naming: Naming,
+    /// Borders trimmed off raw frames as soon as they are read
+    #[serde(default)]
+    trim: Trim,
+    /// Record the provenance of processed images in their header
+    #[serde(default)]
+    provenance: bool,
+    /// Optional high-accuracy second pass into an archival tree
+    #[serde(default)]
+    archive: Option<ArchiveConf>,
+    /// Optional per-shot correction factors of the bright frames
+    #[serde(default)]
+    corrections: Option<CorrectionsConf>,
+    /// Optional health probe of the watched folders
+    #[serde(default)]
+    health: Option<HealthConf>,
+    /// Choice among several files matching the pattern of a frame
+    #[serde(default)]
+    multimatch: MultiMatch,
+    /// Optional cross-check of file times against our clock
+    #[serde(default)]
+    skew: Option<SkewConf>,
+    /// Optional Zarr stores of the runs
+    #[serde(default)]
+    zarr: Option<ZarrConf>,
+    /// Optional HTTP gallery of the last shots
+    #[serde(default)]
+    gallery: Option<GalleryConf>,
+    /// False colors of the previews, unless overridden by a sink
+    #[serde(default)]
+    colormap: ColormapConf,
+    /// Optional warm-up of the processors at startup
+    #[serde(default)]
+    warmup: Option<WarmupConf>,
+    /// Noise model of the camera
+    #[serde(default)]
+    noise: NoiseConf,
+    /// Routing rules of the outputs, the first matching one wins
+    #[serde(default)]
+    route: Vec<RouteConf>,
+    /// Consumers of the shots, each with its own filter
+    #[serde(default)]
+    consumer: Vec<ConsumerConf>,
+    /// Optional dumps of the intermediate stages of some shots
+    #[serde(default)]
+    debug: Option<DebugConf>,
+}
+
+/// Value of a configuration override from the command line
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum SetValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+/// Split a `key=value` override, guessing the type of the value.
+fn parseset(set: &str) -> Result<(String, SetValue)> {
+    let (key, val) = set
+        .split_once('=')
+        .ok_or(anyhow!("Override {} is not in the form key=value", set))?;
+    let key = String::from(key.trim());
+    let val = val.trim();
+    let val = if let Ok(b) = val.parse::<bool>() {
+        SetValue::Bool(b)
+    } else if let Ok(i) = val.parse::<i64>() {
+        SetValue::Int(i)
+    } else if let Ok(x) = val.parse::<f64>() {
+        SetValue::Float(x)
+    } else {
+        SetValue::Str(String::from(val))
+    };
+    debug!("Configuration override {} = {:?}", key, val);
+    Ok((key, val))
+}
+
+/// Parameters of the processors
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Processors {
+    /// Parameters of the fkspecies processor
+    fkspecies: FKSpeciesConf,
+}
+
+/// Parameters of the fkspecies processor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct FKSpeciesConf {
+    /// Scale of the u16 encoding of the OD image
+    od_scale: f32,
+    /// Offset of the u16 encoding of the OD image
+    od_offset: f32,
+    /// Frames of a shot, with their roles
+    frames: Vec<FrameConf>,
+    /// Copy the raw frames to the live output
+    copy_raws: bool,
+    /// Write the per-pixel variance of the OD next to it
+    variance: bool,
+    /// Optional region of interest, measured in every OD image
+    roi: Option<RoiConf>,
+    /// Fraction of the OD pixels clipped to the u16 range failing the shot
+    max_clipped: Option<f64>,
+}
+
+impl Default for FKSpeciesConf {
+    fn default() -> Self {
+        FKSpeciesConf {
+            od_scale: 1000.0,
+            od_offset: 1.0,
+            frames: absorption::default_frames(),
+            copy_raws: true,
+            variance: false,
+            roi: None,
+            max_clipped: None,
+        }
+    }
+}
+
+/// Configuration of a watch entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WatchConf {
+    /// Name used to tag logs and reports
+    name: String,
+    /// Input folder path
+    inpath: String,
+    /// Output folder path
+    outpath: String,
+    /// Optional archive folder path, for float outputs with versioned names
+    #[serde(default)]
+    archive_root: Option<String>,
+    /// Processor name
+    proc: String,
+    /// Copy the raw frames to the output, overriding the processor setting
+    #[serde(default)]
+    copy_raws: Option<bool>,
+}
+
+impl Config {
+    /// All the watch entries, the main one first.
+    fn entries(&self) -> Vec<WatchConf> {
+        let main = WatchConf {
+            name: self.name.clone(),
+            inpath: self.inpath.clone(),
+            outpath: self.outpath.clone(),
+            archive_root: self.archive_root.clone(),
+            proc: self.proc.clone(),
+            copy_raws: None,
+        };
+        let mut entries = vec![main];
+        entries.extend(self.watch.iter().cloned());
+        entries
+    }
+}
+
+/// A watched folder, with its processor
+struct Entry {
+    conf: WatchConf,
+    processor: Box<dyn Process>,
+}
+
+/// Paths of a watch entry to be handled together
+#[derive(Debug, Clone)]
+struct Batch {
+    /// Index of the watch entry
+    entry: usize,
+    paths: Vec<PathBuf>,
+    /// Number of previous checksum verification attempts
+    attempt: u32,
+    /// Number of times the batch waited for its files to be complete
+    waits: u32,
+    /// Number of previous failed publications of the shot
+    failures: u32,
+}
+
+/// Mutable state of the event handlers
+struct State {
+    acqlog: Option<AcqLog>,
+    announcer: Option<Announcer>,
+    /// Queue of the archival pass
+    archive: Option<Sender<Job>>,
+    corrections: Option<Corrections>,
+    gallery: Option<Gallery>,
+    preview: Option<Preview>,
+    zarr: Option<Zarr>,
+    /// Gaps in the shot ids of the run, by watch entry
+    gaps: BTreeMap<String, Gaps>,
+    /// Routing rules of the outputs
+    routes: Vec<Route>,
+    /// Consumers of the shots
+    consumers: Vec<Consumer>,
+    /// Watch entries whose folder is lost, their retries are paused
+    paused: Vec<usize>,
+    /// Batches to be handled again, with the time they are due
+    retries: Vec<(Instant, Batch)>,
+    /// Time the last run was aborted, older events are dropped
+    aborted: Option<Instant>,
+    /// Shots processed since startup, successfully or not
+    processed: u64,
 }
 
-#[derive(Debug)]
+// Layout of the sis header, all integers are little endian:
+// 10 bytes of prefix (unused), u16 height, u16 width, 186 bytes of padding
+// (unused by cam.py, holding our version stamp), then the u16 pixels.
+
+/// Length of the unused prefix of the sis header
+const SIS_PREFIX_LEN: usize = 10;
+/// Offset of the u16 image height in the sis header
+const SIS_HEIGHT_OFFSET: usize = SIS_PREFIX_LEN;
+/// Offset of the u16 image width in the sis header
+const SIS_WIDTH_OFFSET: usize = SIS_HEIGHT_OFFSET + 2;
+/// Offset of the padding in the sis header
+const SIS_PAD_OFFSET: usize = SIS_WIDTH_OFFSET + 2;
+/// Length of the padding in the sis header
+const SIS_PAD_LEN: usize = 186;
+/// Length of the whole sis header, i.e. offset of the pixels
+const SIS_HEADER_LEN: usize = SIS_PAD_OFFSET + SIS_PAD_LEN;
+
+#[derive(Debug, PartialEq)]
 struct SisImg {
     height: usize,
     width: usize,
     image: Vec<u16>,
+    stamp: Option<Stamp>,
+    provenance: Option<Provenance>,
 }
 
 impl SisImg {
@@ -93,30 +614,52 @@ impl SisImg {
             height,
             width,
             image,
+            stamp: None,
+            provenance: None,
         })
     }
 
-    fn read(path: &PathBuf) -> Result<SisImg> {
+    /// Set the version stamp written in the header padding.
+    fn with_stamp(mut self, stamp: Stamp) -> SisImg {
+        self.stamp = Some(stamp);
+        self
+    }
+
+    /// Set the provenance written in the header padding.
+    fn with_provenance(mut self, provenance: Provenance) -> SisImg {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Read a sis image we wrote, or an input again, without counting it.
+    fn read(path: &Path) -> Result<SisImg> {
+        SisImg::decode(input::reread(path)?, path)
+    }
+
+    /// Parse the content of a sis file.
+    fn decode(bytes: Vec<u8>, path: &Path) -> Result<SisImg> {
         debug!("Reading sis image from {:?}", path);
-        let mut file = File::open(path)?;
+        let mut file = Cursor::new(bytes);
 
-        // First ten bytes are empty
-        file.seek(SeekFrom::Start(10))?;
+        let mut header = [0u8; SIS_HEADER_LEN];
+        file.read_exact(&mut header)
+            .context(format!("Sis header of {:?} is truncated", path))?;
 
-        // Height is a 16 bit integer
-        let mut heightbuf = [0u8; 2];
-        file.read_exact(&mut heightbuf)?;
-        let height = usize::from(u16::from_le_bytes(heightbuf));
+        // Height and width are both 16 bit integers
+        let height = usize::from(LittleEndian::read_u16(
+            &header[SIS_HEIGHT_OFFSET..SIS_HEIGHT_OFFSET + 2],
+        ));
         debug!("Image height: {}", height);
+        let width = usize::from(LittleEndian::read_u16(
+            &header[SIS_WIDTH_OFFSET..SIS_WIDTH_OFFSET + 2],
+        ));
+        debug!("Image width: {}", width);
 
-        // Width is another 64 bit integer
-        let mut widthbuf = [0u8; 2];
-        file.read_exact(&mut widthbuf)?;
-        let width = usize::from(u16::from_le_bytes(widthbuf));
-        debug!("Image width: {}", height);
-
-        // Then there are 186 empty bytes
-        file.seek(SeekFrom::Current(186))?;
+        // The padding may hold our stamp and provenance
+        let stamp = Stamp::decode(&header[SIS_PAD_OFFSET..]);
+        debug!("Image stamp: {:?}", stamp);
+        let provenance = Provenance::decode(&header[SIS_PAD_OFFSET..]);
+        debug!("Image provenance: {:?}", provenance);
 
         let len = height * width;
         let mut image: Vec<u16> = vec![0; len];
@@ -126,28 +669,57 @@ impl SisImg {
             height,
             width,
             image,
+            stamp,
+            provenance,
         })
     }
 
+    /// Size the file will have once completely written, from its header.
+    ///
+    /// None if the size cannot be known in advance (compressed files, or the
+    /// header itself is not complete yet).
+    fn expected_len(path: &Path) -> Result<Option<u64>> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; SIS_PAD_OFFSET];
+        if file.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+        if header.starts_with(&[0x1f, 0x8b]) {
+            return Ok(None);
+        }
+        let height = u64::from(LittleEndian::read_u16(
+            &header[SIS_HEIGHT_OFFSET..SIS_HEIGHT_OFFSET + 2],
+        ));
+        let width = u64::from(LittleEndian::read_u16(
+            &header[SIS_WIDTH_OFFSET..SIS_WIDTH_OFFSET + 2],
+        ));
+        Ok(Some(SIS_HEADER_LEN as u64 + 2 * height * width))
+    }
+
     fn write(&self, path: PathBuf) -> Result<()> {
         debug!("Writing sis image to path {:?}", path);
         let mut file = File::create(path)?;
-        for _ in 0..10 {
-            file.write(b" ")?;
-        }
-
-        let height = self.height as u16;
-        let width = self.width as u16;
-
-        file.write(&height.to_le_bytes())?;
-        file.write(&width.to_le_bytes())?;
 
-        for _ in 0..186 {
-            file.write(b" ")?;
+        // Prefix and padding are filled with spaces
+        let mut header = [b' '; SIS_HEADER_LEN];
+        LittleEndian::write_u16(
+            &mut header[SIS_HEIGHT_OFFSET..SIS_HEIGHT_OFFSET + 2],
+            self.height as u16,
+        );
+        LittleEndian::write_u16(
+            &mut header[SIS_WIDTH_OFFSET..SIS_WIDTH_OFFSET + 2],
+            self.width as u16,
+        );
+        if let Some(stamp) = &self.stamp {
+            stamp.encode(&mut header[SIS_PAD_OFFSET..]);
+        }
+        if let Some(provenance) = &self.provenance {
+            provenance.encode(&mut header[SIS_PAD_OFFSET..]);
         }
+        file.write_all(&header)?;
 
-        let nbytes = 2 * self.height as u32 * self.width as u32;
-        let mut imgbuf: Vec<u8> = vec![0; nbytes as usize];
+        let nbytes = 2 * self.height * self.width;
+        let mut imgbuf: Vec<u8> = vec![0; nbytes];
         LittleEndian::write_u16_into(&self.image, &mut imgbuf);
 
         file.write_all(&imgbuf)?;
@@ -163,34 +735,87 @@ impl From<SisImg> for Array2<u16> {
     }
 }
 
+impl SisImg {
+    /// Pixel values, in OD if the image has an OD encoding and in counts
+    /// otherwise, and whether they are OD.
+    fn values(self) -> (Array2<f64>, bool) {
+        let stamp = self.stamp;
+        let raw: Array2<u16> = self.into();
+        match stamp {
+            Some(st) if st.od_scale != 0.0 => {
+                let (scale, offset) = (st.od_scale as f64, st.od_offset as f64);
+                (raw.mapv(|x| x as f64 / scale - offset), true)
+            }
+            _ => (raw.mapv(f64::from), false),
+        }
+    }
+}
+
 /// Common trait for processors.
 ///
 /// Each processor is just a thin layer over the proc function, which implements
 /// all of the logic
-trait Process {
-    /// Process the files in paths according to processor logic.
-    fn proc(&self, paths: Vec<PathBuf>) -> Result<()>;
+trait Process: Send {
+    /// Process the files in paths according to processor logic, returning
+    /// the outputs written, the main one last. The bright frames are
+    /// multiplied by the correction factor.
+    fn proc(&self, paths: Vec<PathBuf>, factor: f64) -> Result<Vec<PathBuf>>;
+
+    /// Describe, without processing, the role the file would have and the
+    /// outputs it would contribute to.
+    fn explain(&self, path: &Path) -> Result<(String, Vec<PathBuf>)>;
+
+    /// Process the files again with the high-accuracy settings, writing the
+    /// outputs of the shot in the archive folder dir.
+    fn accurate(
+        &self,
+        paths: &[PathBuf],
+        dir: &Path,
+        shot: &str,
+        factor: f64,
+    ) -> Result<Vec<PathBuf>>;
+
+    /// Run the processing once on a synthetic shot of the given size,
+    /// without writing anything, so that the first real shot does not pay
+    /// for allocations and thread pool startup.
+    fn warmup(&self, _height: usize, _width: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write every intermediate array of the processing of the shot to the
+    /// folder dir, for debugging, returning the paths written. Nothing is
+    /// written by processors without intermediate stages.
+    fn stages(
+        &self,
+        _paths: &[PathBuf],
+        _factor: f64,
+        _dir: &Path,
+        _shot: &str,
+    ) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
 }
 
 /// This process just copies the files from input to output.
 #[derive(Debug, Clone)]
 struct Identity {
     outpath: String,
+    writer: Writer,
 }
 
 impl Identity {
     /// Create a new identity processor with specified paths for input and
     /// output.
-    fn new(outpath: &str) -> Identity {
+    fn new(outpath: &str, writer: &Writer) -> Identity {
         debug!("Identity processor created with outpath {}", outpath);
         Identity {
             outpath: String::from(outpath),
+            writer: writer.clone(),
         }
     }
 
-    fn filecp(&self, path: PathBuf) -> Result<()> {
-        debug!("Identity processor function.\n\tPath: {:?}", path);
-        let fname = path.file_name();
+    fn outname(&self, path: &Path) -> Result<PathBuf> {
+        let fname = input::plainname(path);
         if fname.is_none() {
             bail!("Path {:?} is file, but cannot extract filename.", path);
         }
@@ -198,6 +823,12 @@ impl Identity {
 
         let mut outname = PathBuf::from(self.outpath.clone());
         outname.push(fname);
+        Ok(outname)
+    }
+
+    fn filecp(&self, path: PathBuf) -> Result<PathBuf> {
+        debug!("Identity processor function.\n\tPath: {:?}", path);
+        let outname = self.outname(&path)?;
         debug!("Output filename: {:?}", outname);
 
         let errstr = format!(
@@ -206,160 +837,992 @@ impl Identity {
         );
         let infostr = format!("Copied {:?} to {:?}", path, outname);
 
-        fs::copy(path, outname).context(errstr)?;
+        self.writer.copy(&path, &outname).context(errstr)?;
+        // The copy is the only read of the input
+        usage::read(&path);
         debug!("{}", infostr);
 
-        Ok(())
+        Ok(outname)
     }
 }
 
 impl Process for Identity {
-    fn proc(&self, paths: Vec<PathBuf>) -> Result<()> {
+    fn proc(&self, paths: Vec<PathBuf>, _factor: f64) -> Result<Vec<PathBuf>> {
+        let mut outputs = vec![];
         for p in paths {
-            self.filecp(p)?;
+            outputs.push(self.filecp(p)?);
         }
         info!("Identity processor successful.");
-        Ok(())
+        Ok(outputs)
+    }
+
+    fn explain(&self, path: &Path) -> Result<(String, Vec<PathBuf>)> {
+        Ok((String::from("copied as is"), vec![self.outname(path)?]))
+    }
+
+    fn accurate(
+        &self,
+        paths: &[PathBuf],
+        dir: &Path,
+        _shot: &str,
+        _factor: f64,
+    ) -> Result<Vec<PathBuf>> {
+        let mut outputs = vec![];
+        for p in paths {
+            let fname = input::plainname(p)
+                .ok_or(anyhow!("Cannot find file name in path {:?}", p))?;
+            let dest = dir.join(fname);
+            self.writer.copy(p, &dest)?;
+            outputs.push(dest);
+        }
+        Ok(outputs)
     }
 }
 
 #[derive(Clone, Debug)]
 struct FKSpecies {
     outpath: String,
+    archive_root: Option<String>,
+    conf: FKSpeciesConf,
+    noise: NoiseConf,
+    /// ROI, shared with the clones of the processor
+    roi: Option<Arc<Mutex<roi::Tracker>>>,
+    trim: Trim,
+    multimatch: MultiMatch,
+    shotre: Regex,
+    writer: Writer,
 }
 
 impl FKSpecies {
-    fn new(outpath: &str) -> FKSpecies {
-        debug!("FKSpecies processor created with outpath {}", outpath);
-        FKSpecies {
-            outpath: String::from(outpath),
+    fn new(
+        wc: &WatchConf,
+        conf: &Config,
+        writer: &Writer,
+    ) -> Result<FKSpecies> {
+        let mut params = conf.processors.fkspecies.clone();
+        if let Some(copy) = wc.copy_raws {
+            params.copy_raws = copy;
         }
+        debug!(
+            "FKSpecies processor created with outpath {}, {:?}, {:?}",
+            wc.outpath, params, conf.trim
+        );
+        let roi = params.roi.as_ref().map(roi::Tracker::new);
+        Ok(FKSpecies {
+            outpath: wc.outpath.clone(),
+            archive_root: wc.archive_root.clone(),
+            conf: params,
+            noise: conf.noise.load()?,
+            roi: roi.map(|r| Arc::new(Mutex::new(r))),
+            trim: conf.trim.clone(),
+            multimatch: conf.multimatch,
+            shotre: Regex::new(&conf.shotid)?,
+            writer: writer.clone(),
+        })
     }
 
-    fn findpattern(paths: Vec<PathBuf>, pattern: &str) -> Result<PathBuf> {
-        debug!("Finding pattern {} in {:?}", pattern, paths);
-        let imgp = paths
-            .iter()
-            .filter(|x| x.to_string_lossy().contains(pattern))
-            .collect::<Vec<&PathBuf>>();
+    /// Output path of the copy of a raw frame.
+    fn rawout(&self, path: &Path) -> Result<PathBuf> {
+        let fname = input::plainname(path)
+            .ok_or(anyhow!("Cannot find file name in path {:?}", path))?;
+        Ok(Path::new(&self.outpath).join(fname))
+    }
 
-        if imgp.len() == 0 {
-            bail!("Cannot find pattern {} in {:?}", pattern, paths)
-        } else {
-            let p = imgp[0];
-            Ok(p.clone())
+    /// Parameters affecting the OD image, as text for its provenance.
+    fn params(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Params<'a> {
+            fkspecies: &'a FKSpeciesConf,
+            trim: &'a Trim,
+        }
+        let params = Params {
+            fkspecies: &self.conf,
+            trim: &self.trim,
+        };
+        Ok(toml::to_string(&params)?)
+    }
+
+    /// Stamped OD image, with its provenance if enabled.
+    fn odimg(&self, od: Array2<u16>, inputs: &[&Path]) -> Result<SisImg> {
+        let stamp = Stamp::od(self.conf.od_scale, self.conf.od_offset);
+        let mut img = SisImg::new(od)?.with_stamp(stamp);
+        if self.writer.provenance() {
+            let params = self.params()?;
+            let prov = Provenance::new("fkspecies", &params, inputs)?;
+            debug!("OD image provenance: {:?}", prov);
+            img = img.with_provenance(prov);
         }
+        Ok(img)
     }
 
-    fn calc_od(
-        img1: &Array2<u16>,
-        img2: &Array2<u16>,
-        img3: &Array2<u16>,
-    ) -> Array2<f32> {
-        // subtract offset
-        debug!("Calculating OD from images.");
-        let mut img1s: Array2<f32> = (img1 - img3).mapv(|x| f32::from(x));
-        let mut img2s: Array2<f32> = (img2 - img3).mapv(|x| f32::from(x));
-        let mut output = Array2::<f32>::zeros(img1.raw_dim());
+    /// Output path of the OD image.
+    fn odout(&self) -> PathBuf {
+        let dir = Path::new(&self.outpath);
+        self.writer.outname(dir, "20140000-img-0000.sis", "od.sis")
+    }
+
+    /// Path of a companion of an OD image, e.g. `-var.npy`.
+    fn companion(od: &Path, suffix: &str) -> PathBuf {
+        let stem = od.file_stem().unwrap_or_default().to_string_lossy();
+        od.with_file_name(format!("{}{}", stem, suffix))
+    }
+
+    /// Encode a scaled OD image as u16, counting the clipped pixels and
+    /// failing if they are more than allowed.
+    fn encode<F: Float>(
+        &self,
+        img: &Array2<F>,
+        round: bool,
+    ) -> Result<Array2<u16>> {
+        let (out, clipped) = absorption::encode(img, round);
+        if clipped == 0 {
+            return Ok(out);
+        }
+        absorption::count_clipped(clipped);
+        let fraction = clipped as f64 / out.len() as f64;
+        match self.conf.max_clipped {
+            Some(max) if fraction > max => bail!(
+                "{} of {} OD pixels clipped to the u16 range, more than \
+                 max_clipped = {}: check od_scale, od_offset and the \
+                 calibration",
+                clipped,
+                out.len(),
+                max
+            ),
+            _ => warn!(
+                "{} of {} OD pixels clipped to the u16 range",
+                clipped,
+                out.len()
+            ),
+        }
+        Ok(out)
+    }
 
-        let height = img1s.shape()[0];
-        debug!("Image height {} px", height);
+    /// Measure the OD in the ROI, writing the result next to the OD image.
+    fn measure(&self, od: &Array2<f32>, odpath: &Path) -> Result<PathBuf> {
+        let mut roi = match &self.roi {
+            Some(r) => r.lock().unwrap_or_else(|e| e.into_inner()),
+            None => bail!("No ROI configured"),
+        };
+        let m = roi.measure(od);
+        info!("Summed OD {:.3} in ROI at {:?}", m.sum, m.origin);
+        let centroid = m.centroid.unwrap_or([f64::NAN; 2]);
+        let int = |x: usize| Value::Int(x as i64);
+        let rec = vec![
+            (String::from("roi_row"), int(m.origin[0])),
+            (String::from("roi_col"), int(m.origin[1])),
+            (String::from("roi_height"), int(m.size[0])),
+            (String::from("roi_width"), int(m.size[1])),
+            (String::from("sum_od"), Value::Float(m.sum)),
+            (String::from("centroid_row"), Value::Float(centroid[0])),
+            (String::from("centroid_col"), Value::Float(centroid[1])),
+        ];
+        let path = FKSpecies::companion(odpath, "-roi.json");
+        textout::write(&path, &NumFmt::default(), &rec)?;
+        Ok(path)
+    }
 
-        img1s.par_mapv_inplace(f32::ln);
-        let img1s_at = &img1s.slice(s![..height / 2, ..]);
-        let img1s_br = &img1s.slice(s![height / 2.., ..]);
-        output
-            .slice_mut(s![..height / 2, ..])
-            .assign(&(img1s_br - img1s_at));
+    fn findpattern(&self, paths: &[PathBuf], pattern: &str) -> Result<PathBuf> {
+        debug!("Finding pattern {} in {:?}", pattern, paths);
+        let imgp = paths
+            .iter()
+            .filter(|x| x.to_string_lossy().contains(pattern))
+            .collect::<Vec<&PathBuf>>();
 
-        img2s.par_mapv_inplace(f32::ln);
-        let img2s_at = &img2s.slice(s![..height / 2, ..]);
-        let img2s_br = &img2s.slice(s![height / 2.., ..]);
-        output
-            .slice_mut(s![height / 2.., ..])
-            .assign(&(img2s_br - img2s_at));
+        self.multimatch
+            .pick(&self.shotre, pattern, &imgp)
+            .context(format!("Cannot choose frame among {:?}", paths))
+    }
 
-        output
+    /// Find the frames of the shot in paths, in the order of the frame
+    /// mapping, and read them.
+    fn frames(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Array2<u16>)>> {
+        let mut frames = vec![];
+        for f in &self.conf.frames {
+            let p = self.findpattern(paths, &f.pattern)?;
+            debug!("Frame {} ({}): {:?}", f.pattern, f.describe(), p);
+            let img = input::readframe(&p, &self.trim)?;
+            frames.push((p, img));
+        }
+        Ok(frames)
     }
 }
 
 impl Process for FKSpecies {
-    fn proc(&self, paths: Vec<PathBuf>) -> Result<()> {
+    fn proc(&self, paths: Vec<PathBuf>, factor: f64) -> Result<Vec<PathBuf>> {
         // TODO: optimize with pre-allocated image processing buffers
-        let img1p = FKSpecies::findpattern(paths.clone(), "rawimg-0001")?;
-        let img1fn = img1p
-            .file_name()
-            .ok_or(anyhow!("Cannot find file name in path {:?}", img1p))?;
-        debug!("Filename of image 1: {:?}", img1fn);
-        let img1op = PathBuf::from(&self.outpath).with_file_name(img1fn);
-        debug!("Image 1 will output to: {:?}", img1op);
-
-        let img2p = FKSpecies::findpattern(paths.clone(), "rawimg-0002")?;
-        let img2fn = img2p
-            .file_name()
-            .ok_or(anyhow!("Cannot find file name in path {:?}", img2p))?;
-        debug!("Filename of image 2: {:?}", img2fn);
-        let img2op = PathBuf::from(&self.outpath).with_file_name(img2fn);
-        debug!("Image 2 will output to: {:?}", img2op);
-
-        let img3p = FKSpecies::findpattern(paths.clone(), "rawimg-0003")?;
-        let img3fn = img3p
-            .file_name()
-            .ok_or(anyhow!("Cannot find file name in path {:?}", img3p))?;
-        debug!("Filename of image 3: {:?}", img3fn);
-        let img3op = PathBuf::from(&self.outpath).with_file_name(img3fn);
-        debug!("Image 3 will output to: {:?}", img3op);
-
-        let img1: Array2<u16> = SisImg::read(&img1p)?.into();
-        let img2: Array2<u16> = SisImg::read(&img2p)?.into();
-        let img3: Array2<u16> = SisImg::read(&img3p)?.into();
-
-        let imgod = (FKSpecies::calc_od(&img1, &img2, &img3) + 1.0) * 1000.0;
-        let imgod: Array2<u16> = imgod.mapv(|x| x as u16);
-
-        debug!("Copying raw images to their respective output paths");
-        fs::copy(img1p, img1op)?;
-        fs::copy(img2p, img2op)?;
-        fs::copy(img3p, img3op)?;
-
-        let imgodop = PathBuf::from(&self.outpath)
-            .with_file_name("20140000-img-0000.sis");
+        let frames = self.frames(&paths)?;
+        let imgs = frames.iter().map(|(_, i)| i.clone()).collect::<Vec<_>>();
+
+        let (scale, offset) = (self.conf.od_scale, self.conf.od_offset);
+        let imgod =
+            absorption::calc_od(&self.conf.frames, &imgs, factor as f32)?;
+
+        // The archive gets the OD itself, from the same computation
+        let mut archived = None;
+        if let Some(root) = &self.archive_root {
+            let stem = match shot::shot_id(&self.shotre, &paths) {
+                Some(shot) => format!("{}-od", shot),
+                None => String::from("od"),
+            };
+            let path = self.writer.versioned(Path::new(root), &stem, "npy");
+            self.writer.npy(&imgod, &path)?;
+            debug!("OD archived to {:?}", path);
+            archived = Some(path);
+        }
+        let imgodop = self.odout();
+        let measured = match self.roi {
+            Some(_) => Some(self.measure(&imgod, &imgodop)?),
+            None => None,
+        };
+
+        let imgod = self.encode(&((imgod + offset) * scale), false)?;
+
+        let mut outputs = vec![];
+        if self.conf.copy_raws {
+            debug!("Copying raw images to their respective output paths");
+            for (p, _) in &frames {
+                let op = self.rawout(p)?;
+                self.writer.copy(p, &op)?;
+                outputs.push(op);
+            }
+        }
+
+        outputs.extend(measured);
+        if self.conf.variance {
+            let var =
+                absorption::calc_var(&self.conf.frames, &imgs, &self.noise)?;
+            let varop = FKSpecies::companion(&imgodop, "-var.npy");
+            self.writer.npy(&var, &varop)?;
+            debug!("OD variance written to {:?}", varop);
+            outputs.push(varop);
+        }
 
         debug!("Writing OD image to its path");
-        SisImg::new(imgod)?.write(imgodop.clone())?;
+        let inputs =
+            frames.iter().map(|(p, _)| p.as_path()).collect::<Vec<_>>();
+        let imgod = self.odimg(imgod, &inputs)?;
+        self.writer.sis(&imgod, &imgodop)?;
         info!(
             "FKSpecies processor succesful. Output written to {:?}",
             imgodop
         );
 
+        outputs.extend(archived);
+        outputs.push(imgodop);
+        Ok(outputs)
+    }
+
+    fn explain(&self, path: &Path) -> Result<(String, Vec<PathBuf>)> {
+        let name = path.to_string_lossy();
+        match self.conf.frames.iter().find(|f| name.contains(&f.pattern)) {
+            Some(f) => {
+                let mut outputs = vec![];
+                if self.conf.copy_raws {
+                    outputs.push(self.rawout(path)?);
+                }
+                let odout = self.odout();
+                if self.conf.variance {
+                    outputs.push(FKSpecies::companion(&odout, "-var.npy"));
+                }
+                if self.roi.is_some() {
+                    outputs.push(FKSpecies::companion(&odout, "-roi.json"));
+                }
+                outputs.push(odout);
+                Ok((format!("{} ({})", f.describe(), f.pattern), outputs))
+            }
+            None => Ok((String::from("not part of a shot, ignored"), vec![])),
+        }
+    }
+
+    fn accurate(
+        &self,
+        paths: &[PathBuf],
+        dir: &Path,
+        shot: &str,
+        factor: f64,
+    ) -> Result<Vec<PathBuf>> {
+        let frames = self.frames(paths)?;
+        let imgs = frames.iter().map(|(_, i)| i.clone()).collect::<Vec<_>>();
+        let mut outputs = vec![];
+        for (p, _) in &frames {
+            let fname = input::plainname(p)
+                .ok_or(anyhow!("Cannot find file name in path {:?}", p))?;
+            let dest = dir.join(fname);
+            self.writer.copy(p, &dest)?;
+            outputs.push(dest);
+        }
+
+        let scale = f64::from(self.conf.od_scale);
+        let offset = f64::from(self.conf.od_offset);
+        let imgod = absorption::calc_od(&self.conf.frames, &imgs, factor)?;
+        let imgod = self.encode(&((imgod + offset) * scale), true)?;
+
+        let dest = dir.join(format!("{}-od.sis", shot));
+        let inputs =
+            frames.iter().map(|(p, _)| p.as_path()).collect::<Vec<_>>();
+        let imgod = self.odimg(imgod, &inputs)?;
+        self.writer.sis(&imgod, &dest)?;
+        outputs.push(dest);
+
+        Ok(outputs)
+    }
+
+    fn warmup(&self, height: usize, width: usize) -> Result<()> {
+        let imgs = bench::shot(&self.conf.frames, height, width)
+            .into_iter()
+            .map(|i| self.trim.apply(i))
+            .collect::<Result<Vec<_>>>()?;
+        let (scale, offset) = (self.conf.od_scale, self.conf.od_offset);
+        let imgod = absorption::calc_od(&self.conf.frames, &imgs, 1.0f32)?;
+        let (imgod, _) = absorption::encode(&((imgod + offset) * scale), false);
+        debug!("Warm-up OD of size {:?}", imgod.dim());
         Ok(())
     }
+
+    fn stages(
+        &self,
+        paths: &[PathBuf],
+        factor: f64,
+        dir: &Path,
+        shot: &str,
+    ) -> Result<Vec<PathBuf>> {
+        let frames = self.frames(paths)?;
+        let imgs = frames.iter().map(|(_, i)| i.clone()).collect::<Vec<_>>();
+        let mut stages = frames
+            .iter()
+            .zip(&self.conf.frames)
+            .map(|((_, img), f)| {
+                (format!("raw-{}", f.pattern), img.mapv(f32::from))
+            })
+            .collect::<Vec<_>>();
+        stages.extend(absorption::stages(
+            &self.conf.frames,
+            &imgs,
+            factor as f32,
+        )?);
+
+        let mut outputs = vec![];
+        for (n, (stage, img)) in stages.iter().enumerate() {
+            let path = dir.join(format!("{}-{:02}-{}.npy", shot, n, stage));
+            self.writer.npy(img, &path)?;
+            outputs.push(path);
+        }
+        Ok(outputs)
+    }
+}
+
+/// Attach the parameters logged by acquire.py to the shot, writing them in a
+/// metadata file next to the outputs.
+fn pairparams(
+    log: &mut AcqLog,
+    conf: &Config,
+    outpath: &str,
+    shotre: &Regex,
+    paths: &[PathBuf],
+) -> Result<()> {
+    log.poll()?;
+    let Some(shot) = shot::shot_id(shotre, paths) else {
+        warn!("Cannot find shot id in {:?}, parameters not paired", paths);
+        return Ok(());
+    };
+
+    match log.get(&shot) {
+        Some(mut rec) => {
+            rec.insert(0, (String::from("shot"), Value::Str(shot.clone())));
+            rec.insert(
+                0,
+                (
+                    String::from("behavior_rev"),
+                    Value::Int(i64::from(history::current())),
+                ),
+            );
+            rec.insert(
+                0,
+                (
+                    String::from("format_version"),
+                    Value::Int(i64::from(FORMAT_VERSION)),
+                ),
+            );
+            let mut metap = PathBuf::from(outpath);
+            metap.push(format!("{}-meta.json", shot));
+            textout::write(&metap, &conf.format, &rec)?;
+            info!(
+                "acquire.py parameters of shot {} written to {:?}",
+                shot, metap
+            );
+        }
+        None => warn!("No acquire.py parameters found for shot {}", shot),
+    }
+
+    Ok(())
+}
+
+/// Index of the watch entry whose input folder contains the path.
+fn matchentry(entries: &[Entry], path: &Path) -> Option<usize> {
+    entries
+        .iter()
+        .position(|e| path.starts_with(&e.conf.inpath))
+}
+
+/// Print how a file would be routed and processed, without processing it.
+fn explain(conf: &Config, path: &Path) -> Result<()> {
+    let writer = Writer::new(false, conf.naming, false);
+    let mut entries = vec![];
+    for wc in conf.entries() {
+        let processor = getproc(&wc, conf, &writer)?;
+        entries.push(Entry {
+            conf: wc,
+            processor,
+        });
+    }
+
+    println!("File:        {}", path.display());
+    let entry = match matchentry(&entries, path) {
+        Some(idx) => &entries[idx],
+        None => {
+            println!("Watch entry: none, the file is not in a watched folder");
+            return Ok(());
+        }
+    };
+    println!("Watch entry: {} ({})", entry.conf.name, entry.conf.inpath);
+
+    let shotre = Regex::new(&conf.shotid)
+        .context(format!("Invalid shot id regex {}", conf.shotid))?;
+    let shot = shot::shot_id(&shotre, &[path.to_path_buf()]);
+    println!("Shot id:     {}", shot.as_deref().unwrap_or("none"));
+
+    let (role, outputs) = entry.processor.explain(path)?;
+    println!("Processor:   {}", entry.conf.proc);
+    println!("Role:        {}", role);
+    for (i, o) in outputs.iter().enumerate() {
+        let label = if i == 0 { "Outputs:" } else { "" };
+        println!("{:<12} {}", label, o.display());
+    }
+
+    if let (Some(_), Some(shot)) = (&conf.acqlog, &shot) {
+        let mut metap = PathBuf::from(&entry.conf.outpath);
+        metap.push(format!("{}-meta.json", shot));
+        println!("Metadata:    {}", metap.display());
+    }
+
+    Ok(())
 }
 
-/// Call the process function on the debounced event, once for every distinct
-/// file path
+/// Call the process function of each watch entry on the paths of the
+/// debounced events that belong to it.
+///
+/// Paths outside of every watched folder (e.g. from the append source) belong
+/// to the main entry.
 fn handle_events(
-    proc: &Box<dyn Process>,
+    entries: &[Entry],
+    conf: &Config,
+    shotre: &Regex,
+    state: &mut State,
     events: Vec<DebouncedEvent>,
 ) -> Result<()> {
-    let mut paths = vec![];
-    for ev in events {
-        for p in ev.paths.clone() {
-            paths.push(p);
-        }
+    for ev in &events {
+        receipt::record(&ev.paths, ev.time, conf.skew.as_ref());
     }
+    let classified = events::classify(&conf.events, &events);
+    let mut paths = classified.candidates;
     paths.dedup();
     debug!("Event paths: {:?}", paths);
+
+    if !classified.removed.is_empty() {
+        debug!("Removed paths: {:?}", classified.removed);
+        state.retries.retain(|(_, b)| {
+            let cancel = b.paths.iter().any(|p| classified.removed.contains(p));
+            if cancel {
+                info!("Cancelling pending retry of deleted {:?}", b.paths);
+            }
+            !cancel
+        });
+    }
+
+    let mut split: Vec<Vec<PathBuf>> = vec![vec![]; entries.len()];
+    for p in paths {
+        let idx = matchentry(entries, &p).unwrap_or(0);
+        split[idx].push(p);
+    }
+
+    for (idx, paths) in split.into_iter().enumerate() {
+        if !paths.is_empty() {
+            // Fresh events supersede pending retries of the same files
+            state.retries.retain(|(_, b)| {
+                b.entry != idx || !b.paths.iter().any(|p| paths.contains(p))
+            });
+            let batch = Batch {
+                entry: idx,
+                paths,
+                attempt: 0,
+                waits: 0,
+                failures: 0,
+            };
+            handle_entry(entries, conf, shotre, state, batch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Handle the batches whose retry is due.
+fn handle_retries(
+    entries: &[Entry],
+    conf: &Config,
+    shotre: &Regex,
+    state: &mut State,
+) -> Result<()> {
+    let now = Instant::now();
+    let paused = &state.paused;
+    let (due, pending) = std::mem::take(&mut state.retries)
+        .into_iter()
+        .partition(|(t, b)| *t <= now && !paused.contains(&b.entry));
+    state.retries = pending;
+    for (_, batch) in due {
+        handle_entry(entries, conf, shotre, state, batch)?;
+    }
+    Ok(())
+}
+
+/// Check that the sis files of a batch have all been completely written,
+/// scheduling a new check if not. Returns whether the batch can be processed.
+fn completebatch(cc: &CompleteConf, state: &mut State, batch: &Batch) -> bool {
+    let incomplete = batch
+        .paths
+        .iter()
+        .filter(|p| p.extension().is_some_and(|e| e == "sis"))
+        .filter(|p| {
+            match (SisImg::expected_len(p), p.metadata().map(|m| m.len())) {
+                (Ok(Some(expected)), Ok(len)) => len < expected,
+                // Frame size not there yet
+                (Ok(None), Ok(len)) => len < (SIS_WIDTH_OFFSET + 2) as u64,
+                _ => false,
+            }
+        })
+        .collect::<Vec<&PathBuf>>();
+    if incomplete.is_empty() {
+        return true;
+    }
+
+    if batch.waits < cc.retries {
+        debug!("Files {:?} still being written, waiting", incomplete);
+        let due = Instant::now() + Duration::from_secs_f64(cc.retry_s);
+        let mut retry = batch.clone();
+        retry.waits += 1;
+        state.retries.push((due, retry));
+        false
+    } else {
+        warn!(
+            "Files {:?} still incomplete after {} checks, processing anyway",
+            incomplete, cc.retries
+        );
+        true
+    }
+}
+
+/// Verify the checksums of a batch, scheduling a retry or quarantining it on
+/// mismatch. Returns whether the batch can be processed.
+fn checkbatch(
+    ck: &ChecksumConf,
+    state: &mut State,
+    batch: &Batch,
+) -> Result<bool> {
+    let mut bad = vec![];
+    let mut unverified = vec![];
+    for p in &batch.paths {
+        match checksum::verify(p) {
+            Ok(Verdict::Ok) => {}
+            Ok(Verdict::NoChecksum) => unverified.push(p),
+            _ => bad.push(p),
+        }
+    }
+    if bad.is_empty() && unverified.is_empty() {
+        return Ok(true);
+    }
+
+    if batch.attempt < ck.retries {
+        // The companion can arrive after the frame
+        if bad.is_empty() {
+            debug!(
+                "No checksum yet for {:?}, waiting ({}/{})",
+                unverified,
+                batch.attempt + 1,
+                ck.retries
+            );
+        } else {
+            warn!(
+                "Checksum mismatch for {:?}, retrying ({}/{})",
+                bad,
+                batch.attempt + 1,
+                ck.retries
+            );
+        }
+        let due = Instant::now() + Duration::from_secs_f64(ck.retry_s);
+        let mut retry = batch.clone();
+        retry.attempt += 1;
+        state.retries.push((due, retry));
+    } else if bad.is_empty() {
+        warn!(
+            "No checksum for {:?} after {} retries, processing unverified",
+            unverified, ck.retries
+        );
+        return Ok(true);
+    } else {
+        error!(
+            "Checksum mismatch for {:?} after {} retries, quarantining",
+            bad, ck.retries
+        );
+        checksum::quarantine(ck, &batch.paths)?;
+    }
+    Ok(false)
+}
+
+/// Hand a published shot to the sinks that are best-effort: the archival
+/// pass, the zarr store, the gallery and the terminal preview.
+///
+/// They run after the transaction is closed, so the files they write are
+/// never rolled back, and a failure is only logged: it cannot fail a shot
+/// that is already published.
+fn besteffort(
+    state: &mut State,
+    idx: usize,
+    name: &str,
+    shot: Option<&str>,
+    paths: &[PathBuf],
+    factor: f64,
+    outputs: &[PathBuf],
+) {
+    debug_assert!(!transaction::journaling());
+    match (shot, &state.archive) {
+        (Some(shot), Some(archive)) => {
+            let job = Job {
+                entry: idx,
+                shot: String::from(shot),
+                paths: paths.to_vec(),
+                factor,
+            };
+            if archive.send(job).is_err() {
+                error!("Archival pass stopped, shot not archived");
+            }
+        }
+        (None, Some(_)) => {
+            warn!("Cannot find shot id in {:?}, not archived", paths)
+        }
+        _ => {}
+    }
+    let (Some(output), shot) = (outputs.last(), shot.unwrap_or_default())
+    else {
+        return;
+    };
+    if let Some(zarr) = state.zarr.as_mut() {
+        if let Err(e) = zarr.shot(name, shot, output) {
+            warn!("Cannot add shot to the zarr store: {:?}", e);
+        }
+    }
+    if let Some(gallery) = &state.gallery {
+        if let Err(e) = gallery.shot(name, shot, output) {
+            warn!("Cannot add shot to the gallery: {:?}", e);
+        }
+    }
+    if let Some(preview) = &state.preview {
+        if let Err(e) = preview.shot(name, shot, output) {
+            warn!("Cannot preview shot: {:?}", e);
+        }
+    }
+}
+
+/// Call the process function of the watch entry on its event paths.
+fn handle_entry(
+    entries: &[Entry],
+    conf: &Config,
+    shotre: &Regex,
+    state: &mut State,
+    batch: Batch,
+) -> Result<()> {
+    let entry = &entries[batch.entry];
+    let _ctx = logctx::enter(&entry.conf.name);
+
+    if conf.complete.enabled && !completebatch(&conf.complete, state, &batch) {
+        return Ok(());
+    }
+    if let Some(ck) = &conf.checksum {
+        if !checkbatch(ck, state, &batch)? {
+            let shot = shot::shot_id(shotre, &batch.paths);
+            if let (true, Some(shot)) = (batch.attempt >= ck.retries, shot) {
+                let gaps = state.gaps.entry(entry.conf.name.clone());
+                gaps.or_default().quarantined(&shot);
+            }
+            return Ok(());
+        }
+    }
+    // Held until the shot is processed
+    let _guard = match &conf.lock {
+        Some(lc) => match lockfile::acquire(&batch.paths, lc) {
+            Ok(Some(guard)) => Some(guard),
+            Ok(None) => {
+                let due = Instant::now() + Duration::from_secs_f64(lc.retry_s);
+                state.retries.push((due, batch));
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Cannot lock inputs, processing anyway: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let paths = batch.paths;
+
     let start = Instant::now();
-    let stat = proc.proc(paths);
+    let before = Usage::now();
+    let clipped_before = absorption::clipped_count();
+    let nfiles = paths.len();
+    let shot = shot::shot_id(shotre, &paths);
+    let mut factor = 1.0;
+    if let Some(corr) = state.corrections.as_mut() {
+        match shot.as_deref().map(|s| corr.get(s)) {
+            Some(Ok(Some(f))) => {
+                debug!("Correction factor {}", f);
+                factor = f;
+            }
+            Some(Err(e)) => warn!("Cannot get correction factor: {:?}", e),
+            _ => warn!("No correction factor for {:?}, using 1", paths),
+        }
+    }
+    let tc = conf.transaction.as_ref();
+    transaction::begin();
+    let mut stat = entry.processor.proc(paths.clone(), factor);
+    state.processed += 1;
+    if let (Ok(outputs), false) = (&stat, state.routes.is_empty()) {
+        let outpath = Path::new(&entry.conf.outpath);
+        match hooks::route(&state.routes, &entry.conf.name, outpath, outputs) {
+            Ok(routed) => stat = Ok(routed),
+            Err(e) if tc.is_some() => stat = Err(e.context("Cannot route")),
+            Err(e) => warn!("Cannot route shot: {:?}", e),
+        }
+    }
+    if let (Ok(_), Some(log)) = (&stat, state.acqlog.as_mut()) {
+        let outpath = &entry.conf.outpath;
+        match pairparams(log, conf, outpath, shotre, &paths) {
+            Ok(()) => {}
+            Err(e) if tc.is_some() => stat = Err(e.context("Cannot pair")),
+            Err(e) => warn!("Cannot pair acquire.py parameters: {:?}", e),
+        }
+    }
+    // Rows of the shot index are rolled back with the files
+    let mut indexed = None;
+    if let (Ok(_), true) = (&stat, conf.shotdb) {
+        let dir = Path::new(&entry.conf.outpath);
+        let id = shot.as_deref().unwrap_or_default();
+        let files = transaction::written();
+        indexed = Some(shotdb::mark(dir));
+        match shotdb::record(dir, id, &files, &conf.format) {
+            Ok(()) => {}
+            Err(e) if tc.is_some() => stat = Err(e.context("Cannot index")),
+            Err(e) => warn!("Cannot index shot: {:?}", e),
+        }
+    }
+    let mut delivered = vec![];
+    if let (Ok(outputs), false) = (&stat, state.consumers.is_empty()) {
+        let id = shot.as_deref().unwrap_or_default();
+        let params = state.acqlog.as_ref().and_then(|l| l.get(id));
+        let attrs =
+            consumers::attributes(&entry.conf.name, id, outputs, params);
+        match attrs
+            .and_then(|a| consumers::deliver(&state.consumers, &a, outputs))
+        {
+            Ok(d) => delivered = d,
+            Err(e) if tc.is_some() => stat = Err(e.context("Cannot deliver")),
+            Err(e) => warn!("Cannot deliver shot to consumers: {:?}", e),
+        }
+    }
+    // Announced last, once every other sink has its part of the shot. A
+    // shot someone was told about is published: it is not rolled back for
+    // the announcements that fail after that.
+    let mut told = false;
+    for (n, output) in delivered {
+        if stat.is_err() {
+            break;
+        }
+        let shot = shot.as_deref().unwrap_or_default();
+        match state.consumers[n].notify(&entry.conf.name, shot, &output) {
+            Ok(()) => told = true,
+            Err(e) if tc.is_some() && !told => stat = Err(e),
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+    if let (Ok(outputs), Some(ann)) = (&stat, &state.announcer) {
+        if let Some(output) = outputs.last() {
+            let shot = shot.clone().unwrap_or_default();
+            match ann.shot(&entry.conf.name, &shot, output) {
+                Ok(()) => {}
+                Err(e) if tc.is_some() && !told => stat = Err(e),
+                Err(e) => warn!("Cannot announce shot: {:?}", e),
+            }
+        }
+    }
+    let written = transaction::end();
+    let mut retrying = false;
+    if let (Err(e), Some(tc)) = (&stat, tc) {
+        let removed = transaction::rollback(&written);
+        if let Some(mark) = indexed {
+            let dir = Path::new(&entry.conf.outpath);
+            if let Err(e) = shotdb::rollback(dir, mark) {
+                warn!("{:?}", e);
+            }
+        }
+        if batch.failures < tc.retries {
+            warn!(
+                "Shot failed, {} files rolled back, retrying ({}/{}): {:?}",
+                removed,
+                batch.failures + 1,
+                tc.retries,
+                e
+            );
+            let retry = Batch {
+                entry: batch.entry,
+                paths: paths.clone(),
+                attempt: batch.attempt,
+                waits: batch.waits,
+                failures: batch.failures + 1,
+            };
+            let due = Instant::now() + Duration::from_secs_f64(tc.retry_s);
+            state.retries.push((due, retry));
+            retrying = true;
+        } else {
+            error!(
+                "Shot failed after {} retries, {} files rolled back",
+                tc.retries, removed
+            );
+        }
+    }
+    if let (Ok(_), Some(log), Some(shot)) =
+        (&stat, state.acqlog.as_mut(), &shot)
+    {
+        log.take(shot);
+    }
     let end = Instant::now();
+    let total = Usage::now();
+    let gaps = state.gaps.entry(entry.conf.name.clone()).or_default();
+    let gap = shot.as_deref().map(|s| gaps.shot(s)).unwrap_or_default();
+    let missing = gaps.count();
+    let (empty, spurious) = events::spurious_counts();
+    let (missed_shots, alerts) = deadman::counts();
+    let clipped = absorption::clipped_count() - clipped_before;
+    // Dumped also for failed shots, whose stages are the interesting ones
+    let dump = conf.debug.as_ref().zip(shot.as_ref());
+    if let Some((dc, shot)) = dump.filter(|(dc, s)| dc.shots.contains(s)) {
+        let dir = match &dc.folder {
+            Some(f) => PathBuf::from(f),
+            None => Path::new(&entry.conf.outpath).join("debug"),
+        };
+        let dumped = fs::create_dir_all(&dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| entry.processor.stages(&paths, factor, &dir, shot));
+        match dumped {
+            Ok(d) => {
+                info!("{} intermediate stages written to {:?}", d.len(), dir)
+            }
+            Err(e) => warn!("Cannot write intermediate stages: {:?}", e),
+        }
+    }
+    let used = total.since(&before);
+    if let Ok(outputs) = &stat {
+        let name = &entry.conf.name;
+        let shot = shot.as_deref();
+        besteffort(state, batch.entry, name, shot, &paths, factor, outputs);
+    }
+    if let Some(report) = &conf.report {
+        let status = match (&stat, retrying) {
+            (Ok(_), _) => "ok",
+            (Err(_), true) => "retrying",
+            (Err(_), false) => "error",
+        };
+        let rec = vec![
+            (
+                String::from("format_version"),
+                Value::Int(i64::from(FORMAT_VERSION)),
+            ),
+            (
+                String::from("behavior_rev"),
+                Value::Int(i64::from(history::current())),
+            ),
+            (String::from("time"), Value::Time(SystemTime::now())),
+            (String::from("watch"), Value::Str(entry.conf.name.clone())),
+            (
+                String::from("processor"),
+                Value::Str(entry.conf.proc.clone()),
+            ),
+            (String::from("files"), Value::Int(nfiles as i64)),
+            (
+                String::from("elapsed_s"),
+                Value::Float((end - start).as_secs_f64()),
+            ),
+            (String::from("status"), Value::Str(String::from(status))),
+            (
+                String::from("read_mb"),
+                Value::Float(usage::mb(used.bytes_read)),
+            ),
+            (
+                String::from("written_mb"),
+                Value::Float(usage::mb(used.bytes_written)),
+            ),
+            (
+                String::from("files_written"),
+                Value::Int(used.files_written as i64),
+            ),
+            (
+                String::from("run_read_mb"),
+                Value::Float(usage::mb(total.bytes_read)),
+            ),
+            (
+                String::from("run_written_mb"),
+                Value::Float(usage::mb(total.bytes_written)),
+            ),
+            (
+                String::from("run_files_read"),
+                Value::Int(total.files_read as i64),
+            ),
+            (
+                String::from("run_files_written"),
+                Value::Int(total.files_written as i64),
+            ),
+            (String::from("gap"), Value::Str(gaps::ranges(&gap))),
+            (String::from("run_empty_batches"), Value::Int(empty as i64)),
+            (
+                String::from("run_spurious_paths"),
+                Value::Int(spurious as i64),
+            ),
+            (String::from("run_missing"), Value::Int(missing as i64)),
+            (
+                String::from("run_missed_shots"),
+                Value::Int(missed_shots as i64),
+            ),
+            (
+                String::from("run_deadman_alerts"),
+                Value::Int(alerts as i64),
+            ),
+            (String::from("clipped_pixels"), Value::Int(clipped as i64)),
+        ];
+        if let Err(e) = textout::append(Path::new(report), &conf.format, &rec) {
+            warn!("Cannot write report: {:?}", e);
+        }
+    }
     match stat {
-        Ok(()) => {
+        Ok(_) => {
             let elapsed = end - start;
             info!(
                 "Events handled. Total elapsed time {} s.",
                 elapsed.as_secs()
             );
+            debug!(
+                "Run totals: {} files ({:.1} MB) read, {} files ({:.1} MB) \
+                 written",
+                total.files_read,
+                usage::mb(total.bytes_read),
+                total.files_written,
+                usage::mb(total.bytes_written)
+            );
         }
         Err(e) => {
             error!("Error while processing events: {:?}.\nRetrying.", e);
@@ -388,63 +1851,466 @@ fn getloglvl(conf: &Config) -> LogSpecification {
 }
 
 /// Check that specified filepaths are not identical, and that they are folders.
-fn checkpaths(conf: &Config) -> Result<()> {
-    debug!("Checking paths.");
+fn checkpaths(conf: &WatchConf) -> Result<()> {
+    debug!("Checking paths of watch entry {}.", conf.name);
 
     if conf.inpath == conf.outpath {
-        bail!("Input path and output path must not be identical.");
+        bail!(
+            "[{}] Input path and output path must not be identical.",
+            conf.name
+        );
     }
 
     if !Path::new(&conf.inpath).is_dir() {
-        bail!("Input path must be a directory.");
+        bail!("[{}] Input path must be a directory.", conf.name);
     }
 
     if !Path::new(&conf.outpath).is_dir() {
-        bail!("Output path must be a directory.");
+        bail!("[{}] Output path must be a directory.", conf.name);
+    }
+
+    if let Some(root) = &conf.archive_root {
+        if root == &conf.inpath || root == &conf.outpath {
+            bail!(
+                "[{}] Archive root must differ from input and output paths.",
+                conf.name
+            );
+        }
+        if !Path::new(root).is_dir() {
+            bail!("[{}] Archive root must be a directory.", conf.name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Static description of a processor
+struct ProcInfo {
+    /// Name used in the configuration
+    name: &'static str,
+    /// Parameters accepted in the processors.<name> table
+    params: &'static [&'static str],
+    /// Parameters that must be given
+    required: &'static [&'static str],
+}
+
+/// All the available processors
+const PROCESSORS: [ProcInfo; 2] = [
+    ProcInfo {
+        name: "identity",
+        params: &[],
+        required: &[],
+    },
+    ProcInfo {
+        name: "fkspecies",
+        params: &[
+            "od_scale",
+            "od_offset",
+            "frames",
+            "copy_raws",
+            "variance",
+            "roi",
+            "max_clipped",
+        ],
+        required: &[],
+    },
+];
+
+/// Description of the processor with the given name.
+fn procinfo(name: &str) -> Option<&'static ProcInfo> {
+    PROCESSORS.iter().find(|p| p.name == name)
+}
+
+/// Absolute path with `.` and `..` resolved, and links too if it exists.
+fn normalized(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    let abs = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut out = PathBuf::new();
+    for c in abs.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Name of the watch entry whose input folder holds the path, if any.
+fn watched_by(conf: &Config, path: &str) -> Option<String> {
+    let path = normalized(Path::new(path));
+    conf.entries()
+        .into_iter()
+        .find(|wc| path.starts_with(normalized(Path::new(&wc.inpath))))
+        .map(|wc| wc.name)
+}
+
+/// Validate the merged configuration against the processor declarations,
+/// listing all of the unknown processors and missing or unknown parameters.
+fn validate(figment: &Figment, conf: &Config) -> Result<()> {
+    let mut problems = vec![];
+    let names = PROCESSORS.iter().map(|p| p.name).collect::<Vec<&str>>();
+
+    for wc in conf.entries() {
+        if procinfo(&wc.proc).is_none() {
+            problems.push(format!(
+                "[{}] unknown processor {}, possible values are {:?}",
+                wc.name, wc.proc, names
+            ));
+        }
+    }
+
+    // Keys are taken from the raw configuration, since the typed one
+    // silently drops what it does not know
+    let given = figment
+        .extract_inner::<BTreeMap<String, BTreeMap<String, IgnoredAny>>>(
+            "processors",
+        )
+        .unwrap_or_default();
+    for (proc, params) in &given {
+        let Some(info) = procinfo(proc) else {
+            problems.push(format!("unknown key processors.{}", proc));
+            continue;
+        };
+        for key in params.keys() {
+            if !info.params.contains(&key.as_str()) {
+                problems.push(format!(
+                    "unknown key processors.{}.{}, accepted keys are {:?}",
+                    proc, key, info.params
+                ));
+            }
+        }
+    }
+
+    for wc in conf.entries() {
+        let Some(info) = procinfo(&wc.proc) else {
+            continue;
+        };
+        for key in info.required {
+            let present = given
+                .get(info.name)
+                .is_some_and(|params| params.contains_key(*key));
+            if !present {
+                problems.push(format!(
+                    "[{}] missing key processors.{}.{}",
+                    wc.name, info.name, key
+                ));
+            }
+        }
+    }
+
+    let uses = |name: &str| conf.entries().iter().any(|wc| wc.proc == name);
+    let fkframes = &conf.processors.fkspecies.frames;
+    if uses("fkspecies") {
+        if let Err(e) = absorption::check(fkframes) {
+            problems.push(format!("processors.fkspecies.frames: {}", e));
+        }
+        // The height of the camera frames is only known for the warm-up
+        let trimmed = conf.trim.top + conf.trim.bottom;
+        match &conf.warmup {
+            Some(w) => {
+                let height = w.height.saturating_sub(trimmed);
+                if let Err(e) = absorption::check_height(fkframes, height) {
+                    problems.push(format!("warmup.height with [trim]: {}", e));
+                }
+            }
+            // An even frame height is odd after an odd trim
+            None if absorption::check_height(fkframes, trimmed).is_err() => {
+                warn!(
+                    "[trim] removes an odd number of rows, kinetics frames of \
+                 even height become odd and cannot be processed"
+                )
+            }
+            None => {}
+        }
+        let max_clipped = conf.processors.fkspecies.max_clipped;
+        if max_clipped.is_some_and(|m| !(0.0..=1.0).contains(&m)) {
+            problems.push(String::from(
+                "processors.fkspecies.max_clipped must be between 0 and 1",
+            ));
+        }
+    }
+
+    let names = conf
+        .entries()
+        .into_iter()
+        .map(|wc| wc.name)
+        .collect::<Vec<_>>();
+    for (n, rc) in conf.route.iter().enumerate() {
+        if let Err(e) = Route::new(rc) {
+            problems.push(format!("route {}: {:#}", n, e));
+        }
+        if let Some(w) = rc.watch.as_ref().filter(|w| !names.contains(w)) {
+            problems.push(format!("route {}: unknown watch entry {}", n, w));
+        }
+    }
+    for (n, cc) in conf.consumer.iter().enumerate() {
+        if let Err(e) = cc.check() {
+            problems.push(format!("consumer {}: {:#}", cc.name, e));
+        }
+        if conf.consumer[..n].iter().any(|c| c.name == cc.name) {
+            problems.push(format!("consumer {}: name used twice", cc.name));
+        }
+    }
+
+    // Shots from the append source go to the main entry
+    if let (Some(append), "fkspecies") = (&conf.append, conf.proc.as_str()) {
+        if append.frames != fkframes.len() {
+            problems.push(format!(
+                "append.frames is {}, but processor fkspecies needs {} frames",
+                append.frames,
+                fkframes.len(),
+            ));
+        }
     }
 
+    // Spooled frames are sent as events, the watcher would send them again
+    let spools = [
+        ("tcp.spool", conf.tcp.as_ref().map(|t| &t.spool)),
+        ("append.spool", conf.append.as_ref().map(|a| &a.spool)),
+    ];
+    for (key, spool) in spools {
+        let Some(name) = spool.and_then(|s| watched_by(conf, s)) else {
+            continue;
+        };
+        problems
+            .push(format!("{} is inside the input folder of [{}]", key, name));
+    }
+
+    problems.dedup();
+    if !problems.is_empty() {
+        bail!("Invalid configuration:\n\t{}", problems.join("\n\t"));
+    }
+    debug!("Configuration validated.");
     Ok(())
 }
 
 /// Get the processor selected by the user
-fn getproc(conf: &Config) -> Result<Box<dyn Process>> {
+fn getproc(
+    wc: &WatchConf,
+    conf: &Config,
+    writer: &Writer,
+) -> Result<Box<dyn Process>> {
     // I swear I tried to make this better, but I couldn't.
-    let procs = vec![String::from("identity"), String::from("dummy")];
-    if conf.proc == "identity" {
-        Ok(Box::new(Identity::new(&conf.outpath)))
-    } else if conf.proc == "fkspecies" {
-        Ok(Box::new(FKSpecies::new(&conf.outpath)))
+    let procs = PROCESSORS.iter().map(|p| p.name).collect::<Vec<&str>>();
+    if wc.proc == "identity" {
+        Ok(Box::new(Identity::new(&wc.outpath, writer)))
+    } else if wc.proc == "fkspecies" {
+        Ok(Box::new(FKSpecies::new(wc, conf, writer)?))
     } else {
         bail!(
-            "Processor {} unknown, possible values are {:?}",
-            conf.proc,
+            "[{}] Processor {} unknown, possible values are {:?}",
+            wc.name,
+            wc.proc,
             procs
         )
     }
 }
 
 fn main() -> Result<()> {
-    let conf: Config = Figment::new()
+    let cli = Cli::parse();
+    let command = cli.command.clone();
+    let overrides = cli.set.clone();
+    let sets = cli
+        .set
+        .iter()
+        .map(|s| parseset(s))
+        .collect::<Result<Vec<_>>>()?;
+    let mut figment = Figment::new()
         .merge(Toml::file("conf/default.toml"))
-        .merge(Serialized::defaults(Cli::parse()))
-        .extract()?;
+        .merge(Serialized::defaults(cli));
+    // Overrides from --set always win
+    for (key, val) in sets {
+        figment = figment.merge(Serialized::default(&key, val));
+    }
+    let conf: Config = figment.extract()?;
 
+    // Started first, so that the warnings of the validation are shown
     let loglvl = getloglvl(&conf);
     let _logger = Logger::with(loglvl)
+        .format(logctx::format)
         .start()
         .unwrap_or_else(|e| panic!("Cannot start logger. Error:\n{}", e));
+    validate(&figment, &conf)?;
 
-    checkpaths(&conf)?;
-
-    let processor = getproc(&conf)?;
-    if !conf.quiet {
-        println!("Chosen processor: {}", conf.proc);
+    match command {
+        Some(Command::Migrate { paths, dry_run }) => {
+            return version::migrate(&paths, dry_run);
+        }
+        Some(Command::Explain { path }) => {
+            return explain(&conf, &path);
+        }
+        Some(Command::Bench {
+            height,
+            width,
+            repeat,
+        }) => {
+            return bench::run(height, width, repeat);
+        }
+        Some(Command::Regress {
+            session,
+            baseline,
+            entry,
+            record,
+        }) => {
+            return regress::run(&conf, &session, &baseline, &entry, record);
+        }
+        Some(Command::AbortRun) => {
+            return control::send(conf.control.as_ref(), Request::AbortRun);
+        }
+        Some(Command::History { json }) => {
+            history::print(json);
+            return Ok(());
+        }
+        Some(Command::CalibrateNoise {
+            darks,
+            flats,
+            output,
+        }) => {
+            return noise::calibrate(
+                &darks,
+                &flats,
+                &conf.trim,
+                output.as_deref(),
+            );
+        }
+        Some(Command::Soak(args)) => {
+            return soak::run(&conf, &overrides, &args);
+        }
+        None => {}
     }
 
-    let inpath = Path::new(&conf.inpath);
+    let writer = Writer::new(conf.verify_writes, conf.naming, conf.provenance);
+    let mut entries = vec![];
+    for wc in conf.entries() {
+        checkpaths(&wc)?;
+        let processor = getproc(&wc, &conf, &writer)?;
+        if !conf.quiet {
+            println!("[{}] Chosen processor: {}", wc.name, wc.proc);
+        }
+        entries.push(Entry {
+            conf: wc,
+            processor,
+        });
+    }
+    if conf.shotdb {
+        for entry in &entries {
+            let _ctx = logctx::enter(&entry.conf.name);
+            let dir = Path::new(&entry.conf.outpath);
+            match shotdb::audit(dir, conf.repair) {
+                Ok(n) if n > 0 && !conf.repair => {
+                    warn!("{} discrepancies in the shot index, see --repair", n)
+                }
+                Ok(_) => {}
+                Err(e) => error!("Cannot audit the shot index: {:?}", e),
+            }
+        }
+    }
+    let shotre = Regex::new(&conf.shotid)
+        .context(format!("Invalid shot id regex {}", conf.shotid))?;
+    let mut state = State {
+        acqlog: conf.acqlog.as_ref().map(AcqLog::new).transpose()?,
+        announcer: conf.announce.as_ref().map(Announcer::new).transpose()?,
+        gallery: conf
+            .gallery
+            .as_ref()
+            .map(|gc| Gallery::new(gc, &conf.colormap))
+            .transpose()?,
+        preview: match conf.preview {
+            true => Some(Preview::new(&conf.terminal, &conf.colormap)?),
+            false => None,
+        },
+        zarr: conf.zarr.as_ref().map(Zarr::new).transpose()?,
+        gaps: BTreeMap::new(),
+        routes: conf.route.iter().map(Route::new).collect::<Result<_>>()?,
+        consumers: conf
+            .consumer
+            .iter()
+            .map(Consumer::new)
+            .collect::<Result<_>>()?,
+        archive: None,
+        corrections: conf.corrections.as_ref().map(Corrections::new),
+        paused: vec![],
+        retries: vec![],
+        aborted: None,
+        processed: 0,
+    };
+    if let Some(ac) = &conf.archive {
+        let mut procs = vec![];
+        for wc in conf.entries() {
+            let proc = getproc(&wc, &conf, &writer)?;
+            procs.push((wc.name, proc));
+        }
+        state.archive = Some(archive::spawn(ac, procs)?);
+        if !conf.quiet {
+            println!("Archiving shots to: {}", ac.outpath);
+        }
+    }
+    if let (Some(gc), false) = (&conf.gallery, conf.quiet) {
+        println!("Serving gallery on: http://{}/", gc.bind);
+    }
+    if let Some(wu) = &conf.warmup {
+        for entry in &entries {
+            let _ctx = logctx::enter(&entry.conf.name);
+            let start = Instant::now();
+            match entry.processor.warmup(wu.height, wu.width) {
+                Ok(()) => info!(
+                    "Processor warmed up in {:.3} s",
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(e) => warn!("Cannot warm processor up: {:?}", e),
+            }
+        }
+        // Calibrations are loaded now rather than with the first shot
+        if let Some(corr) = state.corrections.as_mut() {
+            if let Err(e) = corr.get("") {
+                warn!("Cannot load correction factors: {:?}", e);
+            }
+        }
+    }
+    let mut backpressure = conf
+        .backpressure
+        .as_ref()
+        .map(BackPressure::new)
+        .transpose()?;
+    let mut deadman = conf.deadman.as_ref().map(DeadMan::new);
+    let mut runs = match &conf.runs {
+        Some(rc) => {
+            let effective = toml::to_string(&conf)
+                .context("Cannot serialize the configuration")?;
+            let mut files = vec![PathBuf::from("conf/default.toml")];
+            files.extend(conf.noise.calibration.iter().map(PathBuf::from));
+            files
+                .extend(conf.corrections.iter().map(|c| c.path.clone().into()));
+            let dirs = entries
+                .iter()
+                .map(|e| PathBuf::from(&e.conf.outpath))
+                .collect();
+            Some(Runs::new(rc, effective, files, dirs, conf.format))
+        }
+        None => None,
+    };
 
     let (tx, rx) = mpsc::channel();
 
+    if let Some(append) = &conf.append {
+        appendsrc::spawn(append, tx.clone())?;
+        if !conf.quiet {
+            println!("Tailing file: {}", append.path);
+        }
+    }
+    if let Some(tcp) = &conf.tcp {
+        tcpsrc::spawn(tcp, tx.clone())?;
+        if !conf.quiet {
+            println!("Receiving frames on: {}", tcp.bind);
+        }
+    }
+
+    let catchup_tx = tx.clone();
     let mut debouncer = notify_debouncer_full::new_debouncer(
         Duration::from_millis(1500),
         None,
@@ -453,19 +2319,152 @@ fn main() -> Result<()> {
 
     let watcher = debouncer.watcher();
 
-    watcher.watch(inpath, RecursiveMode::Recursive)?;
+    for entry in &entries {
+        let inpath = Path::new(&entry.conf.inpath);
+        watcher.watch(inpath, RecursiveMode::Recursive)?;
+        if !conf.quiet {
+            println!(
+                "[{}] Watching path: {}",
+                entry.conf.name,
+                inpath.display()
+            );
+        }
+    }
     // TODO: implement ctrl-c handling with unwatch
 
-    if !conf.quiet {
-        println!("{} {}", "Watching path: ", conf.inpath);
-    }
+    let mut probes = match &conf.health {
+        Some(hc) => entries
+            .iter()
+            .map(|e| Probe::new(Path::new(&e.conf.inpath), hc))
+            .collect(),
+        None => vec![],
+    };
+    let probe_interval = conf
+        .health
+        .as_ref()
+        .map_or(Duration::ZERO, |h| Duration::from_secs_f64(h.interval_s));
+    let mut last_probe = Instant::now();
+
+    // Batches are queued explicitly, so that the backlog can be measured
+    let mut queue = VecDeque::new();
+    loop {
+        let requests = conf.control.as_ref().map(control::poll);
+        for req in requests.unwrap_or_default() {
+            match req {
+                Request::AbortRun => {
+                    queue.extend(rx.try_iter());
+                    warn!(
+                        "Run aborted, dropping {} queued event batches and {} \
+                         pending retries",
+                        queue.len(),
+                        state.retries.len()
+                    );
+                    queue.clear();
+                    state.retries.clear();
+                    // Events still in the debouncer are dropped when they come
+                    state.aborted = Some(Instant::now());
+                    for (name, gaps) in state.gaps.iter_mut() {
+                        let _ctx = logctx::enter(name);
+                        gaps.close();
+                    }
+                    if let Some(zarr) = state.zarr.as_mut() {
+                        zarr.close();
+                    }
+                    if let Some(r) = runs.as_mut() {
+                        r.abort();
+                    }
+                }
+            }
+        }
+        if !probes.is_empty() && last_probe.elapsed() >= probe_interval {
+            last_probe = Instant::now();
+            for (idx, probe) in probes.iter_mut().enumerate() {
+                let _ctx = logctx::enter(&entries[idx].conf.name);
+                let inpath = Path::new(&entries[idx].conf.inpath);
+                match probe.check() {
+                    Some(Change::Lost) => state.paused.push(idx),
+                    Some(Change::Back(since)) => {
+                        state.paused.retain(|&i| i != idx);
+                        // The old watch is on the vanished folder
+                        let _ = watcher.unwatch(inpath);
+                        if let Err(e) =
+                            watcher.watch(inpath, RecursiveMode::Recursive)
+                        {
+                            error!("Cannot watch {:?} again: {:?}", inpath, e);
+                        }
+                        let since = receipt::to_file_clock(since);
+                        match health::catchup(inpath, since) {
+                            Ok(files) if !files.is_empty() => {
+                                info!("Catching up {} files", files.len());
+                                // One batch per shot, as if they had just
+                                // been written
+                                for files in shot::group(&shotre, files) {
+                                    let batch = vec![events::created(files)];
+                                    catchup_tx.send(Ok(batch))?;
+                                }
+                            }
+                            Ok(_) => info!("No files to catch up"),
+                            Err(e) => error!("Cannot catch up: {:?}", e),
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        let processed = state.processed;
+        if queue.is_empty() {
+            match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(res) => queue.push_back(res),
+                Err(RecvTimeoutError::Timeout) => {
+                    handle_retries(&entries, &conf, &shotre, &mut state)?;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        queue.extend(rx.try_iter());
 
-    for res in rx {
-        match res {
-            Ok(events) => {
-                handle_events(&processor, events)?;
+        // Updated also while idle, so that the flag is cleared
+        if let Some(bp) = backpressure.as_mut() {
+            if let Err(e) = bp.update(queue.len() + state.retries.len()) {
+                warn!("Cannot update back-pressure flag: {:?}", e);
             }
-            Err(e) => bail!("Error while processing events:\n\t{:?}", e),
+        }
+
+        match queue.pop_front() {
+            Some(Ok(mut events)) => {
+                // Events of an aborted run are dropped
+                let dropped = state.aborted.is_some_and(|t| {
+                    events.retain(|ev| ev.time >= t);
+                    events.is_empty()
+                });
+                if !dropped {
+                    if runs.as_mut().is_some_and(|r| r.shot()) {
+                        for (name, gaps) in state.gaps.iter_mut() {
+                            let _ctx = logctx::enter(name);
+                            gaps.close();
+                        }
+                        if let Some(zarr) = state.zarr.as_mut() {
+                            zarr.close();
+                        }
+                    }
+                    handle_events(
+                        &entries, &conf, &shotre, &mut state, events,
+                    )?;
+                    handle_retries(&entries, &conf, &shotre, &mut state)?;
+                }
+            }
+            Some(Err(e)) => {
+                bail!("Error while processing events:\n\t{:?}", e)
+            }
+            None => {}
+        }
+        // Only shots reset the switch, not spurious events, which must not
+        // keep it from firing either
+        if let Some(dm) = deadman.as_mut() {
+            if state.processed > processed {
+                dm.shot();
+            }
+            dm.check();
         }
     }
 
@@ -474,7 +2473,11 @@ fn main() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Array2, PathBuf, SisImg};
+    use crate::{
+        normalized, Array2, Path, PathBuf, Provenance, SisImg, Stamp,
+        SIS_HEADER_LEN, SIS_HEIGHT_OFFSET, SIS_PAD_LEN, SIS_PREFIX_LEN,
+        SIS_WIDTH_OFFSET,
+    };
 
     #[test]
     fn test_write_read_sis() {
@@ -488,4 +2491,88 @@ mod tests {
         let img = SisImg::read(&path).unwrap();
         assert!(img.image == imgbuf.into_raw_vec());
     }
+
+    #[test]
+    fn test_write_read_stamp() {
+        let path = PathBuf::from("./test/write_stamp.sis");
+        let stamp = Stamp::od(500.0, 0.5);
+        SisImg::new(Array2::<u16>::eye(4))
+            .unwrap()
+            .with_stamp(stamp)
+            .write(path.clone())
+            .unwrap();
+
+        let img = SisImg::read(&path).unwrap();
+        assert_eq!(img.stamp, Some(stamp));
+    }
+
+    #[test]
+    fn test_write_read_provenance() {
+        let path = PathBuf::from("./test/write_provenance.sis");
+        let stamp = Stamp::od(1000.0, 1.0);
+        let prov = Provenance {
+            inputs_crc: 0xdeadbeef,
+            params_hash: 0x0123456789abcdef,
+            processor: String::from("fkspecies"),
+            behavior_rev: 9,
+        };
+        SisImg::new(Array2::<u16>::eye(4))
+            .unwrap()
+            .with_stamp(stamp)
+            .with_provenance(prov.clone())
+            .write(path.clone())
+            .unwrap();
+
+        let img = SisImg::read(&path).unwrap();
+        assert_eq!(img.stamp, Some(stamp));
+        assert_eq!(img.provenance, Some(prov));
+    }
+
+    #[test]
+    fn test_normalized() {
+        let base = normalized(Path::new("."));
+        assert_eq!(normalized(Path::new("./a/../b/./c")), base.join("b/c"));
+        assert!(normalized(Path::new("test/in/../in/spool"))
+            .starts_with(normalized(Path::new("./test/in"))));
+    }
+
+    #[test]
+    fn test_sis_header_offsets() {
+        assert_eq!(SIS_PREFIX_LEN, 10);
+        assert_eq!(SIS_HEIGHT_OFFSET, 10);
+        assert_eq!(SIS_WIDTH_OFFSET, 12);
+        assert_eq!(SIS_PAD_LEN, 186);
+        assert_eq!(SIS_HEADER_LEN, 200);
+    }
+
+    #[test]
+    fn test_write_sis_golden() {
+        let path = PathBuf::from("./test/write_golden.sis");
+        let arr = Array2::from_shape_vec((3, 2), vec![1, 2, 3, 4, 5, 0xabcd])
+            .unwrap();
+        SisImg::new(arr).unwrap().write(path.clone()).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 200 + 2 * 6);
+        assert_eq!(&bytes[..10], b"          ");
+        assert_eq!(&bytes[10..12], &[3, 0]);
+        assert_eq!(&bytes[12..14], &[2, 0]);
+        assert!(bytes[14..200].iter().all(|b| *b == b' '));
+        assert_eq!(&bytes[200..204], &[1, 0, 2, 0]);
+        assert_eq!(&bytes[210..212], &[0xcd, 0xab]);
+    }
+
+    #[test]
+    fn test_read_sis_golden() {
+        let path = PathBuf::from("./test/read_golden.sis");
+        let mut bytes = vec![0u8; 200];
+        bytes[10..14].copy_from_slice(&[1, 0, 2, 0]);
+        bytes.extend_from_slice(&[0x34, 0x12, 0xff, 0xff]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let img = SisImg::read(&path).unwrap();
+        assert_eq!((img.height, img.width), (1, 2));
+        assert_eq!(img.image, vec![0x1234, 0xffff]);
+        assert_eq!(img.stamp, None);
+    }
 }
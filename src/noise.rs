@@ -0,0 +1,204 @@
+//! Noise model of the cameras, and its calibration.
+//!
+//! The variance of a pixel of S counts is `S / gain + read_noise²`, with the
+//! gain in electrons per count and the read noise in counts; subtracting a
+//! dark frame adds its read noise once more. The variance of the OD follows
+//! from the relative variances of the atoms and of the bright reference.
+//!
+//! The parameters of a camera are estimated from calibration frames: the read
+//! noise from the difference of pairs of darks, and the gain from the photon
+//! transfer curve of pairs of flats taken at the same intensity (consecutive
+//! files, in name order). Differences of pairs cancel the fixed pattern.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum, input, input::Trim};
+
+fn default_gain() -> f64 {
+    1.0
+}
+
+/// Noise parameters of a camera
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoiseConf {
+    /// Gain, electrons per count
+    #[serde(default = "default_gain")]
+    pub gain: f64,
+    /// Read noise, counts
+    #[serde(default)]
+    pub read_noise: f64,
+    /// Calibration file written by calibrate-noise, overriding the above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<String>,
+}
+
+impl Default for NoiseConf {
+    fn default() -> Self {
+        NoiseConf {
+            gain: default_gain(),
+            read_noise: 0.0,
+            calibration: None,
+        }
+    }
+}
+
+impl NoiseConf {
+    /// Parameters in effect, from the calibration file if there is one.
+    pub fn load(&self) -> Result<NoiseConf> {
+        let Some(path) = &self.calibration else {
+            return Ok(self.clone());
+        };
+        let text = fs::read_to_string(path)
+            .context(format!("Cannot read noise calibration {:?}", path))?;
+        let cal: NoiseConf = toml::from_str(&text)
+            .context(format!("Invalid noise calibration {:?}", path))?;
+        debug!("Noise calibration from {:?}: {:?}", path, cal);
+        Ok(NoiseConf {
+            calibration: None,
+            ..cal
+        })
+    }
+
+    /// Relative variance of a dark subtracted exposure, infinite where there
+    /// is no signal.
+    pub fn relvar(&self, img: &Array2<f32>, dark: bool) -> Array2<f32> {
+        let reads = if dark { 2.0 } else { 1.0 };
+        let read = (reads * self.read_noise * self.read_noise) as f32;
+        let gain = self.gain as f32;
+        img.mapv(|x| {
+            if x > 0.0 {
+                (x / gain + read) / (x * x)
+            } else {
+                f32::INFINITY
+            }
+        })
+    }
+}
+
+/// Mean and half the variance of the difference of a pair of frames.
+fn pairstats(a: &Array2<u16>, b: &Array2<u16>) -> (f64, f64) {
+    let n = a.len() as f64;
+    let mean = (a.iter().chain(b.iter()).map(|&x| f64::from(x)).sum::<f64>())
+        / (2.0 * n);
+    let diff = a.mapv(f64::from) - b.mapv(f64::from);
+    let dmean = diff.sum() / n;
+    let var = diff.mapv(|d| (d - dmean).powi(2)).sum() / n;
+    (mean, var / 2.0)
+}
+
+/// Read noise in counts, from pairs of darks, and their mean level.
+fn read_noise(darks: &[Array2<u16>]) -> Result<(f64, f64)> {
+    if darks.len() < 2 {
+        bail!("At least two darks are needed, {} given", darks.len());
+    }
+    let pairs = darks
+        .chunks_exact(2)
+        .map(|p| pairstats(&p[0], &p[1]))
+        .collect::<Vec<_>>();
+    let n = pairs.len() as f64;
+    let level = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let var = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    Ok((var.sqrt(), level))
+}
+
+/// Gain in electrons per count, from the photon transfer curve of pairs of
+/// flats, fitted through the origin once the read noise is removed.
+fn gain(flats: &[Array2<u16>], level: f64, read_noise: f64) -> Result<f64> {
+    if flats.len() < 2 {
+        bail!("At least two flats are needed, {} given", flats.len());
+    }
+    let (mut ss, mut sv) = (0.0, 0.0);
+    for pair in flats.chunks_exact(2) {
+        let (mean, var) = pairstats(&pair[0], &pair[1]);
+        let (signal, shot) = (mean - level, var - read_noise * read_noise);
+        debug!("Flat pair: signal {:.1}, shot variance {:.1}", signal, shot);
+        ss += signal * signal;
+        sv += signal * shot;
+    }
+    if sv <= 0.0 {
+        bail!("Flats have no shot noise above the read noise");
+    }
+    // Shot variance = signal / gain
+    Ok(ss / sv)
+}
+
+/// Frames in a folder, in name order.
+fn readdir(dir: &Path, trim: &Trim) -> Result<Vec<Array2<u16>>> {
+    let mut paths = fs::read_dir(dir)
+        .context(format!("Cannot list {:?}", dir))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.retain(|p| p.is_file() && !checksum::is_companion(p));
+    paths.sort();
+    debug!("Calibration frames in {:?}: {:?}", dir, paths);
+    paths.iter().map(|p| input::readframe(p, trim)).collect()
+}
+
+/// Estimate the noise parameters of a camera, printing them and writing
+/// them to the calibration file if given.
+pub fn calibrate(
+    darks: &Path,
+    flats: &Path,
+    trim: &Trim,
+    output: Option<&Path>,
+) -> Result<()> {
+    let darks = readdir(darks, trim)?;
+    let flats = readdir(flats, trim)?;
+    let (read_noise, level) = read_noise(&darks)?;
+    let gain = gain(&flats, level, read_noise)?;
+    info!(
+        "Read noise {:.3} counts, dark level {:.1}, gain {:.4} e/count",
+        read_noise, level, gain
+    );
+
+    let cal = NoiseConf {
+        gain,
+        read_noise,
+        calibration: None,
+    };
+    let text = toml::to_string(&cal)?;
+    match output {
+        Some(path) => {
+            fs::write(path, &text)
+                .context(format!("Cannot write calibration {:?}", path))?;
+            println!("Noise calibration written to {}", path.display());
+        }
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::{gain, read_noise};
+
+    #[test]
+    fn test_calibrate() {
+        let darks =
+            [array![[10u16, 12], [10, 12]], array![[12u16, 10], [12, 10]]];
+        let (rn, level) = read_noise(&darks).unwrap();
+        assert!((rn - 2f64.sqrt()).abs() < 1e-12);
+        assert_eq!(level, 11.0);
+
+        // 100 counts of signal above the dark, with a variance of 50
+        let flats = [
+            array![[121u16, 101], [121, 101]],
+            array![[111u16, 111], [111, 111]],
+        ];
+        let g = gain(&flats, level, 0.0).unwrap();
+        assert!((g - 2.0).abs() < 1e-12);
+        // Read noise is not shot noise
+        assert!(gain(&flats, level, rn).unwrap() > g);
+        assert!(gain(&flats[..1], level, rn).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Writing of output files.
+//!
+//! All processors write their outputs through a [`Writer`], which can
+//! optionally read every file back after writing it and compare it with what
+//! was meant to be written, to catch silent truncation by failing hardware.
+//! The writer also decides the names of processed outputs.
+//!
+//! Besides sis images for cam.py, float images can be written for the
+//! archive in the NumPy `.npy` format (version 1.0, little endian f32), which
+//! every analysis tool can read without any extra library.
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use log::debug;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::{input, transaction, usage, SisImg};
+
+/// Naming scheme of processed outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// Fixed names, overwritten at every shot (what cam.py expects)
+    #[default]
+    Fixed,
+    /// Local time with milliseconds, e.g. `20240511T140233.123-od.sis`
+    Time,
+}
+
+/// Suffix for the n-th collision: a, b, ..., z, aa, ab, ...
+fn seqsuffix(mut n: usize) -> String {
+    let mut out = vec![];
+    loop {
+        out.push(b'a' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    out.reverse();
+    String::from_utf8(out).unwrap()
+}
+
+/// Magic string of the npy format, followed by version 1.0
+const NPY_MAGIC: &[u8; 8] = b"\x93NUMPY\x01\x00";
+
+/// Encode a float image in the npy format.
+fn npy_bytes(img: &Array2<f32>) -> Vec<u8> {
+    let (h, w) = img.dim();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        h, w
+    );
+    // Magic, header length and header are padded to a multiple of 64 bytes
+    let unpadded = NPY_MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut out = Vec::with_capacity(unpadded + 64 + 4 * h * w);
+    out.extend_from_slice(NPY_MAGIC);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for x in img.iter() {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+    out
+}
+
+/// Writer of output files
+#[derive(Debug, Clone, Default)]
+pub struct Writer {
+    /// Read back and compare every written file
+    verify: bool,
+    /// Naming scheme of processed outputs
+    naming: Naming,
+    /// Record the provenance of processed images
+    provenance: bool,
+}
+
+impl Writer {
+    /// Create a writer, optionally verifying every write.
+    pub fn new(verify: bool, naming: Naming, provenance: bool) -> Writer {
+        Writer {
+            verify,
+            naming,
+            provenance,
+        }
+    }
+
+    /// Whether processors should record the provenance of their images.
+    pub fn provenance(&self) -> bool {
+        self.provenance
+    }
+
+    /// Path of a processed output in `dir`.
+    ///
+    /// With fixed naming this is just `fixed`; with time naming it is the
+    /// current time followed by `-<kind>`, with a `-a`, `-b`, ... suffix
+    /// before the kind if a file with the same name already exists.
+    pub fn outname(&self, dir: &Path, fixed: &str, kind: &str) -> PathBuf {
+        match self.naming {
+            Naming::Fixed => dir.join(fixed),
+            Naming::Time => {
+                let now = Local::now().format("%Y%m%dT%H%M%S%.3f");
+                let mut path = dir.join(format!("{}-{}", now, kind));
+                let mut n = 0;
+                while path.exists() {
+                    let name = format!("{}-{}-{}", now, seqsuffix(n), kind);
+                    path = dir.join(name);
+                    n += 1;
+                }
+                debug!("Time based output name {:?}", path);
+                path
+            }
+        }
+    }
+
+    /// First free versioned path `<stem>-vNNN.<ext>` in `dir`, so that
+    /// archived outputs are never overwritten.
+    pub fn versioned(&self, dir: &Path, stem: &str, ext: &str) -> PathBuf {
+        let mut n = 1;
+        loop {
+            let path = dir.join(format!("{}-v{:03}.{}", stem, n, ext));
+            if !path.exists() {
+                return path;
+            }
+            n += 1;
+        }
+    }
+
+    /// Flush the file to disk, so that the read-back does not only see what
+    /// is still in the write buffers.
+    fn sync(path: &Path) -> Result<()> {
+        File::open(path)?
+            .sync_all()
+            .context(format!("Cannot sync {:?} to disk", path))
+    }
+
+    /// Write a sis image.
+    pub fn sis(&self, img: &SisImg, path: &Path) -> Result<()> {
+        img.write(path.to_path_buf())?;
+        usage::written(path);
+        transaction::record(path);
+        if self.verify {
+            Writer::sync(path)?;
+            let back = SisImg::read(path)
+                .context(format!("Cannot read back {:?}", path))?;
+            if back != *img {
+                bail!("Read-back of {:?} differs from what was written", path);
+            }
+            debug!("Read-back of {:?} verified", path);
+        }
+        Ok(())
+    }
+
+    /// Write a float image in the npy format.
+    pub fn npy(&self, img: &Array2<f32>, path: &Path) -> Result<()> {
+        let bytes = npy_bytes(img);
+        fs::write(path, &bytes).context(format!("Cannot write {:?}", path))?;
+        usage::written(path);
+        transaction::record(path);
+        if self.verify {
+            Writer::sync(path)?;
+            let back = fs::read(path)
+                .context(format!("Cannot read back {:?}", path))?;
+            if back != bytes {
+                bail!("Read-back of {:?} differs from what was written", path);
+            }
+            debug!("Read-back of {:?} verified", path);
+        }
+        Ok(())
+    }
+
+    /// Copy an input file, decompressing it if needed.
+    pub fn copy(&self, src: &Path, dest: &Path) -> Result<()> {
+        let bytes = input::reread(src)?;
+        fs::write(dest, &bytes)
+            .context(format!("Cannot copy {:?} to {:?}", src, dest))?;
+        usage::written(dest);
+        transaction::record(dest);
+        if self.verify {
+            Writer::sync(dest)?;
+            let back = fs::read(dest)
+                .context(format!("Cannot read back {:?}", dest))?;
+            if back != bytes {
+                bail!(
+                    "Read-back of {:?} differs from what was written ({} \
+                     bytes instead of {})",
+                    dest,
+                    back.len(),
+                    bytes.len()
+                );
+            }
+            debug!("Read-back of {:?} verified", dest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{npy_bytes, seqsuffix};
+
+    #[test]
+    fn test_npy_bytes() {
+        let img =
+            Array2::from_shape_vec((2, 3), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.5])
+                .unwrap();
+        let bytes = npy_bytes(&img);
+        let hlen = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + hlen) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + hlen]).unwrap();
+        assert!(header.contains("'shape': (2, 3)"));
+        assert!(header.ends_with('\n'));
+        assert_eq!(bytes.len(), 10 + hlen + 4 * 6);
+        assert_eq!(&bytes[bytes.len() - 4..], &5.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_seqsuffix() {
+        assert_eq!(seqsuffix(0), "a");
+        assert_eq!(seqsuffix(25), "z");
+        assert_eq!(seqsuffix(26), "aa");
+        assert_eq!(seqsuffix(27), "ab");
+        assert_eq!(seqsuffix(26 + 26 * 26), "aaa");
+    }
+}
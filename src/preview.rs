@@ -0,0 +1,146 @@
+//! Coarse preview of each processed shot in the terminal.
+//!
+//! With `--preview` the main output of every shot is printed as unicode
+//! half blocks (`▀`), the upper pixel in the foreground color and the lower
+//! one in the background color, using 24 bit ANSI colors from the colormap.
+//! Each character cell holds two roughly square pixels, so the image keeps
+//! its aspect ratio. Good enough to align the MOT over SSH.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    colormap::{Colormap, ColormapConf},
+    SisImg,
+};
+
+fn default_width() -> usize {
+    64
+}
+
+/// Configuration of the terminal preview
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TerminalConf {
+    /// Width of the preview, in characters
+    #[serde(default = "default_width")]
+    pub width: usize,
+    /// Colormap of the preview, instead of the global one
+    #[serde(default)]
+    pub colormap: Option<ColormapConf>,
+}
+
+impl Default for TerminalConf {
+    fn default() -> Self {
+        TerminalConf {
+            width: default_width(),
+            colormap: None,
+        }
+    }
+}
+
+/// Terminal preview of the shots
+#[derive(Debug)]
+pub struct Preview {
+    width: usize,
+    colormap: Colormap,
+}
+
+/// Average of the image over the blocks of a `rows` x `cols` grid.
+fn resample(img: &Array2<f64>, rows: usize, cols: usize) -> Array2<f64> {
+    let (h, w) = img.dim();
+    Array2::from_shape_fn((rows, cols), |(r, c)| {
+        let (y0, x0) = (r * h / rows, c * w / cols);
+        let y1 = ((r + 1) * h / rows).max(y0 + 1);
+        let x1 = ((c + 1) * w / cols).max(x0 + 1);
+        let (mut sum, mut n) = (0.0, 0.0);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                if img[[y, x]].is_finite() {
+                    sum += img[[y, x]];
+                    n += 1.0;
+                }
+            }
+        }
+        if n > 0.0 {
+            sum / n
+        } else {
+            f64::NAN
+        }
+    })
+}
+
+/// Half block rendering of the image, `width` characters wide.
+fn render(img: &Array2<f64>, width: usize, colormap: &Colormap) -> String {
+    let (h, w) = img.dim();
+    let cols = width.clamp(1, w.max(1));
+    // Two pixels per character cell, vertically
+    let rows = (h * cols).div_ceil(w.max(1)).max(2).div_ceil(2) * 2;
+    let levels = colormap.levels(&resample(img, rows, cols));
+    let lut = colormap.lut();
+
+    let mut out = String::new();
+    for pair in levels.outer_iter().collect::<Vec<_>>().chunks(2) {
+        for (top, bottom) in pair[0].iter().zip(pair[1].iter()) {
+            let [r, g, b] = lut[*top as usize];
+            let [br, bg, bb] = lut[*bottom as usize];
+            out.push_str(&format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                r, g, b, br, bg, bb
+            ));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+impl Preview {
+    /// Preview with the global colormap unless the preview has its own.
+    pub fn new(
+        conf: &TerminalConf,
+        colormap: &ColormapConf,
+    ) -> Result<Preview> {
+        let colormap =
+            Colormap::new(conf.colormap.as_ref().unwrap_or(colormap))?;
+        Ok(Preview {
+            width: conf.width,
+            colormap,
+        })
+    }
+
+    /// Print the preview of a processed shot, from its main output.
+    pub fn shot(&self, watch: &str, shot: &str, output: &Path) -> Result<()> {
+        let img = SisImg::read(output)
+            .context(format!("Cannot read {:?} for the preview", output))?;
+        let (img, od) = img.values();
+        let max = img.fold(f64::NEG_INFINITY, |m, &x| m.max(x));
+        let unit = if od { "OD" } else { "counts" };
+        print!("{}", render(&img, self.width, &self.colormap));
+        println!("[{}] shot {}: max {:.3} {}", watch, shot, max, unit);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+
+    use super::{render, resample};
+    use crate::colormap::{Colormap, ColormapConf};
+
+    #[test]
+    fn test_render() {
+        let img = array![[0.0, 2.0, 4.0, 6.0], [f64::NAN, 2.0, 4.0, 6.0]];
+        assert_eq!(resample(&img, 1, 2), array![[4.0 / 3.0, 5.0]]);
+
+        let cmap = Colormap::new(&ColormapConf::default()).unwrap();
+        let img = Array2::from_shape_fn((20, 40), |(y, _)| y as f64);
+        let out = render(&img, 10, &cmap);
+        // 10 columns, 5 pixels high, rounded up to 6: 3 lines
+        assert_eq!(out.lines().count(), 3);
+        assert_eq!(out.matches('\u{2580}').count(), 30);
+        assert!(out.starts_with("\x1b[38;2;0;0;0m"));
+    }
+}
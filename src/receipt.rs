@@ -0,0 +1,146 @@
+//! Ordering of input files by the monotonic time their event was received.
+//!
+//! The clock of the NAS can be minutes ahead of (or behind) ours, so file
+//! modification times cannot be trusted to order files. Instead the time at
+//! which the watcher received the event of each file is recorded here, on
+//! the monotonic clock, and used wherever files must be ordered. The mtime
+//! of each received file can optionally be cross-checked against our clock,
+//! warning when the skew is above a threshold; the last measured skew is
+//! also used to correct the mtime comparisons that cannot be avoided.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Files received longer ago than this are forgotten
+const KEEP: Duration = Duration::from_secs(3600);
+/// Minimum time between two skew warnings
+const WARN_EVERY: Duration = Duration::from_secs(60);
+
+fn default_warn_s() -> f64 {
+    2.0
+}
+
+/// Configuration of the clock skew cross-check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkewConf {
+    /// Skew between file mtimes and our clock above which to warn, seconds
+    #[serde(default = "default_warn_s")]
+    pub warn_s: f64,
+}
+
+#[derive(Debug, Default)]
+struct Receipts {
+    received: HashMap<PathBuf, Instant>,
+    /// Last measured skew, file mtime minus our clock, in seconds
+    skew: Option<f64>,
+    last_warning: Option<Instant>,
+}
+
+static RECEIPTS: Mutex<Option<Receipts>> = Mutex::new(None);
+
+/// Skew of a file mtime with respect to our clock, in seconds.
+fn skew_of(path: &Path, now: SystemTime) -> Option<f64> {
+    let mtime = path.metadata().and_then(|m| m.modified()).ok()?;
+    Some(match mtime.duration_since(now) {
+        Ok(ahead) => ahead.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    })
+}
+
+/// Record the receipt of the event of some files, cross-checking their
+/// mtimes if configured.
+pub fn record(paths: &[PathBuf], at: Instant, conf: Option<&SkewConf>) {
+    let mut guard = RECEIPTS.lock().unwrap_or_else(|e| e.into_inner());
+    let rec = guard.get_or_insert_with(Receipts::default);
+    rec.received.retain(|_, t| t.elapsed() < KEEP);
+    for p in paths {
+        rec.received.insert(p.clone(), at);
+    }
+
+    let Some(conf) = conf else {
+        return;
+    };
+    let now = SystemTime::now();
+    let Some(skew) = paths.iter().rev().find_map(|p| skew_of(p, now)) else {
+        return;
+    };
+    rec.skew = Some(skew);
+    debug!("Clock skew of the input files: {:.3} s", skew);
+    let due = rec.last_warning.is_none_or(|t| t.elapsed() >= WARN_EVERY);
+    if skew.abs() > conf.warn_s && due {
+        warn!(
+            "File times are {:.1} s {} our clock, ordering by event receipt",
+            skew.abs(),
+            if skew > 0.0 { "ahead of" } else { "behind" }
+        );
+        rec.last_warning = Some(Instant::now());
+    }
+}
+
+/// Monotonic time at which the event of a file was received, if known.
+pub fn received(path: &Path) -> Option<Instant> {
+    let guard = RECEIPTS.lock().unwrap_or_else(|e| e.into_inner());
+    guard.as_ref()?.received.get(path).copied()
+}
+
+/// Time on the clock of the input files corresponding to one of ours,
+/// using the last measured skew.
+pub fn to_file_clock(t: SystemTime) -> SystemTime {
+    let guard = RECEIPTS.lock().unwrap_or_else(|e| e.into_inner());
+    match guard.as_ref().and_then(|r| r.skew) {
+        Some(s) if s >= 0.0 => t + Duration::from_secs_f64(s),
+        Some(s) => t - Duration::from_secs_f64(-s),
+        None => t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use super::{record, to_file_clock, SkewConf, RECEIPTS, WARN_EVERY};
+    use crate::testutil::TempDir;
+
+    fn last_warning() -> Option<Instant> {
+        let guard = RECEIPTS.lock().unwrap_or_else(|e| e.into_inner());
+        guard.as_ref()?.last_warning
+    }
+
+    #[test]
+    fn test_skew_warning() {
+        let dir = TempDir::new("receipt");
+        let path = dir.join("1-rawimg-0001.sis");
+        let ahead = SystemTime::now() + Duration::from_secs(30);
+        File::create(&path).unwrap().set_modified(ahead).unwrap();
+        let paths = [path];
+        let conf = SkewConf { warn_s: 2.0 };
+
+        record(&paths, Instant::now(), Some(&conf));
+        let first = last_warning();
+        assert!(first.is_some());
+        let now = SystemTime::now();
+        let skew = to_file_clock(now).duration_since(now).unwrap();
+        assert!(skew > Duration::from_secs(29));
+
+        // Warned at most once a minute
+        record(&paths, Instant::now(), Some(&conf));
+        assert_eq!(last_warning(), first);
+        {
+            let mut guard = RECEIPTS.lock().unwrap();
+            let rec = guard.as_mut().unwrap();
+            rec.last_warning = Instant::now().checked_sub(WARN_EVERY);
+        }
+        record(&paths, Instant::now(), Some(&conf));
+        assert!(last_warning() > first);
+    }
+}
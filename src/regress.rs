@@ -0,0 +1,234 @@
+//! Record-and-compare of the outputs of a replayed session.
+//!
+//! Before deploying a new build mid-campaign, a recorded session is replayed
+//! through the processor of a watch entry, and the SHA-256 of every output of
+//! every shot is recorded in a baseline CSV file (`version,shot,sha256,
+//! output`). Replaying the same session with another build and comparing
+//! against the baseline shows, shot by shot, which outputs changed.
+//! Outputs are written to a temporary folder with fixed names, and the
+//! correction factors are not applied, so that runs are reproducible.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    checksum, getproc, health,
+    output::{Naming, Writer},
+    shot,
+    textout::{self, Value},
+    Config,
+};
+
+/// Hashes of the outputs of each shot, by output file name
+type Hashes = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Replay the session through the processor of the watch entry.
+fn replay(conf: &Config, session: &Path, entry: &str) -> Result<Hashes> {
+    let mut wc = conf
+        .entries()
+        .into_iter()
+        .find(|w| w.name == entry)
+        .ok_or(anyhow!("No watch entry named {}", entry))?;
+    let tmp = std::env::temp_dir()
+        .join(format!("acqmidproc-regress-{}", std::process::id()));
+    fs::create_dir_all(&tmp)
+        .context(format!("Cannot create temporary folder {:?}", tmp))?;
+    wc.outpath = tmp.to_string_lossy().into_owned();
+    if wc.archive_root.is_some() {
+        let root = tmp.join("archive");
+        fs::create_dir_all(&root)?;
+        wc.archive_root = Some(root.to_string_lossy().into_owned());
+    }
+    let writer = Writer::new(false, Naming::Fixed, conf.provenance);
+    let proc = getproc(&wc, conf, &writer)?;
+
+    let shotre = Regex::new(&conf.shotid)
+        .context(format!("Invalid shot id regex {}", conf.shotid))?;
+    let mut shots: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let mut files = health::catchup(session, SystemTime::UNIX_EPOCH)?;
+    files.sort();
+    for f in files.into_iter().filter(|f| !checksum::is_companion(f)) {
+        match shot::shot_id(&shotre, std::slice::from_ref(&f)) {
+            Some(id) => shots.entry(id).or_default().push(f),
+            None => debug!("No shot id in {:?}, skipped", f),
+        }
+    }
+    info!("Replaying {} shots from {:?}", shots.len(), session);
+
+    let mut hashes = Hashes::new();
+    for (id, paths) in shots {
+        let outputs = match proc.proc(paths, 1.0) {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("Shot {} failed: {:?}", id, e);
+                vec![]
+            }
+        };
+        let shot = hashes.entry(id).or_default();
+        for o in outputs {
+            let data = fs::read(&o).context(format!("Cannot read {:?}", o))?;
+            let name = o
+                .file_name()
+                .map_or(String::new(), |n| n.to_string_lossy().into_owned());
+            shot.insert(name, format!("{:x}", Sha256::digest(&data)));
+        }
+    }
+
+    if let Err(e) = fs::remove_dir_all(&tmp) {
+        warn!("Cannot remove temporary folder {:?}: {}", tmp, e);
+    }
+    Ok(hashes)
+}
+
+/// Parse a baseline, returning its version and hashes.
+fn parse(text: &str) -> Result<(String, Hashes)> {
+    let mut version = String::new();
+    let mut hashes = Hashes::new();
+    for (n, line) in text.lines().enumerate().skip(1) {
+        let fields = line.splitn(4, ',').collect::<Vec<_>>();
+        let [ver, shot, hash, output] = fields[..] else {
+            bail!("Line {} of the baseline is invalid: {:?}", n + 1, line);
+        };
+        // Only the output can need quoting
+        let output = match output.strip_prefix('"') {
+            Some(o) => o.strip_suffix('"').unwrap_or(o).replace("\"\"", "\""),
+            None => String::from(output),
+        };
+        version = String::from(ver);
+        hashes
+            .entry(String::from(shot))
+            .or_default()
+            .insert(output, String::from(hash));
+    }
+    Ok((version, hashes))
+}
+
+/// Differences of a shot with respect to the baseline, if any.
+fn diff(
+    base: Option<&BTreeMap<String, String>>,
+    new: Option<&BTreeMap<String, String>>,
+) -> Option<String> {
+    let (base, new) = match (base, new) {
+        (Some(b), Some(n)) => (b, n),
+        (Some(_), None) => return Some(String::from("missing")),
+        (None, Some(_)) => return Some(String::from("not in baseline")),
+        (None, None) => return None,
+    };
+    let mut diffs = vec![];
+    for (name, hash) in base {
+        match new.get(name) {
+            Some(h) if h == hash => {}
+            Some(_) => diffs.push(format!("{} differs", name)),
+            None => diffs.push(format!("{} missing", name)),
+        }
+    }
+    for name in new.keys().filter(|n| !base.contains_key(*n)) {
+        diffs.push(format!("{} new", name));
+    }
+    (!diffs.is_empty()).then(|| diffs.join(", "))
+}
+
+/// Record the outputs of the replayed session in the baseline, or compare
+/// them with it.
+pub fn run(
+    conf: &Config,
+    session: &Path,
+    baseline: &Path,
+    entry: &str,
+    record: bool,
+) -> Result<()> {
+    let hashes = replay(conf, session, entry)?;
+
+    if record {
+        if baseline.exists() {
+            fs::remove_file(baseline)
+                .context(format!("Cannot replace baseline {:?}", baseline))?;
+        }
+        for (shot, outputs) in &hashes {
+            for (output, hash) in outputs {
+                let rec = vec![
+                    (
+                        String::from("version"),
+                        Value::Str(String::from(env!("CARGO_PKG_VERSION"))),
+                    ),
+                    (String::from("shot"), Value::Str(shot.clone())),
+                    (String::from("sha256"), Value::Str(hash.clone())),
+                    (String::from("output"), Value::Str(output.clone())),
+                ];
+                textout::append(baseline, &conf.format, &rec)?;
+            }
+        }
+        println!(
+            "Recorded {} shots of {:?} in {:?}",
+            hashes.len(),
+            session,
+            baseline
+        );
+        return Ok(());
+    }
+
+    let text = fs::read_to_string(baseline)
+        .context(format!("Cannot read baseline {:?}", baseline))?;
+    let (version, base) = parse(&text)?;
+    println!(
+        "Comparing {} shots with the baseline of version {}",
+        hashes.len(),
+        version
+    );
+    let mut shots = base.keys().chain(hashes.keys()).collect::<Vec<_>>();
+    shots.sort();
+    shots.dedup();
+    let mut changed = 0;
+    for shot in &shots {
+        if let Some(d) = diff(base.get(*shot), hashes.get(*shot)) {
+            println!("{:<12} {}", shot, d);
+            changed += 1;
+        }
+    }
+    if changed > 0 {
+        bail!(
+            "{} of {} shots differ from the baseline",
+            changed,
+            shots.len()
+        );
+    }
+    println!("All {} shots match the baseline", shots.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, parse};
+
+    #[test]
+    fn test_parse_diff() {
+        let text = "version,shot,sha256,output\n\
+                    0.1.0,12,aa,od.sis\n\
+                    0.1.0,12,bb,\"a,b.sis\"\n\
+                    0.1.0,13,cc,od.sis\n";
+        let (version, base) = parse(text).unwrap();
+        assert_eq!(version, "0.1.0");
+        assert_eq!(base["12"]["a,b.sis"], "bb");
+
+        let mut new = base.clone();
+        assert_eq!(diff(base.get("12"), new.get("12")), None);
+        new.get_mut("12")
+            .unwrap()
+            .insert("od.sis".into(), "ff".into());
+        assert_eq!(
+            diff(base.get("12"), new.get("12")).unwrap(),
+            "od.sis differs"
+        );
+        assert_eq!(diff(base.get("13"), None).unwrap(), "missing");
+        assert!(parse("header\nbad line\n").is_err());
+    }
+}
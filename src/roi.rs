@@ -0,0 +1,172 @@
+//! Region of interest of the OD images, optionally following the cloud.
+//!
+//! The summed OD and the centroid of the cloud are measured in the ROI of
+//! each shot. During long scans the cloud can drift across the sensor: with
+//! `follow` the ROI of the next shot is centered on the mean centroid of the
+//! last shots, as long as their summed OD is above `min_sum`, and its center
+//! is kept within `bounds`.
+
+use std::collections::VecDeque;
+
+use log::debug;
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+
+fn default_history() -> usize {
+    5
+}
+
+/// Configuration of the region of interest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoiConf {
+    /// Initial center, row and column of the stacked OD image
+    pub center: [usize; 2],
+    /// Height and width
+    pub size: [usize; 2],
+    /// Re-center on the centroid of the last shots
+    #[serde(default)]
+    pub follow: bool,
+    /// Number of shots whose centroids are averaged
+    #[serde(default = "default_history")]
+    pub history: usize,
+    /// Minimum summed OD of a shot for its centroid to be followed
+    #[serde(default)]
+    pub min_sum: f64,
+    /// Allowed range of the center: first row, last row, first column, last
+    /// column
+    #[serde(default)]
+    pub bounds: Option<[usize; 4]>,
+}
+
+/// What was measured in the ROI of a shot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measure {
+    /// First row and column of the ROI
+    pub origin: [usize; 2],
+    /// Height and width of the ROI, clipped to the image
+    pub size: [usize; 2],
+    /// Summed OD in the ROI
+    pub sum: f64,
+    /// Centroid of the positive OD, row and column, if there is any
+    pub centroid: Option<[f64; 2]>,
+}
+
+/// ROI following the cloud from shot to shot
+#[derive(Debug)]
+pub struct Tracker {
+    conf: RoiConf,
+    center: [usize; 2],
+    centroids: VecDeque<[f64; 2]>,
+}
+
+impl Tracker {
+    /// Start from the configured center.
+    pub fn new(conf: &RoiConf) -> Tracker {
+        Tracker {
+            conf: conf.clone(),
+            center: conf.center,
+            centroids: VecDeque::new(),
+        }
+    }
+
+    /// Measure the OD image in the current ROI, moving the ROI for the next
+    /// shot if following.
+    pub fn measure(&mut self, od: &Array2<f32>) -> Measure {
+        let (h, w) = od.dim();
+        let corner = |c: usize, size: usize, max: usize| {
+            let start = c.saturating_sub(size / 2).min(max.saturating_sub(1));
+            (start, (start + size).min(max))
+        };
+        let (y0, y1) = corner(self.center[0], self.conf.size[0], h);
+        let (x0, x1) = corner(self.center[1], self.conf.size[1], w);
+        let roi = od.slice(s![y0..y1, x0..x1]);
+
+        let (mut sum, mut pos, mut my, mut mx) = (0.0, 0.0, 0.0, 0.0);
+        for ((y, x), &v) in roi.indexed_iter() {
+            let v = f64::from(v);
+            if !v.is_finite() {
+                continue;
+            }
+            sum += v;
+            if v > 0.0 {
+                pos += v;
+                my += v * (y0 + y) as f64;
+                mx += v * (x0 + x) as f64;
+            }
+        }
+        let centroid = (pos > 0.0).then(|| [my / pos, mx / pos]);
+        let measure = Measure {
+            origin: [y0, x0],
+            size: [y1 - y0, x1 - x0],
+            sum,
+            centroid,
+        };
+
+        if let (true, Some(c)) = (self.conf.follow, centroid) {
+            if sum >= self.conf.min_sum {
+                self.centroids.push_back(c);
+                while self.centroids.len() > self.conf.history.max(1) {
+                    self.centroids.pop_front();
+                }
+                self.recenter();
+            } else {
+                debug!("Summed OD {} too low, ROI not moved", sum);
+            }
+        }
+        measure
+    }
+
+    /// Center on the mean of the last centroids, within bounds.
+    fn recenter(&mut self) {
+        let n = self.centroids.len() as f64;
+        let mean = |i: usize| {
+            let m = self.centroids.iter().map(|c| c[i]).sum::<f64>() / n;
+            m.round().max(0.0) as usize
+        };
+        let mut center = [mean(0), mean(1)];
+        if let Some([y0, y1, x0, x1]) = self.conf.bounds {
+            center = [center[0].clamp(y0, y1), center[1].clamp(x0, x1)];
+        }
+        if center != self.center {
+            debug!("ROI center moved from {:?} to {:?}", self.center, center);
+            self.center = center;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{RoiConf, Tracker};
+
+    #[test]
+    fn test_follow() {
+        let conf = RoiConf {
+            center: [4, 4],
+            size: [6, 6],
+            follow: true,
+            history: 2,
+            min_sum: 0.5,
+            bounds: Some([0, 20, 0, 6]),
+        };
+        let mut od = Array2::<f32>::zeros((20, 20));
+        od[[6, 6]] = 1.0;
+        let mut roi = Tracker::new(&conf);
+
+        let m = roi.measure(&od);
+        assert_eq!((m.origin, m.size), ([1, 1], [6, 6]));
+        assert_eq!((m.sum, m.centroid), (1.0, Some([6.0, 6.0])));
+        assert_eq!(roi.center, [6, 6]);
+
+        // The cloud walks on, the center follows the mean within bounds
+        od[[6, 6]] = 0.0;
+        od[[8, 8]] = 1.0;
+        roi.measure(&od);
+        assert_eq!(roi.center, [7, 6]);
+
+        // Empty shots do not move the ROI
+        roi.measure(&Array2::zeros((20, 20)));
+        assert_eq!(roi.center, [7, 6]);
+    }
+}
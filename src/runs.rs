@@ -0,0 +1,157 @@
+//! Detection of runs, with a snapshot of the configuration of each.
+//!
+//! A night of measurements is made of several runs. A run starts with the
+//! first shot after startup, after a pause longer than `gap_s`, or when the
+//! run flag file appears. At each run start the effective configuration,
+//! with the SHA-256 of the configuration and calibration files it depends
+//! on, is written as `run-<time>-config.toml` to the output folder of every
+//! watch entry, and the run is appended to the `runs.csv` index there, so
+//! that later analysis can tell which settings produced which run.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::textout::{self, NumFmt, Value};
+
+fn default_gap_s() -> f64 {
+    600.0
+}
+
+/// Configuration of the run detection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunsConf {
+    /// Pause between shots, in seconds, after which a new run starts
+    #[serde(default = "default_gap_s")]
+    pub gap_s: f64,
+    /// File whose appearance starts a new run
+    #[serde(default)]
+    pub runflag: Option<String>,
+}
+
+/// Run detector
+#[derive(Debug)]
+pub struct Runs {
+    gap: Duration,
+    runflag: Option<PathBuf>,
+    /// Effective configuration, as TOML
+    config: String,
+    /// Configuration and calibration files to hash
+    files: Vec<PathBuf>,
+    /// Output folders receiving the snapshots
+    dirs: Vec<PathBuf>,
+    format: NumFmt,
+    last: Option<Instant>,
+    flag: bool,
+    /// The run was aborted, the next shot starts a new one
+    aborted: bool,
+}
+
+/// SHA-256 of a file, or why it cannot be computed.
+fn hash(path: &Path) -> String {
+    match fs::read(path) {
+        Ok(data) => format!("{:x}", Sha256::digest(&data)),
+        Err(e) => format!("unreadable ({})", e),
+    }
+}
+
+impl Runs {
+    /// Start detecting runs.
+    pub fn new(
+        conf: &RunsConf,
+        config: String,
+        files: Vec<PathBuf>,
+        dirs: Vec<PathBuf>,
+        format: NumFmt,
+    ) -> Runs {
+        let runflag = conf.runflag.as_ref().map(PathBuf::from);
+        let flag = runflag.as_ref().is_some_and(|f| f.exists());
+        Runs {
+            gap: Duration::from_secs_f64(conf.gap_s),
+            runflag,
+            config,
+            files,
+            dirs,
+            format,
+            last: None,
+            flag,
+            aborted: false,
+        }
+    }
+
+    /// Whether the run flag appeared since the last check.
+    fn flag_raised(&mut self) -> bool {
+        let Some(f) = &self.runflag else {
+            return false;
+        };
+        let flag = f.exists();
+        let raised = flag && !self.flag;
+        self.flag = flag;
+        raised
+    }
+
+    /// Check for the start of a run, before a shot is handled, returning
+    /// whether one starts.
+    pub fn shot(&mut self) -> bool {
+        let raised = self.flag_raised();
+        let pause = self.last.map(|t| t.elapsed() > self.gap);
+        self.last = Some(Instant::now());
+        let aborted = std::mem::take(&mut self.aborted);
+        let why = match (pause, raised) {
+            _ if aborted => "previous run aborted",
+            (None, _) => "first shot",
+            (_, true) => "run flag raised",
+            (Some(true), _) => "pause between shots",
+            (Some(false), false) => return false,
+        };
+        info!("New run ({})", why);
+        if let Err(e) = self.snapshot() {
+            warn!("Cannot snapshot the configuration of the run: {:?}", e);
+        }
+        true
+    }
+
+    /// Abort the current run, the next shot starts a new one.
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Write the snapshot of the configuration in every output folder.
+    fn snapshot(&self) -> Result<()> {
+        let now = SystemTime::now();
+        let id = DateTime::<Local>::from(now).format("%Y%m%dT%H%M%S");
+        let mut text = String::from("# Configuration of the run\n");
+        for f in &self.files {
+            text.push_str(&format!("# sha256 {} {}\n", hash(f), f.display()));
+        }
+        text.push('\n');
+        text.push_str(&self.config);
+        let config_hash = format!("{:x}", Sha256::digest(text.as_bytes()));
+
+        for dir in &self.dirs {
+            let path = dir.join(format!("run-{}-config.toml", id));
+            fs::write(&path, &text)
+                .context(format!("Cannot write snapshot {:?}", path))?;
+            let rec = vec![
+                (String::from("time"), Value::Time(now)),
+                (String::from("run"), Value::Str(id.to_string())),
+                (
+                    String::from("config"),
+                    Value::Str(path.display().to_string()),
+                ),
+                (String::from("sha256"), Value::Str(config_hash.clone())),
+            ];
+            textout::append(&dir.join("runs.csv"), &self.format, &rec)?;
+            debug!("Configuration of run {} written to {:?}", id, path);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Shot identification helpers.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use log::{debug, info};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::receipt;
+
+/// What to do when several files match the pattern of a frame.
+///
+/// The default changed twice, see [`crate::history`]: the first file listed
+/// was taken before revision 9, the newest one before revision 11.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiMatch {
+    /// Refuse to process the shot
+    Error,
+    /// Take the file modified last, trusting the file timestamps
+    Newest,
+    /// Take the file whose event was received last
+    #[default]
+    Received,
+    /// Take the file with the highest shot id
+    HighestShot,
+}
+
+/// Default regex extracting the shot id from a raw frame file name.
+pub fn default_shotid() -> String {
+    String::from(r"(?P<shot>\d+)-rawimg")
+}
+
+/// Find the shot id of a set of paths.
+///
+/// The regex is matched against the file names, and the `shot` group of the
+/// first match is returned.
+pub fn shot_id(re: &Regex, paths: &[PathBuf]) -> Option<String> {
+    let id = paths
+        .iter()
+        .filter_map(|p| p.file_name())
+        .filter_map(|f| {
+            re.captures(&f.to_string_lossy())
+                .and_then(|c| c.name("shot").map(|m| m.as_str().to_string()))
+        })
+        .next();
+    debug!("Shot id of {:?}: {:?}", paths, id);
+    id
+}
+
+/// Shot id in a canonical form, for lookups: numeric ids without their
+/// leading zeros, e.g. `0042` as `42`, the others as they are.
+pub fn canonical(id: &str) -> String {
+    match id.trim().parse::<u64>() {
+        Ok(n) => n.to_string(),
+        Err(_) => String::from(id.trim()),
+    }
+}
+
+/// Shot id of a single path, compared numerically when possible.
+fn shot_key(re: &Regex, path: &Path) -> Option<(Option<u64>, String)> {
+    let id = shot_id(re, &[path.to_path_buf()])?;
+    Some((id.parse().ok(), id))
+}
+
+/// Split files into one group per shot, oldest shot first. Files without a
+/// shot id come last, together.
+pub fn group(re: &Regex, paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut shots: BTreeMap<_, Vec<PathBuf>> = BTreeMap::new();
+    let mut rest = vec![];
+    for p in paths {
+        match shot_key(re, &p) {
+            // Numeric ids first, in order, then the others by name
+            Some((num, id)) => {
+                shots.entry((num.is_none(), num, id)).or_default().push(p)
+            }
+            None => rest.push(p),
+        }
+    }
+    let mut groups = shots.into_values().collect::<Vec<_>>();
+    if !rest.is_empty() {
+        groups.push(rest);
+    }
+    groups
+}
+
+impl MultiMatch {
+    /// Choose one of the files matching the pattern of a frame.
+    pub fn pick(
+        &self,
+        re: &Regex,
+        pattern: &str,
+        matches: &[&PathBuf],
+    ) -> Result<PathBuf> {
+        let chosen = match (matches, self) {
+            ([], _) => bail!("Cannot find pattern {}", pattern),
+            ([one], _) => return Ok(one.to_path_buf()),
+            (_, MultiMatch::Error) => {
+                bail!("Several files match pattern {}: {:?}", pattern, matches)
+            }
+            (_, MultiMatch::Newest) => {
+                let mtime = |p: &&&PathBuf| {
+                    p.metadata()
+                        .and_then(|m| m.modified())
+                        .unwrap_or(SystemTime::UNIX_EPOCH)
+                };
+                matches.iter().max_by_key(mtime)
+            }
+            (_, MultiMatch::Received) => {
+                // Files never received (e.g. from before the start) come first
+                matches.iter().max_by_key(|p| receipt::received(p))
+            }
+            (_, MultiMatch::HighestShot) => {
+                matches.iter().max_by_key(|p| shot_key(re, p))
+            }
+        };
+        let chosen = chosen.unwrap().to_path_buf();
+        info!(
+            "Several files match pattern {}: {:?}, chose {:?} ({:?})",
+            pattern, matches, chosen, self
+        );
+        Ok(chosen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Instant};
+
+    use regex::Regex;
+
+    use super::{default_shotid, MultiMatch};
+    use crate::receipt;
+
+    #[test]
+    fn test_pick_highest_shot() {
+        let re = Regex::new(&default_shotid()).unwrap();
+        let a = PathBuf::from("9-rawimg-0001.sis");
+        let b = PathBuf::from("10-rawimg-0001.sis");
+        let pick = |m: MultiMatch| m.pick(&re, "rawimg-0001", &[&a, &b]);
+        assert_eq!(pick(MultiMatch::HighestShot).unwrap(), b);
+        assert!(pick(MultiMatch::Error).is_err());
+        assert_eq!(MultiMatch::Error.pick(&re, "x", &[&a]).unwrap(), a);
+        assert!(MultiMatch::Newest.pick(&re, "x", &[]).is_err());
+    }
+
+    #[test]
+    fn test_pick_received() {
+        let re = Regex::new(&default_shotid()).unwrap();
+        let a = PathBuf::from("11-rawimg-0001.sis");
+        let b = PathBuf::from("12-rawimg-0001.sis");
+        let c = PathBuf::from("13-rawimg-0001.sis");
+        let start = Instant::now();
+        receipt::record(std::slice::from_ref(&b), start, None);
+        receipt::record(std::slice::from_ref(&a), Instant::now(), None);
+        let pick = MultiMatch::Received.pick(&re, "rawimg-0001", &[&a, &b, &c]);
+        assert_eq!(pick.unwrap(), a);
+    }
+}
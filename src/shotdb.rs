@@ -0,0 +1,283 @@
+//! Index of the published shots, and its audit against the output folders.
+//!
+//! With `shotdb = true` every published shot appends one row per file it
+//! wrote (outputs, routed outputs, metadata) to `shots.csv` in the output
+//! folder of its watch entry: `time,shot,output`, the output relative to the
+//! folder. This is our shot database. At startup the index is checked
+//! against the files actually there, to catch crashes between writing and
+//! indexing and files deleted by hand: rows whose file is gone, and output
+//! files (`.sis`, `.npy`, `.json`) no row refers to, are reported. Only
+//! files modified after the first row of the index can be unindexed: older
+//! ones were written before the index existed (like the fixed-name file
+//! cam.py reads, from before `shotdb` was enabled) and are left alone. With
+//! `--repair` the rows of missing files are dropped from the index and the
+//! unindexed files are moved to `orphans/`, keeping their relative path.
+//! With fixed naming only the last shot of each output has its file.
+//! When a transactional shot is rolled back, its rows are removed too.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use log::{debug, info, warn};
+
+use crate::{
+    health,
+    textout::{self, NumFmt, Value},
+};
+
+/// Name of the index in each output folder
+pub const INDEX: &str = "shots.csv";
+/// Folder of the files moved away by the repair
+const ORPHANS: &str = "orphans";
+/// Folders of the output tree that are not shot outputs
+const SKIPPED: [&str; 2] = [ORPHANS, "debug"];
+/// Extensions of the indexed outputs
+const EXTENSIONS: [&str; 3] = ["sis", "npy", "json"];
+
+/// Append the files of a published shot to the index of its output folder.
+pub fn record(
+    dir: &Path,
+    shot: &str,
+    files: &[PathBuf],
+    fmt: &NumFmt,
+) -> Result<()> {
+    let now = SystemTime::now();
+    for f in files {
+        let rel = f.strip_prefix(dir).unwrap_or(f);
+        let rec = vec![
+            (String::from("time"), Value::Time(now)),
+            (String::from("shot"), Value::Str(String::from(shot))),
+            (
+                String::from("output"),
+                Value::Str(rel.display().to_string()),
+            ),
+        ];
+        textout::append(&dir.join(INDEX), fmt, &rec)?;
+    }
+    Ok(())
+}
+
+/// Length of the index of an output folder, to roll back to; None if
+/// there is no index yet.
+pub fn mark(dir: &Path) -> Option<u64> {
+    dir.join(INDEX).metadata().ok().map(|m| m.len())
+}
+
+/// Remove the rows appended to the index since the mark.
+pub fn rollback(dir: &Path, mark: Option<u64>) -> Result<()> {
+    let index = dir.join(INDEX);
+    match mark {
+        Some(len) => OpenOptions::new()
+            .write(true)
+            .open(&index)
+            .and_then(|f| f.set_len(len)),
+        None if index.exists() => fs::remove_file(&index),
+        None => Ok(()),
+    }
+    .context(format!("Cannot roll back {:?}", index))?;
+    debug!("Shot index {:?} rolled back to {:?} bytes", index, mark);
+    Ok(())
+}
+
+/// Rows of an index, as (line, shot, output); the output is the last field,
+/// the only one that can be quoted.
+fn parse(text: &str) -> Vec<(String, String, String)> {
+    let mut rows = vec![];
+    for line in text.lines().skip(1) {
+        let fields = line.splitn(3, ',').collect::<Vec<_>>();
+        let [_, shot, output] = fields[..] else {
+            warn!("Invalid shot index line {:?}, ignored", line);
+            continue;
+        };
+        let output = match output.strip_prefix('"') {
+            Some(o) => o.strip_suffix('"').unwrap_or(o).replace("\"\"", "\""),
+            None => String::from(output),
+        };
+        rows.push((String::from(line), String::from(shot), output));
+    }
+    rows
+}
+
+/// Time of the first row of an index, when the index was created.
+fn created(text: &str) -> Option<SystemTime> {
+    let first = text.lines().nth(1)?;
+    let time = first.split(',').next()?;
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(SystemTime::from)
+}
+
+/// Whether a file was modified before the time, so before the index.
+fn predates(path: &Path, time: SystemTime) -> bool {
+    path.metadata()
+        .and_then(|m| m.modified())
+        .is_ok_and(|m| m < time)
+}
+
+/// Content of the index of an output folder, empty if there is none.
+fn read(index: &Path) -> Result<String> {
+    match fs::read_to_string(index) {
+        Ok(t) => Ok(t),
+        Err(_) if !index.exists() => Ok(String::new()),
+        Err(e) => Err(e).context(format!("Cannot read {:?}", index)),
+    }
+}
+
+/// Shots in the index of an output folder.
+pub fn shots(dir: &Path) -> Result<BTreeSet<String>> {
+    let text = read(&dir.join(INDEX))?;
+    Ok(parse(&text).into_iter().map(|(_, shot, _)| shot).collect())
+}
+
+/// Output files of the folder, relative to it.
+fn outputs(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let files = health::catchup(dir, SystemTime::UNIX_EPOCH)?;
+    Ok(files
+        .into_iter()
+        .filter_map(|f| f.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .filter(|f| {
+            f.extension()
+                .is_some_and(|e| EXTENSIONS.iter().any(|x| e == *x))
+        })
+        .filter(|f| {
+            !f.components()
+                .next()
+                .is_some_and(|c| SKIPPED.iter().any(|s| c.as_os_str() == *s))
+        })
+        .collect())
+}
+
+/// Check the index of an output folder against its files, repairing it if
+/// asked. Returns the number of discrepancies found.
+pub fn audit(dir: &Path, repair: bool) -> Result<usize> {
+    let index = dir.join(INDEX);
+    let text = read(&index)?;
+    let rows = parse(&text);
+    let files = outputs(dir)?;
+
+    // Last shot of each indexed output
+    let indexed = rows
+        .iter()
+        .map(|(_, shot, out)| (PathBuf::from(out), shot))
+        .collect::<BTreeMap<_, _>>();
+    let missing = indexed
+        .iter()
+        .filter(|(out, _)| out.is_relative() && !files.contains(*out))
+        .collect::<Vec<_>>();
+    // Without an index every file predates it
+    let since = created(&text);
+    let (older, orphans): (Vec<_>, Vec<_>) = files
+        .iter()
+        .filter(|f| !indexed.contains_key(*f))
+        .partition(|f| since.is_none_or(|t| predates(&dir.join(f), t)));
+    if !older.is_empty() {
+        debug!("{} files predate the shot index, not audited", older.len());
+    }
+    for (out, shot) in &missing {
+        warn!("Shot {} indexed with {:?}, which is missing", shot, out);
+    }
+    for f in &orphans {
+        warn!("{:?} is not in the shot index", f);
+    }
+    let found = missing.len() + orphans.len();
+    if found == 0 {
+        info!("Shot index of {:?} consistent with its files", dir);
+    }
+    if !repair || found == 0 {
+        return Ok(found);
+    }
+
+    let mut kept = text.lines().take(1).map(String::from).collect::<Vec<_>>();
+    kept.extend(
+        rows.iter()
+            .filter(|(_, _, out)| !missing.iter().any(|(m, _)| **m == *out))
+            .map(|(line, _, _)| line.clone()),
+    );
+    let tmp = dir.join(format!("{}.repair", INDEX));
+    fs::write(&tmp, kept.join("\n") + "\n")
+        .and_then(|_| fs::rename(&tmp, &index))
+        .context(format!("Cannot rewrite {:?}", index))?;
+    for f in &orphans {
+        let dest = dir.join(ORPHANS).join(f);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(dir.join(f), &dest)
+            .context(format!("Cannot move {:?} to {:?}", f, dest))?;
+        debug!("Moved {:?} to {:?}", f, dest);
+    }
+    info!(
+        "Shot index of {:?} repaired: {} rows dropped, {} files moved to {}",
+        dir,
+        rows.len() + 1 - kept.len(),
+        orphans.len(),
+        ORPHANS
+    );
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{audit, mark, record, rollback, INDEX};
+    use crate::{testutil::TempDir, textout::NumFmt};
+
+    #[test]
+    fn test_audit() {
+        let dir = TempDir::new("shotdb");
+        fs::create_dir_all(dir.join("bad")).unwrap();
+        let fmt = NumFmt::default();
+        // Written before the index, like the fixed-name output of cam.py
+        let old = dir.join("od.sis");
+        fs::write(&old, "x").unwrap();
+        let day = SystemTime::now() - Duration::from_secs(86400);
+        File::options()
+            .write(true)
+            .open(&old)
+            .and_then(|f| f.set_modified(day))
+            .unwrap();
+        assert_eq!(audit(&dir, true).unwrap(), 0);
+
+        let (a, b) = (dir.join("1-od.sis"), dir.join("bad/2-od.sis"));
+        for p in [&a, &b] {
+            fs::write(p, "x").unwrap();
+        }
+        record(&dir, "1", std::slice::from_ref(&a), &fmt).unwrap();
+        record(&dir, "2", std::slice::from_ref(&b), &fmt).unwrap();
+        assert_eq!(audit(&dir, false).unwrap(), 0);
+
+        // Deleted by hand, and written without being indexed
+        fs::remove_file(&b).unwrap();
+        fs::write(dir.join("3-od.sis"), "x").unwrap();
+        fs::write(dir.join("notes.txt"), "x").unwrap();
+        assert_eq!(audit(&dir, true).unwrap(), 2);
+        assert!(dir.join("orphans/3-od.sis").exists());
+        assert!(old.exists());
+        let index = fs::read_to_string(dir.join(INDEX)).unwrap();
+        assert_eq!(index.lines().count(), 2);
+        let gone = PathBuf::from("bad/2-od.sis").display().to_string();
+        assert!(!index.contains(&gone));
+        assert_eq!(audit(&dir, false).unwrap(), 0);
+
+        // A shot rolled back leaves neither files nor rows
+        let before = mark(&dir);
+        let c = dir.join("4-od.sis");
+        fs::write(&c, "x").unwrap();
+        record(&dir, "4", std::slice::from_ref(&c), &fmt).unwrap();
+        fs::remove_file(&c).unwrap();
+        rollback(&dir, before).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(INDEX)).unwrap(), index);
+        assert_eq!(audit(&dir, false).unwrap(), 0);
+    }
+}
@@ -0,0 +1,361 @@
+//! Soak test of the daemon, our qualification before a deployment.
+//!
+//! The daemon is started as a child process on temporary input and output
+//! folders, with the fkspecies processor, the configuration in
+//! `conf/default.toml` and the `--set` overrides given to the soak command,
+//! and is fed synthetic shots of the fkspecies frame mapping for hours. A
+//! fraction of the shots carries a fault: a truncated frame, a frame
+//! arriving with the next shot, an unreadable frame, or an output folder
+//! that cannot be written to (standing in for a full disk or an exhausted
+//! quota). The test fails as soon as the daemon exits, or at the end if its
+//! resident memory grew by more than `max_growth_mb` since the warm-up
+//! shots, or if it processed none of the shots, as counted in its shot
+//! index. Memory is read from `/proc`, so it is only checked on Linux.
+//! Input frames of old shots are removed as the test goes, so that it can
+//! run unattended.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use log::{debug, info, warn};
+
+use crate::{bench, shotdb, Config, SisImg};
+
+/// Shots before the memory baseline is taken
+const WARMUP_SHOTS: u64 = 20;
+/// Shots whose input frames are kept
+const KEEP_SHOTS: usize = 20;
+/// How long paths stay locked out, longer than the debouncer delay
+const LOCKOUT: Duration = Duration::from_secs(5);
+
+/// Arguments of the soak test
+#[derive(Debug, Clone, Args)]
+pub struct SoakArgs {
+    /// Duration of the test, in hours
+    #[arg(long, default_value_t = 4.0)]
+    pub hours: f64,
+
+    /// Seconds between shots
+    #[arg(long, default_value_t = 1.0)]
+    pub interval_s: f64,
+
+    /// Fraction of the shots with an injected fault
+    #[arg(long, default_value_t = 0.1)]
+    pub fault_rate: f64,
+
+    /// Largest accepted growth of the daemon memory, in MB
+    #[arg(long, default_value_t = 50.0)]
+    pub max_growth_mb: f64,
+
+    /// Height and width of the synthetic frames
+    #[arg(long, default_value_t = 256)]
+    pub size: usize,
+
+    /// Seed of the fault injection
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+}
+
+/// Fault injected into a shot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Fault {
+    /// One frame cut to half its size
+    Truncated,
+    /// One frame written only with the next shot
+    Delayed,
+    /// One frame that cannot be read
+    Unreadable,
+    /// Output folder that cannot be written to
+    DiskFull,
+}
+
+const FAULTS: [Fault; 4] = [
+    Fault::Truncated,
+    Fault::Delayed,
+    Fault::Unreadable,
+    Fault::DiskFull,
+];
+
+/// Small LCG, faults only have to be reproducible from the seed
+struct Lcg(u64);
+
+impl Lcg {
+    /// Uniform number in [0, 1).
+    fn next(&mut self) -> f64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Fault of the next shot, if any.
+    fn fault(&mut self, rate: f64) -> Option<Fault> {
+        let (hit, pick) = (self.next(), self.next());
+        (hit < rate).then(|| FAULTS[(pick * FAULTS.len() as f64) as usize])
+    }
+}
+
+/// Resident memory in kB, from the content of `/proc/<pid>/status`.
+fn vmrss(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Resident memory of a process in MB, where it can be known.
+fn rss_mb(pid: u32) -> Option<f64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    vmrss(&status).map(|kb| kb as f64 / 1024.0)
+}
+
+/// Make a path unreadable, or writable again.
+#[cfg(unix)]
+fn lockout(path: &Path, locked: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = match (locked, path.is_dir()) {
+        (true, true) => 0o555,
+        (true, false) => 0o000,
+        (false, true) => 0o755,
+        (false, false) => 0o644,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .context(format!("Cannot change permissions of {:?}", path))
+}
+
+#[cfg(not(unix))]
+fn lockout(path: &Path, locked: bool) -> Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(locked);
+    fs::set_permissions(path, perms)
+        .context(format!("Cannot change permissions of {:?}", path))
+}
+
+/// Daemon under test, killed when dropped
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Run the soak test for `hours`, one shot every `interval_s` seconds,
+/// passing the `--set` overrides on to the daemon.
+pub fn run(conf: &Config, overrides: &[String], args: &SoakArgs) -> Result<()> {
+    let SoakArgs {
+        hours,
+        interval_s,
+        fault_rate,
+        max_growth_mb,
+        size,
+        seed,
+    } = args.clone();
+    if hours <= 0.0 || interval_s <= 0.0 || size < 2 {
+        bail!("Soak test needs hours > 0, interval_s > 0 and size >= 2");
+    }
+    let frames = &conf.processors.fkspecies.frames;
+    let imgs = bench::shot(frames, size, size)
+        .into_iter()
+        .map(SisImg::new)
+        .collect::<Result<Vec<_>>>()?;
+
+    let tmp = std::env::temp_dir()
+        .join(format!("acqmidproc-soak-{}", std::process::id()));
+    let (inpath, outpath) = (tmp.join("in"), tmp.join("out"));
+    fs::create_dir_all(&inpath)
+        .and_then(|_| fs::create_dir_all(&outpath))
+        .context(format!("Cannot create soak folders in {:?}", tmp))?;
+    let logpath = tmp.join("daemon.log");
+    let log = File::create(&logpath)?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("--inpath")
+        .arg(&inpath)
+        .arg("--outpath")
+        .arg(&outpath)
+        .args(["--proc", "fkspecies"]);
+    for o in overrides {
+        command.arg("--set").arg(o);
+    }
+    // The index tells how many shots were processed
+    let child = command
+        .args(["--set", "shotdb=true"])
+        .stdout(Stdio::null())
+        .stderr(log)
+        .spawn()
+        .context("Cannot start the daemon")?;
+    let mut daemon = Daemon(child);
+    let pid = daemon.0.id();
+    println!(
+        "Soaking daemon {} for {} h, logging to {:?}",
+        pid, hours, logpath
+    );
+
+    let mut rng = Lcg(seed);
+    let mut counts: BTreeMap<Fault, u64> = BTreeMap::new();
+    let mut delayed: Option<(PathBuf, usize)> = None;
+    let mut written: VecDeque<Vec<PathBuf>> = VecDeque::new();
+    let mut locked: Vec<(Instant, PathBuf)> = vec![];
+    let (mut baseline, mut last) = (None, None);
+    let interval = Duration::from_secs_f64(interval_s);
+    let end = Instant::now() + Duration::from_secs_f64(hours * 3600.0);
+
+    let mut shot = 0u64;
+    while Instant::now() < end {
+        let tick = Instant::now();
+        if let Some(status) = daemon.0.try_wait()? {
+            bail!(
+                "Daemon exited with {} after {} shots, see {:?}",
+                status,
+                shot,
+                logpath
+            );
+        }
+        let (due, still): (Vec<_>, Vec<_>) =
+            locked.into_iter().partition(|(t, _)| *t <= tick);
+        locked = still;
+        // Frames of old shots may be gone already
+        for (_, p) in due.iter().filter(|(_, p)| p.exists()) {
+            lockout(p, false)?;
+        }
+        if let Some((path, idx)) = delayed.take() {
+            imgs[idx].write(path)?;
+        }
+
+        shot += 1;
+        let fault = rng.fault(fault_rate);
+        let victim = (rng.next() * imgs.len() as f64) as usize;
+        let mut paths = vec![];
+        for (idx, (img, f)) in imgs.iter().zip(frames).enumerate() {
+            let path = inpath.join(format!("{:08}-{}.sis", shot, f.pattern));
+            paths.push(path.clone());
+            match fault {
+                Some(Fault::Delayed) if idx == victim => {
+                    delayed = Some((path, idx));
+                    continue;
+                }
+                _ => img.write(path.clone())?,
+            }
+            if idx != victim {
+                continue;
+            }
+            match fault {
+                Some(Fault::Truncated) => {
+                    let file = OpenOptions::new().write(true).open(&path)?;
+                    file.set_len(file.metadata()?.len() / 2)?;
+                }
+                Some(Fault::Unreadable) => {
+                    lockout(&path, true)?;
+                    locked.push((tick + LOCKOUT, path));
+                }
+                _ => {}
+            }
+        }
+        if fault == Some(Fault::DiskFull) {
+            lockout(&outpath, true)?;
+            locked.push((tick + LOCKOUT, outpath.clone()));
+        }
+        if let Some(f) = fault {
+            debug!("Shot {}: {:?}", shot, f);
+            *counts.entry(f).or_default() += 1;
+        }
+
+        written.push_back(paths);
+        while written.len() > KEEP_SHOTS {
+            for p in written.pop_front().unwrap_or_default() {
+                let _ = lockout(&p, false);
+                let _ = fs::remove_file(&p);
+            }
+        }
+
+        last = rss_mb(pid);
+        if shot == WARMUP_SHOTS {
+            baseline = last;
+            info!("Memory after warm-up: {:?} MB", baseline);
+        }
+        if shot.is_multiple_of(1000) {
+            info!("{} shots, memory {:?} MB", shot, last);
+        }
+        thread::sleep(interval.saturating_sub(tick.elapsed()));
+    }
+
+    for (_, p) in locked.iter().filter(|(_, p)| p.exists()) {
+        lockout(p, false)?;
+    }
+    if let Some(status) = daemon.0.try_wait()? {
+        bail!(
+            "Daemon exited with {} at the end, see {:?}",
+            status,
+            logpath
+        );
+    }
+    drop(daemon);
+
+    let processed = shotdb::shots(&outpath)?.len();
+    println!(
+        "{} shots, {} processed, faults injected: {:?}",
+        shot, processed, counts
+    );
+    if processed == 0 {
+        bail!("Daemon processed none of the shots, see {:?}", logpath);
+    }
+    let growth = match (baseline, last) {
+        (Some(b), Some(l)) => {
+            println!(
+                "Memory: {:.1} MB after warm-up, {:.1} MB at the end",
+                b, l
+            );
+            l - b
+        }
+        _ => {
+            warn!("Memory of the daemon unknown, only crashes were checked");
+            0.0
+        }
+    };
+    if growth > max_growth_mb {
+        bail!(
+            "Daemon memory grew by {:.1} MB, more than {} MB, see {:?}",
+            growth,
+            max_growth_mb,
+            logpath
+        );
+    }
+    if let Err(e) = fs::remove_dir_all(&tmp) {
+        warn!("Cannot remove soak folder {:?}: {}", tmp, e);
+    }
+    println!("Soak test passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{vmrss, Lcg};
+
+    #[test]
+    fn test_soak() {
+        let status = "Name:\tacqmidproc\nVmPeak:\t  20000 kB\n\
+                      VmRSS:\t   12345 kB\nThreads:\t3\n";
+        assert_eq!(vmrss(status), Some(12345));
+        assert_eq!(vmrss("Name:\tacqmidproc\n"), None);
+
+        // Reproducible, and roughly at the requested rate
+        let faults = |seed| {
+            let mut rng = Lcg(seed);
+            (0..1000).map(|_| rng.fault(0.1)).collect::<Vec<_>>()
+        };
+        assert_eq!(faults(7), faults(7));
+        let hits = faults(7).iter().flatten().count();
+        assert!((50..150).contains(&hits), "{} faults", hits);
+    }
+}
@@ -0,0 +1,261 @@
+//! Source receiving frames over TCP, straight from acquire.py.
+//!
+//! Without the NAS, the debouncer and the wait for complete files, a shot
+//! reaches cam.py tens of milliseconds after acquisition. Each frame is sent
+//! as a 16 byte little endian header (magic `AQMF`, u32 shot number, u16
+//! frame number starting at 1, u16 number of frames of the shot, u16 height,
+//! u16 width) followed by the u16 pixels, and acknowledged with a single
+//! byte, `K` if accepted or `E` if not. Frames are written to the spool
+//! folder, named like acquire.py raw frames, since processors read files:
+//! with a spool on tmpfs this costs next to nothing. When all the frames of a
+//! shot are in, their paths are sent to the event queue as if the watcher had
+//! seen them being created. With `keep_shots` only the last shots are kept in
+//! the spool, otherwise every received frame is persisted there.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error, info, warn};
+use ndarray::Array2;
+use notify_debouncer_full::DebounceEventResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{events, SisImg};
+
+/// Magic bytes starting every frame
+const MAGIC: &[u8; 4] = b"AQMF";
+/// Length of the frame header
+const HEADER_LEN: usize = 16;
+/// Largest frame accepted, in pixels
+const MAX_PIXELS: usize = 8192 * 8192;
+
+fn default_bind() -> String {
+    String::from("0.0.0.0:5555")
+}
+
+/// Configuration of the TCP source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpSrcConf {
+    /// Address and port to listen on
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Folder where received frames are written, outside the watched folders
+    pub spool: String,
+    /// Number of shots kept in the spool, all if not given
+    #[serde(default)]
+    pub keep_shots: Option<usize>,
+}
+
+/// Header of a received frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    shot: u32,
+    frame: u16,
+    frames: u16,
+    height: usize,
+    width: usize,
+}
+
+fn parse(buf: &[u8; HEADER_LEN]) -> Result<Header> {
+    if &buf[..4] != MAGIC {
+        bail!("Bad magic {:?}", &buf[..4]);
+    }
+    let header = Header {
+        shot: LittleEndian::read_u32(&buf[4..8]),
+        frame: LittleEndian::read_u16(&buf[8..10]),
+        frames: LittleEndian::read_u16(&buf[10..12]),
+        height: LittleEndian::read_u16(&buf[12..14]) as usize,
+        width: LittleEndian::read_u16(&buf[14..16]) as usize,
+    };
+    if header.frame == 0 || header.frame > header.frames {
+        bail!("Frame {} of {} is invalid", header.frame, header.frames);
+    }
+    if header.height * header.width == 0
+        || header.height * header.width > MAX_PIXELS
+    {
+        bail!("Frame size {}x{} invalid", header.height, header.width);
+    }
+    Ok(header)
+}
+
+/// Receiver of the frames, assembling them into shots
+struct TcpSrc {
+    conf: TcpSrcConf,
+    /// Frames received so far of incomplete shots
+    pending: BTreeMap<u32, Vec<PathBuf>>,
+    /// Frames of the complete shots still in the spool
+    spooled: VecDeque<Vec<PathBuf>>,
+}
+
+impl TcpSrc {
+    /// Write a frame to the spool, returning the paths of its shot if it is
+    /// now complete.
+    fn frame(
+        &mut self,
+        header: Header,
+        pixels: &[u8],
+    ) -> Result<Option<Vec<PathBuf>>> {
+        let mut image = vec![0u16; header.height * header.width];
+        LittleEndian::read_u16_into(pixels, &mut image);
+        let arr = Array2::from_shape_vec((header.height, header.width), image)?;
+        let mut path = PathBuf::from(&self.conf.spool);
+        path.push(format!("{:08}-rawimg-{:04}.sis", header.shot, header.frame));
+        SisImg::new(arr)?.write(path.clone())?;
+        debug!(
+            "Frame {} of shot {} received to {:?}",
+            header.frame, header.shot, path
+        );
+
+        let paths = self.pending.entry(header.shot).or_default();
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+        if paths.len() < header.frames as usize {
+            return Ok(None);
+        }
+        let paths = self.pending.remove(&header.shot).unwrap_or_default();
+        // Frames of older shots will never be completed
+        let stale = self.pending.range(..header.shot).count();
+        if stale > 0 {
+            warn!("Dropping {} incomplete shots before {}", stale, header.shot);
+            self.pending = self.pending.split_off(&header.shot);
+        }
+        self.prune(paths.clone());
+        Ok(Some(paths))
+    }
+
+    /// Remove the frames of the shots beyond the ones to keep.
+    fn prune(&mut self, paths: Vec<PathBuf>) {
+        let Some(keep) = self.conf.keep_shots else {
+            return;
+        };
+        self.spooled.push_back(paths);
+        while self.spooled.len() > keep.max(1) {
+            for p in self.spooled.pop_front().unwrap_or_default() {
+                if let Err(e) = fs::remove_file(&p) {
+                    debug!("Cannot remove spooled frame {:?}: {}", p, e);
+                }
+            }
+        }
+    }
+
+    /// Receive frames from a connection until it is closed.
+    fn serve(
+        &mut self,
+        mut stream: TcpStream,
+        tx: &Sender<DebounceEventResult>,
+    ) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut buf = [0u8; HEADER_LEN];
+        loop {
+            match stream.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let header = match parse(&buf) {
+                Ok(h) => h,
+                Err(e) => {
+                    // The stream cannot be resynchronized
+                    stream.write_all(b"E")?;
+                    return Err(e);
+                }
+            };
+            let mut pixels = vec![0u8; 2 * header.height * header.width];
+            stream.read_exact(&mut pixels)?;
+            match self.frame(header, &pixels) {
+                Ok(shot) => {
+                    stream.write_all(b"K")?;
+                    if let Some(paths) = shot {
+                        let batch = vec![events::created(paths)];
+                        if tx.send(Ok(batch)).is_err() {
+                            bail!("Event queue closed");
+                        }
+                    }
+                }
+                Err(e) => {
+                    stream.write_all(b"E")?;
+                    error!("Cannot spool received frame: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Start receiving frames in a separate thread, one connection at a time.
+///
+/// Every complete shot is sent on `tx` as a batch of creation events.
+pub fn spawn(
+    conf: &TcpSrcConf,
+    tx: Sender<DebounceEventResult>,
+) -> Result<JoinHandle<()>> {
+    fs::create_dir_all(&conf.spool)
+        .context(format!("Cannot create spool folder {}", conf.spool))?;
+    let listener = TcpListener::bind(&conf.bind)
+        .context(format!("Cannot receive frames on {}", conf.bind))?;
+    let mut src = TcpSrc {
+        conf: conf.clone(),
+        pending: BTreeMap::new(),
+        spooled: VecDeque::new(),
+    };
+
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Cannot accept frame connection: {}", e);
+                    continue;
+                }
+            };
+            let peer = stream.peer_addr().ok();
+            info!("Receiving frames from {:?}", peer);
+            match src.serve(stream, &tx) {
+                Ok(()) => info!("Connection from {:?} closed", peer),
+                Err(e) => error!("Connection from {:?} failed: {:?}", peer, e),
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Header, HEADER_LEN};
+
+    #[test]
+    fn test_header() {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[..4].copy_from_slice(b"AQMF");
+        buf[4..8].copy_from_slice(&1234u32.to_le_bytes());
+        buf[8..10].copy_from_slice(&2u16.to_le_bytes());
+        buf[10..12].copy_from_slice(&3u16.to_le_bytes());
+        buf[12..14].copy_from_slice(&512u16.to_le_bytes());
+        buf[14..16].copy_from_slice(&1024u16.to_le_bytes());
+        let header = Header {
+            shot: 1234,
+            frame: 2,
+            frames: 3,
+            height: 512,
+            width: 1024,
+        };
+        assert_eq!(parse(&buf).unwrap(), header);
+
+        buf[8..10].copy_from_slice(&4u16.to_le_bytes());
+        assert!(parse(&buf).is_err());
+        buf[..4].copy_from_slice(b"AQMX");
+        assert!(parse(&buf).is_err());
+    }
+}
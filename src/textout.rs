@@ -0,0 +1,295 @@
+//! Locale-independent serialization for text outputs (CSV and JSON).
+//!
+//! Every text file written by acqmidproc goes through this module, so that
+//! decimals always use '.' and timestamps are ISO-8601 in UTC, whatever the
+//! locale of the machine we are running on (Rust formatting never reads
+//! it).
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::transaction;
+
+/// Number formatting options, shared by all text writers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NumFmt {
+    /// Digits after the decimal point for floating point values
+    pub precision: usize,
+}
+
+impl Default for NumFmt {
+    fn default() -> Self {
+        NumFmt { precision: 6 }
+    }
+}
+
+impl NumFmt {
+    /// Format a float with the configured precision.
+    ///
+    /// Rust formatting never looks at the locale, this is only here to have a
+    /// single place where precision is applied.
+    pub fn float(&self, x: f64) -> String {
+        format!("{:.*}", self.precision, x)
+    }
+}
+
+/// A single field of a record.
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// Integer value
+    Int(i64),
+    /// Floating point value, formatted according to [`NumFmt`]
+    Float(f64),
+    /// String value, quoted/escaped as needed
+    Str(String),
+    /// Timestamp, formatted as ISO-8601 UTC with millisecond resolution
+    Time(SystemTime),
+}
+
+/// Ordered list of named fields: one CSV row, or one JSON object.
+pub type Record = Vec<(String, Value)>;
+
+/// Format a timestamp as ISO-8601 in UTC, e.g. `2024-05-11T14:02:33.123Z`.
+pub fn timestamp(t: SystemTime) -> String {
+    DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn csv_quote(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+fn json_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// CSV header line for the record (without trailing newline).
+pub fn csv_header(rec: &Record) -> String {
+    rec.iter()
+        .map(|(k, _)| csv_quote(k))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// CSV data line for the record (without trailing newline).
+pub fn csv_row(fmt: &NumFmt, rec: &Record) -> String {
+    rec.iter()
+        .map(|(_, v)| match v {
+            Value::Int(i) => i.to_string(),
+            Value::Float(x) => fmt.float(*x),
+            Value::Str(s) => csv_quote(s),
+            Value::Time(t) => timestamp(*t),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// JSON object for the record, on a single line.
+///
+/// Non-finite floats are written as `null`, since JSON cannot represent them.
+pub fn json_object(fmt: &NumFmt, rec: &Record) -> String {
+    let fields = rec
+        .iter()
+        .map(|(k, v)| {
+            let val = match v {
+                Value::Int(i) => i.to_string(),
+                Value::Float(x) if x.is_finite() => fmt.float(*x),
+                Value::Float(_) => String::from("null"),
+                Value::Str(s) => json_quote(s),
+                Value::Time(t) => json_quote(&timestamp(*t)),
+            };
+            format!("{}:{}", json_quote(k), val)
+        })
+        .collect::<Vec<String>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Write a single record to a text file, replacing it.
+///
+/// The format is chosen from the extension, like in [`append`].
+pub fn write(path: &Path, fmt: &NumFmt, rec: &Record) -> Result<()> {
+    let text = match extension(path).as_str() {
+        "csv" => format!("{}\n{}\n", csv_header(rec), csv_row(fmt, rec)),
+        "json" | "jsonl" => format!("{}\n", json_object(fmt, rec)),
+        _ => bail!(
+            "Unknown text output format for {:?}, use .csv, .json or .jsonl",
+            path
+        ),
+    };
+
+    debug!("Writing record to {:?}", path);
+    fs::write(path, text).context(format!("Cannot write {:?}", path))?;
+    transaction::record(path);
+
+    Ok(())
+}
+
+/// Append a record to a text file, choosing the format from the extension.
+///
+/// `.csv` files get a header line when they are created, `.json` and `.jsonl`
+/// files get one JSON object per line. A `.csv` file whose header differs
+/// from the fields of the record, e.g. written by an older version, is
+/// renamed to `<stem>-<time>.csv` and a new one started.
+pub fn append(path: &Path, fmt: &NumFmt, rec: &Record) -> Result<()> {
+    if extension(path) == "csv" && path.exists() {
+        let mut header = String::new();
+        BufReader::new(File::open(path)?).read_line(&mut header)?;
+        if header.trim_end() != csv_header(rec) {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let time = DateTime::<Utc>::from(SystemTime::now());
+            let old = path.with_file_name(format!(
+                "{}-{}.csv",
+                stem,
+                time.format("%Y%m%dT%H%M%S")
+            ));
+            if old.exists() {
+                bail!("Cannot rotate {:?}, {:?} exists", path, old);
+            }
+            fs::rename(path, &old)
+                .context(format!("Cannot rotate {:?}", path))?;
+            warn!("Columns of {:?} changed, old rows moved to {:?}", path, old);
+        }
+    }
+    let isnew = !path.exists();
+
+    let line = match extension(path).as_str() {
+        "csv" if isnew => {
+            format!("{}\n{}\n", csv_header(rec), csv_row(fmt, rec))
+        }
+        "csv" => format!("{}\n", csv_row(fmt, rec)),
+        "json" | "jsonl" => format!("{}\n", json_object(fmt, rec)),
+        _ => bail!(
+            "Unknown text output format for {:?}, use .csv, .json or .jsonl",
+            path
+        ),
+    };
+
+    debug!("Appending record to {:?}", path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Cannot open {:?} for appending", path))?;
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn record() -> Record {
+        vec![
+            (
+                String::from("time"),
+                Value::Time(UNIX_EPOCH + Duration::from_millis(1715436153123)),
+            ),
+            (String::from("od"), Value::Float(1234.5678)),
+            (String::from("n"), Value::Int(-3)),
+            (String::from("name"), Value::Str(String::from("a,\"b\""))),
+        ]
+    }
+
+    #[test]
+    fn test_csv_row() {
+        let fmt = NumFmt { precision: 2 };
+        assert_eq!(
+            csv_row(&fmt, &record()),
+            "2024-05-11T14:02:33.123Z,1234.57,-3,\"a,\"\"b\"\"\""
+        );
+        assert_eq!(csv_header(&record()), "time,od,n,name");
+    }
+
+    #[test]
+    fn test_append_rotates() {
+        let dir = TempDir::new("textout");
+        let path = dir.join("report.csv");
+        let fmt = NumFmt::default();
+        let mut rec = record();
+        append(&path, &fmt, &rec).unwrap();
+        append(&path, &fmt, &rec).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // A new column starts a new file, the old one is kept aside
+        rec.push((String::from("extra"), Value::Int(1)));
+        append(&path, &fmt, &rec).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().next(), Some("time,od,n,name,extra"));
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_json_object() {
+        let fmt = NumFmt { precision: 1 };
+        let mut rec = record();
+        rec.push((String::from("bad"), Value::Float(f64::NAN)));
+        assert_eq!(
+            json_object(&fmt, &rec),
+            "{\"time\":\"2024-05-11T14:02:33.123Z\",\"od\":1234.6,\"n\":-3,\
+             \"name\":\"a,\\\"b\\\"\",\"bad\":null}"
+        );
+    }
+
+    #[test]
+    fn test_comma_locale() {
+        // As set on the acquisition PC; Rust formatting never reads them
+        for var in ["LANG", "LC_ALL", "LC_NUMERIC", "LC_TIME"] {
+            std::env::set_var(var, "de_DE.UTF-8");
+        }
+        let fmt = NumFmt { precision: 3 };
+        let rec = record();
+        assert_eq!(
+            csv_row(&fmt, &rec),
+            "2024-05-11T14:02:33.123Z,1234.568,-3,\"a,\"\"b\"\"\""
+        );
+        assert!(json_object(&fmt, &rec).contains("\"od\":1234.568,"));
+        assert!(json_object(&fmt, &rec).contains("2024-05-11T14:02:33.123Z"));
+
+        // Nor does the writer call anything that would
+        let source = include_str!("textout.rs");
+        let code = source.split("#[cfg(test)]").next().unwrap();
+        let apis = ["setlocale", "format_localized", "Local::", "%c", "%x"];
+        for api in apis {
+            assert!(!code.contains(api), "{} is locale-dependent", api);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! All-or-nothing publication of each shot.
+//!
+//! A shot is published to several sinks in turn: its output files, the
+//! paired acquire.py metadata, the shot index, the copies of the consumers,
+//! then the notifications of the consumers and the multicast announcement.
+//! With a `[transaction]` section, every file written while handling a shot
+//! is recorded in a journal, and if any sink fails the files are removed
+//! again and the shot is retried after `retry_s` seconds, up to `retries`
+//! times before it is marked failed in the report. The notifications go out
+//! last, so consumers never see, nor are told about, a half-published shot:
+//! once one of them went out, the shot is published and a failing
+//! notification after it is only logged.
+//!
+//! The archival pass, the zarr store, the gallery and the terminal preview
+//! are best-effort: they only get shots that were published, after the
+//! journal is closed, and their failures are only logged.
+//!
+//! Fixed-name outputs of a failed shot are removed too: cam.py then finds no
+//! image rather than a mix of two shots.
+
+use std::{
+    cell::RefCell,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_retry_s() -> f64 {
+    1.0
+}
+
+/// Configuration of the transactional publication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionConf {
+    /// Number of times a failed shot is handled again
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Seconds before a failed shot is handled again
+    #[serde(default = "default_retry_s")]
+    pub retry_s: f64,
+}
+
+thread_local! {
+    /// Files written since the journal was started, on this thread only:
+    /// the archival pass writes on its own thread and is never rolled back.
+    static JOURNAL: RefCell<Option<Vec<PathBuf>>> =
+        const { RefCell::new(None) };
+}
+
+/// Start recording the files written by this thread.
+pub fn begin() {
+    JOURNAL.with(|j| *j.borrow_mut() = Some(vec![]));
+}
+
+/// Record a written file, if a journal is started.
+pub fn record(path: &Path) {
+    JOURNAL.with(|j| {
+        if let Some(paths) = j.borrow_mut().as_mut() {
+            if !paths.iter().any(|p| p == path) {
+                paths.push(path.to_path_buf());
+            }
+        }
+    });
+}
+
+/// Files written since [`begin`], still recording.
+pub fn written() -> Vec<PathBuf> {
+    JOURNAL.with(|j| j.borrow().clone()).unwrap_or_default()
+}
+
+/// Whether a journal is started on this thread.
+pub fn journaling() -> bool {
+    JOURNAL.with(|j| j.borrow().is_some())
+}
+
+/// Stop recording, returning the files written since [`begin`].
+pub fn end() -> Vec<PathBuf> {
+    JOURNAL.with(|j| j.borrow_mut().take()).unwrap_or_default()
+}
+
+/// Remove the files of a failed shot, returning how many were removed.
+pub fn rollback(paths: &[PathBuf]) -> usize {
+    let mut removed = 0;
+    for p in paths {
+        match fs::remove_file(p) {
+            Ok(()) => {
+                debug!("Rolled back {:?}", p);
+                removed += 1;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Cannot roll back {:?}: {}", p, e),
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{begin, end, journaling, record, rollback};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("transaction");
+        let (a, b) = (dir.join("a.sis"), dir.join("b-meta.json"));
+
+        // Nothing is recorded outside of a journal
+        record(&a);
+        assert!(end().is_empty());
+
+        begin();
+        assert!(journaling());
+        for p in [&a, &b, &a] {
+            fs::write(p, "x").unwrap();
+            record(p);
+        }
+        let written = end();
+        assert!(!journaling());
+        assert_eq!(written, vec![a.clone(), b.clone()]);
+        fs::remove_file(&b).unwrap();
+        assert_eq!(rollback(&written), 1);
+        assert!(!a.exists());
+    }
+}
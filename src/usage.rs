@@ -0,0 +1,118 @@
+//! Data-rate and disk-usage accounting of the run.
+//!
+//! Every input read and every output written is counted here, in bytes on
+//! disk, so that the storage needed by long campaigns can be forecast, and
+//! runaway duplicate outputs show up as a written/read ratio that grows.
+//! Counters are global, since outputs are written by the live processors
+//! and by the archival thread alike. Inputs are only counted when the live
+//! processors read them: the archival thread reads them again.
+
+use std::{
+    cell::Cell,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::debug;
+
+static FILES_READ: AtomicU64 = AtomicU64::new(0);
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static FILES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The inputs read by this thread were already counted
+    static REREADING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Snapshot of the counters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// Input files read
+    pub files_read: u64,
+    /// Bytes of input files read
+    pub bytes_read: u64,
+    /// Output files written
+    pub files_written: u64,
+    /// Bytes of output files written
+    pub bytes_written: u64,
+}
+
+/// Bytes to megabytes, as reported.
+pub fn mb(bytes: u64) -> f64 {
+    bytes as f64 / 1e6
+}
+
+impl Usage {
+    /// Counters since the start of the run.
+    pub fn now() -> Usage {
+        Usage {
+            files_read: FILES_READ.load(Ordering::Relaxed),
+            bytes_read: BYTES_READ.load(Ordering::Relaxed),
+            files_written: FILES_WRITTEN.load(Ordering::Relaxed),
+            bytes_written: BYTES_WRITTEN.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counters accumulated since an earlier snapshot.
+    pub fn since(&self, earlier: &Usage) -> Usage {
+        Usage {
+            files_read: self.files_read - earlier.files_read,
+            bytes_read: self.bytes_read - earlier.bytes_read,
+            files_written: self.files_written - earlier.files_written,
+            bytes_written: self.bytes_written - earlier.bytes_written,
+        }
+    }
+}
+
+/// Size of a file on disk, 0 if it cannot be found.
+fn size(path: &Path) -> u64 {
+    path.metadata().map_or(0, |m| m.len())
+}
+
+/// Do not count the inputs read by the current thread.
+pub fn rereading() {
+    REREADING.with(|r| r.set(true));
+}
+
+/// Count an input file that was read.
+pub fn read(path: &Path) {
+    if REREADING.with(Cell::get) {
+        return;
+    }
+    let len = size(path);
+    FILES_READ.fetch_add(1, Ordering::Relaxed);
+    BYTES_READ.fetch_add(len, Ordering::Relaxed);
+    debug!("Read {} bytes from {:?}", len, path);
+}
+
+/// Count an output file that was written.
+pub fn written(path: &Path) {
+    let len = size(path);
+    FILES_WRITTEN.fetch_add(1, Ordering::Relaxed);
+    BYTES_WRITTEN.fetch_add(len, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Usage;
+
+    #[test]
+    fn test_since() {
+        let earlier = Usage {
+            files_read: 3,
+            bytes_read: 3000,
+            files_written: 1,
+            bytes_written: 500,
+        };
+        let now = Usage {
+            files_read: 6,
+            bytes_read: 6000,
+            files_written: 4,
+            bytes_written: 2500,
+        };
+        let delta = now.since(&earlier);
+        assert_eq!((delta.files_read, delta.bytes_read), (3, 3000));
+        assert_eq!((delta.files_written, delta.bytes_written), (3, 2000));
+    }
+}
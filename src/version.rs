@@ -0,0 +1,302 @@
+//! Output format versioning and migration of archived outputs.
+//!
+//! Processed sis images carry a stamp in the padding area of their header:
+//!
+//! | pad bytes | content                          |
+//! |-----------|----------------------------------|
+//! | 0..4      | magic `AMPV`                     |
+//! | 4..6      | format version, u16 LE           |
+//! | 6..10     | OD scale, f32 LE                 |
+//! | 10..14    | OD offset, f32 LE                |
+//!
+//! so that `od = pixel / scale - offset`. Files without the magic are
+//! version 0, written when the OD encoding was fixed to scale 1000 and
+//! offset 1, and when OD images were always named `*-img-*.sis`: migration
+//! takes the sis files with the magic, whatever their name, and the
+//! unstamped ones with that name.
+//!
+//! Optionally, the padding also holds the provenance of the image:
+//!
+//! | pad bytes | content                                      |
+//! |-----------|----------------------------------------------|
+//! | 14..18    | magic `AMPP`                                 |
+//! | 18..22    | CRC-32 of the input files, in order, u32 LE  |
+//! | 22..30    | first 8 bytes of the SHA-256 of the params   |
+//! | 30..46    | processor name, ASCII, zero padded           |
+//! | 46..50    | behavior revision, u32 LE, 0 if older        |
+//!
+//! The rest of the padding is reserved.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use flate2::Crc;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{history, input, SisImg, SIS_HEADER_LEN, SIS_PAD_OFFSET};
+
+/// Current version of the output formats
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Magic bytes identifying a stamped sis image
+const MAGIC: &[u8; 4] = b"AMPV";
+
+/// Magic bytes identifying the provenance of a sis image
+const PROVENANCE_MAGIC: &[u8; 4] = b"AMPP";
+
+/// Offset of the provenance in the header padding
+const PROVENANCE_OFFSET: usize = 14;
+
+/// Length of the provenance in the header padding
+const PROVENANCE_LEN: usize = 36;
+
+/// OD scale of unstamped (version 0) outputs
+const LEGACY_OD_SCALE: f32 = 1000.0;
+
+/// OD offset of unstamped (version 0) outputs
+const LEGACY_OD_OFFSET: f32 = 1.0;
+
+/// Version and encoding stamp of a processed sis image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    /// Format version
+    pub version: u16,
+    /// Scale of the u16 OD encoding
+    pub od_scale: f32,
+    /// Offset of the u16 OD encoding
+    pub od_offset: f32,
+}
+
+impl Stamp {
+    /// Stamp of the current format version with the given OD encoding.
+    pub fn od(od_scale: f32, od_offset: f32) -> Stamp {
+        Stamp {
+            version: FORMAT_VERSION,
+            od_scale,
+            od_offset,
+        }
+    }
+
+    /// Write the stamp at the start of the header padding.
+    pub fn encode(&self, pad: &mut [u8]) {
+        pad[0..4].copy_from_slice(MAGIC);
+        pad[4..6].copy_from_slice(&self.version.to_le_bytes());
+        pad[6..10].copy_from_slice(&self.od_scale.to_le_bytes());
+        pad[10..14].copy_from_slice(&self.od_offset.to_le_bytes());
+    }
+
+    /// Read the stamp from the header padding, if there is one.
+    pub fn decode(pad: &[u8]) -> Option<Stamp> {
+        if pad.len() < 14 || &pad[0..4] != MAGIC {
+            return None;
+        }
+        let word = |i: usize| [pad[i], pad[i + 1], pad[i + 2], pad[i + 3]];
+        Some(Stamp {
+            version: u16::from_le_bytes([pad[4], pad[5]]),
+            od_scale: f32::from_le_bytes(word(6)),
+            od_offset: f32::from_le_bytes(word(10)),
+        })
+    }
+}
+
+/// Provenance of a processed sis image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// CRC-32 of the decompressed input files, concatenated in order
+    pub inputs_crc: u32,
+    /// First 8 bytes of the SHA-256 of the processing parameters
+    pub params_hash: u64,
+    /// Name of the processor, at most 16 bytes
+    pub processor: String,
+    /// Behavior revision of acqmidproc, 0 if written before it was recorded
+    pub behavior_rev: u32,
+}
+
+impl Provenance {
+    /// Compute the provenance of an output of the processor, from its inputs
+    /// and a textual representation of its parameters.
+    pub fn new(
+        processor: &str,
+        params: &str,
+        inputs: &[&Path],
+    ) -> Result<Provenance> {
+        let mut crc = Crc::new();
+        for p in inputs {
+            crc.update(&input::reread(p)?);
+        }
+        let digest = Sha256::digest(params.as_bytes());
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&digest[..8]);
+
+        Ok(Provenance {
+            inputs_crc: crc.sum(),
+            params_hash: u64::from_le_bytes(hash),
+            processor: processor.chars().take(16).collect(),
+            behavior_rev: history::current(),
+        })
+    }
+
+    /// Write the provenance in the header padding, after the stamp.
+    pub fn encode(&self, pad: &mut [u8]) {
+        let pad =
+            &mut pad[PROVENANCE_OFFSET..PROVENANCE_OFFSET + PROVENANCE_LEN];
+        pad.fill(0);
+        pad[0..4].copy_from_slice(PROVENANCE_MAGIC);
+        pad[4..8].copy_from_slice(&self.inputs_crc.to_le_bytes());
+        pad[8..16].copy_from_slice(&self.params_hash.to_le_bytes());
+        let name = self.processor.as_bytes();
+        let len = name.len().min(16);
+        pad[16..16 + len].copy_from_slice(&name[..len]);
+        pad[32..36].copy_from_slice(&self.behavior_rev.to_le_bytes());
+    }
+
+    /// Read the provenance from the header padding, if there is one.
+    pub fn decode(pad: &[u8]) -> Option<Provenance> {
+        let pad =
+            pad.get(PROVENANCE_OFFSET..PROVENANCE_OFFSET + PROVENANCE_LEN)?;
+        if &pad[0..4] != PROVENANCE_MAGIC {
+            return None;
+        }
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&pad[8..16]);
+        let name = &pad[16..32];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        Some(Provenance {
+            inputs_crc: u32::from_le_bytes([pad[4], pad[5], pad[6], pad[7]]),
+            params_hash: u64::from_le_bytes(hash),
+            processor: String::from_utf8_lossy(&name[..end]).into_owned(),
+            behavior_rev: u32::from_le_bytes([
+                pad[32], pad[33], pad[34], pad[35],
+            ]),
+        })
+    }
+}
+
+/// Whether the file is a processed OD image: stamped, or unstamped with the
+/// name OD images had before the stamp.
+fn is_od(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !name.ends_with(".sis") {
+        return false;
+    }
+    if name.contains("-img-") {
+        return true;
+    }
+    let mut header = [0u8; SIS_HEADER_LEN];
+    let read = File::open(path).and_then(|mut f| f.read_exact(&mut header));
+    read.is_ok() && Stamp::decode(&header[SIS_PAD_OFFSET..]).is_some()
+}
+
+/// Collect all the files under a path, recursively.
+fn collect(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            collect(&entry?.path(), files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Upgrade one OD image to the current format, returning whether it changed.
+fn migrate_file(path: &Path, dry_run: bool) -> Result<bool> {
+    let img = SisImg::read(path)?;
+    let old = img.stamp.map_or(0, |s| s.version);
+    if old == FORMAT_VERSION {
+        debug!("{:?} already at version {}", path, old);
+        return Ok(false);
+    }
+    if old > FORMAT_VERSION {
+        warn!("{:?} has version {}, newer than this program", path, old);
+        return Ok(false);
+    }
+
+    info!(
+        "Migrating {:?} from version {} to {}",
+        path, old, FORMAT_VERSION
+    );
+    if dry_run {
+        return Ok(true);
+    }
+
+    let stamp = match img.stamp {
+        Some(s) => Stamp::od(s.od_scale, s.od_offset),
+        None => Stamp::od(LEGACY_OD_SCALE, LEGACY_OD_OFFSET),
+    };
+
+    // Write beside the original and rename, never leaving a half-written file
+    let tmp = path.with_extension("sis.migrating");
+    img.with_stamp(stamp).write(tmp.clone())?;
+    fs::rename(&tmp, path)
+        .context(format!("Cannot replace {:?} with migrated file", path))?;
+
+    Ok(true)
+}
+
+/// Upgrade all the archived OD images under the given paths.
+pub fn migrate(paths: &[PathBuf], dry_run: bool) -> Result<()> {
+    let mut files = vec![];
+    for p in paths {
+        collect(p, &mut files)
+            .context(format!("Cannot list files under {:?}", p))?;
+    }
+
+    let (mut migrated, mut failed) = (0, 0);
+    for f in files.iter().filter(|f| is_od(f)) {
+        match migrate_file(f, dry_run) {
+            Ok(true) => migrated += 1,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Cannot migrate {:?}: {:?}", f, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let verb = if dry_run {
+        "would be migrated"
+    } else {
+        "migrated"
+    };
+    println!("{} files {}, {} failed.", migrated, verb, failed);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ndarray::Array2;
+
+    use super::{is_od, Stamp};
+    use crate::{testutil::TempDir, SisImg};
+
+    #[test]
+    fn test_is_od() {
+        let dir = TempDir::new("version");
+        let img = || SisImg::new(Array2::<u16>::eye(4)).unwrap();
+        let stamp = Stamp::od(1000.0, 1.0);
+        // Fixed and time naming, stamped
+        for name in ["od.sis", "20240511T140233.123-od.sis"] {
+            img().with_stamp(stamp).write(dir.join(name)).unwrap();
+            assert!(is_od(&dir.join(name)));
+        }
+        // Legacy unstamped OD image, and a raw frame
+        img().write(dir.join("20140000-img-0000.sis")).unwrap();
+        assert!(is_od(&dir.join("20140000-img-0000.sis")));
+        img().write(dir.join("0042-rawimg.sis")).unwrap();
+        assert!(!is_od(&dir.join("0042-rawimg.sis")));
+        fs::write(dir.join("meta.json"), "{}").unwrap();
+        assert!(!is_od(&dir.join("meta.json")));
+    }
+}
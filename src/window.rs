@@ -0,0 +1,111 @@
+//! Down-conversion of frames to 8 bits for previews, with windowing.
+//!
+//! A preview scaled from the minimum to the maximum of the image is nearly
+//! black when a few hot pixels, or NaNs clamped to extreme OD, stretch the
+//! range. The window is instead taken between two percentiles of the finite
+//! values: 0 and 100 give the plain minimum and maximum.
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+fn default_low() -> f64 {
+    1.0
+}
+
+fn default_high() -> f64 {
+    99.0
+}
+
+/// Window of the values mapped to the 8 bit range
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindowConf {
+    /// Percentile mapped to black
+    #[serde(default = "default_low")]
+    pub low: f64,
+    /// Percentile mapped to white
+    #[serde(default = "default_high")]
+    pub high: f64,
+}
+
+impl Default for WindowConf {
+    fn default() -> Self {
+        WindowConf {
+            low: default_low(),
+            high: default_high(),
+        }
+    }
+}
+
+/// Value at percentile `p` of sorted values, interpolating linearly.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let pos = (p.clamp(0.0, 100.0) / 100.0) * (sorted.len() - 1) as f64;
+    let (i, frac) = (pos.floor() as usize, pos.fract());
+    match sorted.get(i + 1) {
+        Some(next) => sorted[i] + frac * (next - sorted[i]),
+        None => sorted[i],
+    }
+}
+
+impl WindowConf {
+    /// Black and white levels of the image; `None` if nothing is finite.
+    pub fn levels(&self, img: &Array2<f64>) -> Option<(f64, f64)> {
+        let mut vals = img
+            .iter()
+            .copied()
+            .filter(|x| x.is_finite())
+            .collect::<Vec<_>>();
+        if vals.is_empty() {
+            return None;
+        }
+        vals.sort_by(f64::total_cmp);
+        Some((percentile(&vals, self.low), percentile(&vals, self.high)))
+    }
+
+    /// Map the image to 8 bits, clipping outside the window. Non finite
+    /// values are black.
+    pub fn apply(&self, img: &Array2<f64>) -> Array2<u8> {
+        let Some((lo, hi)) = self.levels(img) else {
+            return Array2::zeros(img.dim());
+        };
+        let span = if hi > lo { hi - lo } else { 1.0 };
+        img.mapv(|x| {
+            if x.is_finite() {
+                ((x - lo) / span * 255.0).round().clamp(0.0, 255.0) as u8
+            } else {
+                0
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::WindowConf;
+
+    #[test]
+    fn test_window() {
+        // A narrow range of OD with a hot pixel
+        let mut img = Array2::from_shape_fn((20, 20), |(y, x)| {
+            0.1 + 0.0001 * (y * 20 + x) as f64
+        });
+        img[[0, 0]] = 50.0;
+        img[[0, 1]] = f64::NAN;
+
+        let minmax = WindowConf {
+            low: 0.0,
+            high: 100.0,
+        };
+        let (lo, hi) = minmax.levels(&img).unwrap();
+        assert!((lo - 0.1002).abs() < 1e-9 && hi == 50.0);
+        assert_eq!(minmax.apply(&img)[[19, 19]], 0);
+
+        let out = WindowConf::default().apply(&img);
+        assert_eq!(out[[0, 0]], 255);
+        assert_eq!(out[[0, 1]], 0);
+        assert_eq!(out[[0, 2]], 0);
+        assert!(out[[10, 0]] > 120 && out[[10, 0]] < 135);
+        assert_eq!(out[[19, 19]], 255);
+    }
+}
@@ -0,0 +1,308 @@
+//! Zarr v3 stores of the runs, for analysis straight from object storage.
+//!
+//! Each run of each watch entry is written as a single array store
+//! `<outpath>/<watch>/run-<time>.zarr`, of shape shots x height x width in
+//! float32 (OD, or counts for images that are not OD), with dimension names
+//! `shot`, `y` and `x`. Every shot is a chunk, or a grid of `chunks` sized
+//! chunks, compressed with the `codec` of the store: `gzip`, optionally after
+//! the `byte` shuffle (the `numcodecs.shuffle` codec of zarr-python). There
+//! is no bit shuffle codec in Zarr v3 outside of blosc, so it is refused.
+//! The `shots` attribute lists, in order, the id, time, watch entry and unit
+//! of every shot, and `behavior_rev` the behavior revision of acqmidproc
+//! (see `acqmidproc history`). Metadata is rewritten atomically after each
+//! shot, so readers only ever see complete shots; xarray opens a store with
+//! `xr.open_zarr(path, zarr_format=3)`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use log::{debug, info};
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, CodecConf, Shuffle},
+    history,
+    textout::{self, NumFmt, Value},
+    usage,
+    version::FORMAT_VERSION,
+    SisImg,
+};
+
+/// Little endian serialization of the elements
+const BYTES: &str = r#"{"name":"bytes","configuration":{"endian":"little"}}"#;
+/// Byte shuffle of float32 elements
+const SHUFFLE: &str =
+    r#"{"name":"numcodecs.shuffle","configuration":{"elementsize":4}}"#;
+
+/// Configuration of the Zarr stores
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZarrConf {
+    /// Folder of the stores, e.g. a mounted bucket
+    pub outpath: String,
+    /// Chunk height and width, the whole image if not given
+    #[serde(default)]
+    pub chunks: Option<[usize; 2]>,
+    /// Compression of the chunks
+    #[serde(default)]
+    pub codec: CodecConf,
+}
+
+/// Array store of a run
+#[derive(Debug)]
+struct Store {
+    path: PathBuf,
+    height: usize,
+    width: usize,
+    /// Attributes of the shots written so far, as JSON objects
+    shots: Vec<String>,
+}
+
+/// Zarr stores of the current run, by watch entry
+#[derive(Debug)]
+pub struct Zarr {
+    conf: ZarrConf,
+    stores: BTreeMap<String, Store>,
+}
+
+/// Chunk of an image, padded with NaN to the full chunk shape.
+fn chunk(
+    img: &Array2<f64>,
+    i: usize,
+    j: usize,
+    ch: usize,
+    cw: usize,
+) -> Vec<f32> {
+    let (h, w) = img.dim();
+    let (y0, x0) = (i * ch, j * cw);
+    let mut out = vec![f32::NAN; ch * cw];
+    let part = img.slice(s![y0..(y0 + ch).min(h), x0..(x0 + cw).min(w)]);
+    for ((y, x), v) in part.indexed_iter() {
+        out[y * cw + x] = *v as f32;
+    }
+    out
+}
+
+impl Store {
+    /// Chunk shape of the store.
+    fn chunks(&self, conf: &ZarrConf) -> (usize, usize) {
+        let [ch, cw] = conf.chunks.unwrap_or([self.height, self.width]);
+        (ch.clamp(1, self.height), cw.clamp(1, self.width))
+    }
+
+    /// Array metadata, `zarr.json`.
+    fn metadata(&self, conf: &ZarrConf) -> String {
+        let (ch, cw) = self.chunks(conf);
+        let mut codecs = vec![String::from(BYTES)];
+        if conf.codec.shuffle == Shuffle::Byte {
+            codecs.push(String::from(SHUFFLE));
+        }
+        if conf.codec.codec == Codec::Gzip {
+            codecs.push(format!(
+                r#"{{"name":"gzip","configuration":{{"level":{}}}}}"#,
+                conf.codec.level
+            ));
+        }
+        format!(
+            "{{\"zarr_format\":3,\"node_type\":\"array\",\
+             \"shape\":[{},{},{}],\"data_type\":\"float32\",\
+             \"chunk_grid\":{{\"name\":\"regular\",\
+             \"configuration\":{{\"chunk_shape\":[1,{},{}]}}}},\
+             \"chunk_key_encoding\":{{\"name\":\"default\",\
+             \"configuration\":{{\"separator\":\"/\"}}}},\
+             \"fill_value\":\"NaN\",\"codecs\":[{}],\
+             \"attributes\":{{\"format_version\":{},\
+             \"behavior_rev\":{},\"shots\":[{}]}},\
+             \"dimension_names\":[\"shot\",\"y\",\"x\"]}}\n",
+            self.shots.len(),
+            self.height,
+            self.width,
+            ch,
+            cw,
+            codecs.join(","),
+            FORMAT_VERSION,
+            history::current(),
+            self.shots.join(",")
+        )
+    }
+
+    /// Append an image to the array, with the attributes of its shot.
+    fn append(
+        &mut self,
+        conf: &ZarrConf,
+        img: &Array2<f64>,
+        attrs: String,
+    ) -> Result<()> {
+        if img.dim() != (self.height, self.width) {
+            bail!(
+                "Image of {:?} does not fit the {}x{} store {:?}",
+                img.dim(),
+                self.height,
+                self.width,
+                self.path
+            );
+        }
+        let (ch, cw) = self.chunks(conf);
+        let n = self.shots.len();
+        for i in 0..self.height.div_ceil(ch) {
+            for j in 0..self.width.div_ceil(cw) {
+                let dir = self.path.join(format!("c/{}/{}", n, i));
+                fs::create_dir_all(&dir)
+                    .context(format!("Cannot create {:?}", dir))?;
+                let bytes = chunk(img, i, j, ch, cw)
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<u8>>();
+                let path = dir.join(j.to_string());
+                fs::write(&path, conf.codec.encode(&bytes, 4)?)
+                    .context(format!("Cannot write chunk {:?}", path))?;
+                usage::written(&path);
+            }
+        }
+
+        // The shot becomes visible with the metadata
+        self.shots.push(attrs);
+        let meta = self.path.join("zarr.json");
+        let tmp = self.path.join("zarr.json.tmp");
+        let written = fs::write(&tmp, self.metadata(conf))
+            .and_then(|_| fs::rename(&tmp, &meta));
+        if let Err(e) = written {
+            self.shots.pop();
+            return Err(e).context(format!("Cannot write {:?}", meta));
+        }
+        Ok(())
+    }
+}
+
+impl Zarr {
+    /// Check the configuration and create the folder of the stores.
+    pub fn new(conf: &ZarrConf) -> Result<Zarr> {
+        conf.codec.check(true)?;
+        if conf.codec.shuffle == Shuffle::Bit {
+            bail!("Zarr stores have no bit shuffle codec, use byte");
+        }
+        if conf.chunks.is_some_and(|[h, w]| h == 0 || w == 0) {
+            bail!("Zarr chunks must not be empty");
+        }
+        fs::create_dir_all(&conf.outpath)
+            .context(format!("Cannot create zarr folder {}", conf.outpath))?;
+        Ok(Zarr {
+            conf: conf.clone(),
+            stores: BTreeMap::new(),
+        })
+    }
+
+    /// End the stores of the run, the next shots start new ones.
+    pub fn close(&mut self) {
+        for store in self.stores.values() {
+            info!(
+                "Zarr store {:?} closed with {} shots",
+                store.path,
+                store.shots.len()
+            );
+        }
+        self.stores.clear();
+    }
+
+    /// Append a processed shot, from its main output, to the store of its
+    /// watch entry.
+    pub fn shot(
+        &mut self,
+        watch: &str,
+        shot: &str,
+        output: &Path,
+    ) -> Result<()> {
+        let img = SisImg::read(output)
+            .context(format!("Cannot read {:?} for zarr", output))?;
+        let (img, od) = img.values();
+        let now = SystemTime::now();
+        if !self.stores.contains_key(watch) {
+            let (height, width) = img.dim();
+            let id = DateTime::<Local>::from(now).format("%Y%m%dT%H%M%S");
+            let path = Path::new(&self.conf.outpath)
+                .join(watch)
+                .join(format!("run-{}.zarr", id));
+            fs::create_dir_all(&path)
+                .context(format!("Cannot create zarr store {:?}", path))?;
+            info!("New zarr store {:?}", path);
+            let store = Store {
+                path,
+                height,
+                width,
+                shots: vec![],
+            };
+            self.stores.insert(String::from(watch), store);
+        }
+        let rec = vec![
+            (String::from("shot"), Value::Str(String::from(shot))),
+            (String::from("time"), Value::Time(now)),
+            (String::from("watch"), Value::Str(String::from(watch))),
+            (
+                String::from("unit"),
+                Value::Str(String::from(if od { "OD" } else { "counts" })),
+            ),
+        ];
+        let attrs = textout::json_object(&NumFmt::default(), &rec);
+        let store = self.stores.get_mut(watch).unwrap();
+        store.append(&self.conf, &img, attrs)?;
+        debug!("Shot {} appended to {:?}", shot, store.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ndarray::Array2;
+
+    use super::{chunk, Store, ZarrConf};
+    use crate::{
+        codec::{CodecConf, Shuffle},
+        testutil::TempDir,
+    };
+
+    #[test]
+    fn test_store() {
+        let img = Array2::from_shape_fn((3, 5), |(y, x)| (y * 5 + x) as f64);
+        let part = chunk(&img, 1, 1, 2, 3);
+        assert_eq!(&part[..2], &[13.0, 14.0]);
+        assert!(part[2..].iter().all(|x| x.is_nan()));
+
+        let dir = TempDir::new("zarr");
+        let conf = ZarrConf {
+            outpath: dir.to_string_lossy().into_owned(),
+            chunks: Some([2, 3]),
+            codec: CodecConf {
+                shuffle: Shuffle::Byte,
+                ..CodecConf::default()
+            },
+        };
+        let mut store = Store {
+            path: dir.join("run.zarr"),
+            height: 3,
+            width: 5,
+            shots: vec![],
+        };
+        store.append(&conf, &img, String::from("{}")).unwrap();
+        store.append(&conf, &img, String::from("{}")).unwrap();
+        assert!(store
+            .append(&conf, &img.t().to_owned(), String::new())
+            .is_err());
+
+        let meta = fs::read_to_string(dir.join("run.zarr/zarr.json")).unwrap();
+        assert!(meta.contains(r#""shape":[2,3,5]"#));
+        assert!(meta.contains(r#""chunk_shape":[1,2,3]"#));
+        assert!(meta.contains(r#""shots":[{},{}]"#));
+        assert!(meta.contains("numcodecs.shuffle"));
+        assert!(meta.contains(r#""behavior_rev":"#));
+        let last = fs::read(dir.join("run.zarr/c/1/1/1")).unwrap();
+        assert_eq!(last.len(), 2 * 3 * 4);
+    }
+}
@@ -0,0 +1,21 @@
+//! Soak test of the daemon binary, for a few shots.
+
+use std::process::Command;
+
+#[test]
+fn test_soak_child() {
+    let out = Command::new(env!("CARGO_BIN_EXE_acqmidproc"))
+        .args(["--proc", "fkspecies", "soak", "--hours", "0.001"])
+        .args(["--interval-s", "0.5", "--fault-rate", "0", "--size", "16"])
+        .args(["--max-growth-mb", "1000"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success(),
+        "{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(stdout.contains("Soak test passed"), "{}", stdout);
+}